@@ -0,0 +1,1270 @@
+//! Approximate nearest neighbour search over chunk embeddings.
+//!
+//! Each embedding model gets a Hierarchical Navigable Small World graph
+//! stored under `ann/` next to the Tantivy index, derived from the
+//! `embeddings` table. The graph is saved as a snapshot laid out so it can be
+//! memory-mapped and searched in place, so a query only reads the nodes it
+//! visits. [`sync`] appends the vectors the index is missing, and tombstones
+//! for the ones deleted from the table, to a log next to the snapshot; queries
+//! score the logged vectors exactly. Once the log holds too many records it is
+//! folded into a new snapshot, rebuilt from scratch when tombstones outnumber
+//! live entries.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use memmap2::Mmap;
+use once_cell::sync::Lazy;
+use rusqlite::{params, Connection};
+
+use crate::config::Config;
+use crate::embed;
+
+const MAGIC: &[u8; 8] = b"FXHNSW02";
+/// Magic of the previous format, read whole into memory. Such indexes are
+/// rebuilt by the next [`sync`].
+const MAGIC_V1: &[u8; 8] = b"FXHNSW01";
+const LOG_MAGIC: &[u8; 8] = b"FXHLOG01";
+const LOG_INSERT: u8 = b'+';
+const LOG_REMOVE: u8 = b'-';
+/// Neighbours kept per node on the upper layers; layer 0 keeps twice as many.
+const M: usize = 16;
+/// Size of the candidate list while inserting.
+const EF_CONSTRUCTION: usize = 100;
+/// Minimum size of the candidate list while searching.
+const EF_SEARCH: usize = 64;
+const MAX_LEVEL: usize = 16;
+
+/// Directory holding the ANN indexes, next to the Tantivy index.
+pub fn dir(cfg: &Config) -> Utf8PathBuf {
+    cfg.tantivy_index
+        .parent()
+        .unwrap_or(Utf8Path::new(""))
+        .join("ann")
+}
+
+fn index_path(dir: &Utf8Path, model: &str) -> Utf8PathBuf {
+    let name: String = model
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    dir.join(format!("{name}.hnsw"))
+}
+
+fn log_path(dir: &Utf8Path, model: &str) -> Utf8PathBuf {
+    index_path(dir, model).with_extension("hnsw.log")
+}
+
+/// Records the log may hold before it is folded into the snapshot of an
+/// index of `len` entries: queries read the whole log and score it exactly,
+/// while compacting rewrites the snapshot.
+fn compact_at(len: usize) -> usize {
+    (len / 32).clamp(1024, 16384)
+}
+
+/// Open the index of `model`, if one was built.
+pub fn load(dir: &Utf8Path, model: &str) -> Result<Option<Index>> {
+    let path = index_path(dir, model);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let Some(snapshot) = Snapshot::open(&path).with_context(|| format!("read {path}"))? else {
+        return Ok(None);
+    };
+    let log = log_path(dir, model);
+    let delta = Delta::read(&log, &snapshot).with_context(|| format!("read {log}"))?;
+    Ok(Some(Index::new(snapshot, delta)))
+}
+
+/// Modification times and sizes of the snapshot and log of an index.
+type Stamp = [Option<(SystemTime, u64)>; 2];
+
+/// Indexes opened by [`cached`], with the [`Stamp`] of their files.
+type Loaded = HashMap<Utf8PathBuf, (Stamp, Arc<Index>)>;
+
+static LOADED: Lazy<Mutex<Loaded>> = Lazy::new(Default::default);
+
+/// Like [`load`], but keeps the index open for later queries of the same
+/// process until its files change.
+pub fn cached(dir: &Utf8Path, model: &str) -> Result<Option<Arc<Index>>> {
+    let path = index_path(dir, model);
+    let stamp = |p: &Utf8Path| {
+        fs::metadata(p)
+            .and_then(|m| Ok((m.modified()?, m.len())))
+            .ok()
+    };
+    let current = [stamp(&path), stamp(&log_path(dir, model))];
+    if current[0].is_none() {
+        return Ok(None);
+    }
+    let mut loaded = LOADED.lock().unwrap();
+    if let Some((at, index)) = loaded.get(&path) {
+        if *at == current {
+            return Ok(Some(index.clone()));
+        }
+    }
+    let Some(index) = load(dir, model)? else {
+        return Ok(None);
+    };
+    let index = Arc::new(index);
+    loaded.insert(path, (current, index.clone()));
+    Ok(Some(index))
+}
+
+/// Bring the index of `model` in line with its rows in `embeddings`. Changes
+/// are appended to the log, and the log is folded into a new snapshot once it
+/// holds more than [`compact_at`] records.
+pub fn sync(conn: &Connection, dir: &Utf8Path, model: &str) -> Result<()> {
+    let mut stmt = conn.prepare("SELECT chunk_id, dim FROM embeddings WHERE model_id=?1")?;
+    let mut stored = HashSet::new();
+    let mut dim = 0;
+    for row in stmt.query_map(params![model], |r| {
+        Ok((r.get::<_, String>(0)?, r.get::<_, i64>(1)?))
+    })? {
+        let (chunk_id, d) = row?;
+        stored.insert(chunk_id);
+        dim = d as usize;
+    }
+    if stored.is_empty() {
+        for path in [index_path(dir, model), log_path(dir, model)] {
+            if path.exists() {
+                fs::remove_file(&path)?;
+            }
+        }
+        return Ok(());
+    }
+
+    let index = match load(dir, model)? {
+        Some(index) if index.dim() == dim => index,
+        _ => {
+            let mut index = Hnsw::new(model, dim);
+            let mut ids: Vec<&String> = stored.iter().collect();
+            ids.sort();
+            for (id, vector) in stored_vectors(conn, model, ids)? {
+                index.insert(&id, &vector)?;
+            }
+            return save(dir, &index);
+        }
+    };
+    let gone: Vec<String> = index
+        .live_ids()
+        .filter(|id| !stored.contains(*id))
+        .map(str::to_string)
+        .collect();
+    let mut missing: Vec<&String> = stored.iter().filter(|id| !index.contains(id)).collect();
+    missing.sort();
+    if gone.is_empty() && missing.is_empty() {
+        return Ok(());
+    }
+    let added = stored_vectors(conn, model, missing)?;
+
+    if index.delta.records + gone.len() + added.len() <= compact_at(index.len()) {
+        return append_log(dir, model, &index, &gone, &added);
+    }
+    let mut graph = index.to_hnsw()?;
+    for id in &gone {
+        graph.remove(id);
+    }
+    for (id, vector) in &added {
+        graph.insert(id, vector)?;
+    }
+    if graph.deleted > graph.len() {
+        graph = graph.rebuilt()?;
+    }
+    save(dir, &graph)
+}
+
+/// Full-precision vectors of the chunks `ids` embedded with `model`.
+fn stored_vectors(
+    conn: &Connection,
+    model: &str,
+    ids: Vec<&String>,
+) -> Result<Vec<(String, Vec<f32>)>> {
+    let mut stmt = conn.prepare(
+        "SELECT vec, quantization, text_hash FROM embeddings WHERE chunk_id=?1 AND model_id=?2",
+    )?;
+    let mut vectors = Vec::with_capacity(ids.len());
+    for id in ids {
+        let (bytes, quantization, text_hash): (Vec<u8>, String, Option<String>) = stmt
+            .query_row(params![id, model], |r| {
+                Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+            })?;
+        let vector =
+            embed::stored_vector(conn, model, &bytes, &quantization, text_hash.as_deref())?;
+        vectors.push((id.clone(), vector));
+    }
+    Ok(vectors)
+}
+
+/// Write `index` as the snapshot of its model, starting an empty log for it.
+fn save(dir: &Utf8Path, index: &Hnsw) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    // Logs only apply to the snapshot of the same generation, so a log left
+    // behind by a crash between the two renames below is ignored.
+    let generation = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64;
+    let path = index_path(dir, &index.model);
+    let tmp = path.with_extension("hnsw.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    index.write(&mut writer, generation)?;
+    writer.into_inner()?.sync_all()?;
+    fs::rename(&tmp, &path)?;
+    reset_log(dir, &index.model, generation)?;
+    tracing::debug!(model = %index.model, entries = index.len(), "ann index saved");
+    Ok(())
+}
+
+/// Replace the log of `model` by an empty one for snapshot `generation`.
+fn reset_log(dir: &Utf8Path, model: &str, generation: u64) -> Result<()> {
+    let path = log_path(dir, model);
+    let tmp = path.with_extension("log.tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(LOG_MAGIC)?;
+    file.write_all(&generation.to_le_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+/// Append tombstones for `gone` and the vectors of `added` to the log of
+/// `index`.
+fn append_log(
+    dir: &Utf8Path,
+    model: &str,
+    index: &Index,
+    gone: &[String],
+    added: &[(String, Vec<f32>)],
+) -> Result<()> {
+    if index.delta.len.is_none() {
+        reset_log(dir, model, index.snapshot.generation)?;
+    }
+    let mut buf = Vec::new();
+    for id in gone {
+        buf.push(LOG_REMOVE);
+        buf.extend_from_slice(&(id.len() as u64).to_le_bytes());
+        buf.extend_from_slice(id.as_bytes());
+    }
+    for (id, vector) in added {
+        buf.push(LOG_INSERT);
+        buf.extend_from_slice(&(id.len() as u64).to_le_bytes());
+        buf.extend_from_slice(id.as_bytes());
+        for x in vector {
+            buf.extend_from_slice(&x.to_le_bytes());
+        }
+    }
+    let mut file = OpenOptions::new().append(true).open(log_path(dir, model))?;
+    // Drop a record cut short by a crash, which would garble the ones after.
+    file.set_len(index.delta.len.unwrap_or((LOG_MAGIC.len() + 8) as u64))?;
+    file.write_all(&buf)?;
+    file.sync_data()?;
+    tracing::debug!(
+        model,
+        entries = index.len() + added.len() - gone.len(),
+        "ann log appended"
+    );
+    Ok(())
+}
+
+/// Sync the index of every model that has stored embeddings or an index
+/// file, removing the files of models left without embeddings.
+pub fn sync_all(conn: &Connection, cfg: &Config) -> Result<()> {
+    let dir = dir(cfg);
+    let mut stmt = conn.prepare("SELECT DISTINCT model_id FROM embeddings")?;
+    let mut models = stmt
+        .query_map([], |r| r.get::<_, String>(0))?
+        .collect::<rusqlite::Result<HashSet<_>>>()?;
+    if dir.exists() {
+        for entry in dir.read_dir_utf8()? {
+            let path = entry?.into_path();
+            if path.extension() == Some("hnsw") {
+                let mut file = BufReader::new(File::open(&path)?);
+                models.insert(read_header(&mut file).with_context(|| format!("read {path}"))?);
+            }
+        }
+    }
+    for model in models {
+        sync(conn, &dir, &model)?;
+    }
+    Ok(())
+}
+
+/// The index of one model: its memory-mapped snapshot and the changes logged
+/// since the snapshot was written.
+pub struct Index {
+    snapshot: Snapshot,
+    delta: Delta,
+    len: usize,
+}
+
+impl Index {
+    fn new(snapshot: Snapshot, delta: Delta) -> Self {
+        let len = snapshot.count - snapshot.deleted - delta.removed.len() + delta.added.len();
+        Self {
+            snapshot,
+            delta,
+            len,
+        }
+    }
+
+    pub fn dim(&self) -> usize {
+        self.snapshot.dim
+    }
+
+    /// Number of live entries.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.delta.added.contains_key(id)
+            || (!self.delta.removed.contains(id) && self.snapshot.contains(id))
+    }
+
+    fn live_ids(&self) -> impl Iterator<Item = &str> {
+        (0..self.snapshot.count as u32)
+            .filter(|&n| !self.snapshot.is_removed(n))
+            .map(|n| self.snapshot.id(n))
+            .filter(|id| !self.delta.removed.contains(*id))
+            .chain(self.delta.added.keys().map(String::as_str))
+    }
+
+    /// The `k` live entries most similar to `query`, best first.
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(&str, f32)> {
+        // Entries removed since the snapshot take room in its results.
+        let mut hits: Vec<(&str, f32)> =
+            search(&self.snapshot, query, k + self.delta.removed.len())
+                .into_iter()
+                .filter(|(id, _)| !self.delta.removed.contains(*id))
+                .collect();
+        hits.extend(
+            self.delta
+                .added
+                .iter()
+                .map(|(id, vector)| (id.as_str(), dot(query, vector))),
+        );
+        hits.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(b.0)));
+        hits.truncate(k);
+        hits
+    }
+
+    /// Load the snapshot into memory and apply the log to it.
+    fn to_hnsw(&self) -> Result<Hnsw> {
+        let s = &self.snapshot;
+        let mut index = Hnsw::new(&s.model, s.dim);
+        index.entry = s.entry;
+        index.rng = s.rng;
+        for node in 0..s.count as u32 {
+            let id = s.id(node).to_string();
+            index.by_id.insert(id.clone(), node);
+            index.ids.push(id);
+            index.vectors.extend(
+                s.vector(node)
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes(b.try_into().unwrap())),
+            );
+            let links = (0..=s.top_layer(node))
+                .map(|layer| {
+                    let mut links = Vec::new();
+                    s.neighbours(node, layer, |n| links.push(n));
+                    links
+                })
+                .collect();
+            index.links.push(links);
+            index.removed.push(s.is_removed(node));
+        }
+        index.deleted = s.deleted;
+        for id in &self.delta.removed {
+            index.remove(id);
+        }
+        let mut added: Vec<_> = self.delta.added.iter().collect();
+        added.sort_by(|a, b| a.0.cmp(b.0));
+        for (id, vector) in added {
+            index.insert(id, vector)?;
+        }
+        Ok(index)
+    }
+}
+
+/// Read access to a graph, held in memory or memory-mapped.
+trait Graph {
+    fn node_count(&self) -> usize;
+    fn entry(&self) -> Option<u32>;
+    fn top_layer(&self, node: u32) -> usize;
+    fn neighbours(&self, node: u32, layer: usize, f: impl FnMut(u32));
+    fn sim(&self, query: &[f32], node: u32) -> f32;
+    fn is_removed(&self, node: u32) -> bool;
+    fn id(&self, node: u32) -> &str;
+}
+
+/// The `k` live entries of `graph` most similar to `query`, best first.
+fn search<'a>(graph: &'a impl Graph, query: &[f32], k: usize) -> Vec<(&'a str, f32)> {
+    let Some(entry) = graph.entry() else {
+        return Vec::new();
+    };
+    let mut entry_points = vec![entry];
+    for layer in (1..=graph.top_layer(entry)).rev() {
+        entry_points = vec![search_layer(graph, query, &entry_points, 1, layer)[0].node];
+    }
+    search_layer(graph, query, &entry_points, EF_SEARCH.max(2 * k), 0)
+        .into_iter()
+        .filter(|s| !graph.is_removed(s.node))
+        .take(k)
+        .map(|s| (graph.id(s.node), s.sim))
+        .collect()
+}
+
+/// Best-first search of one layer, returning up to `ef` nodes sorted by
+/// decreasing similarity.
+fn search_layer(
+    graph: &impl Graph,
+    query: &[f32],
+    entry_points: &[u32],
+    ef: usize,
+    layer: usize,
+) -> Vec<Scored> {
+    // One bit per node.
+    let mut visited = vec![0u64; graph.node_count().div_ceil(64)];
+    let mut visit = |n: u32| {
+        let (word, bit) = (n as usize / 64, 1u64 << (n % 64));
+        let fresh = visited[word] & bit == 0;
+        visited[word] |= bit;
+        fresh
+    };
+    for &node in entry_points {
+        visit(node);
+    }
+    let mut candidates = BinaryHeap::new();
+    let mut results = BinaryHeap::new();
+    for &node in entry_points {
+        let scored = Scored {
+            sim: graph.sim(query, node),
+            node,
+        };
+        candidates.push(scored);
+        results.push(Reverse(scored));
+    }
+    while results.len() > ef {
+        results.pop();
+    }
+    while let Some(current) = candidates.pop() {
+        let worst = results.peek().map_or(f32::MIN, |r| r.0.sim);
+        if current.sim < worst && results.len() >= ef {
+            break;
+        }
+        graph.neighbours(current.node, layer, |n| {
+            if !visit(n) {
+                return;
+            }
+            let scored = Scored {
+                sim: graph.sim(query, n),
+                node: n,
+            };
+            let worst = results.peek().map_or(f32::MIN, |r| r.0.sim);
+            if results.len() < ef || scored.sim > worst {
+                candidates.push(scored);
+                results.push(Reverse(scored));
+                if results.len() > ef {
+                    results.pop();
+                }
+            }
+        });
+    }
+    let mut found: Vec<Scored> = results.into_iter().map(|r| r.0).collect();
+    found.sort_by(|a, b| b.cmp(a));
+    found
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Scored {
+    sim: f32,
+    node: u32,
+}
+
+impl Eq for Scored {}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.sim
+            .total_cmp(&other.sim)
+            .then(other.node.cmp(&self.node))
+    }
+}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// HNSW graph over vectors compared by dot product, held in memory while it
+/// is built.
+pub struct Hnsw {
+    model: String,
+    dim: usize,
+    ids: Vec<String>,
+    vectors: Vec<f32>,
+    /// Neighbours of each node on every layer from 0 up to its level.
+    links: Vec<Vec<Vec<u32>>>,
+    removed: Vec<bool>,
+    deleted: usize,
+    by_id: HashMap<String, u32>,
+    entry: Option<u32>,
+    rng: u64,
+}
+
+impl Hnsw {
+    pub fn new(model: &str, dim: usize) -> Self {
+        Self {
+            model: model.to_string(),
+            dim,
+            ids: Vec::new(),
+            vectors: Vec::new(),
+            links: Vec::new(),
+            removed: Vec::new(),
+            deleted: 0,
+            by_id: HashMap::new(),
+            entry: None,
+            rng: 0x9E37_79B9_7F4A_7C15,
+        }
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    /// Number of live entries.
+    pub fn len(&self) -> usize {
+        self.ids.len() - self.deleted
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.by_id
+            .get(id)
+            .is_some_and(|&n| !self.removed[n as usize])
+    }
+
+    /// Add `vector` under `id`. An id that is already present keeps its
+    /// vector, since chunk ids are derived from the chunk text.
+    pub fn insert(&mut self, id: &str, vector: &[f32]) -> Result<()> {
+        if vector.len() != self.dim {
+            bail!(
+                "cannot index a {}-dimensional vector in a {}-dimensional index",
+                vector.len(),
+                self.dim
+            );
+        }
+        if let Some(&node) = self.by_id.get(id) {
+            if self.removed[node as usize] {
+                self.removed[node as usize] = false;
+                self.deleted -= 1;
+            }
+            return Ok(());
+        }
+        let node = self.ids.len() as u32;
+        let level = self.random_level();
+        self.ids.push(id.to_string());
+        self.vectors.extend_from_slice(vector);
+        self.links.push(vec![Vec::new(); level + 1]);
+        self.removed.push(false);
+        self.by_id.insert(id.to_string(), node);
+        let Some(entry) = self.entry else {
+            self.entry = Some(node);
+            return Ok(());
+        };
+
+        let top = self.links[entry as usize].len() - 1;
+        let mut entry_points = vec![entry];
+        for layer in (level + 1..=top).rev() {
+            entry_points = vec![search_layer(self, vector, &entry_points, 1, layer)[0].node];
+        }
+        for layer in (0..=level.min(top)).rev() {
+            let found = search_layer(self, vector, &entry_points, EF_CONSTRUCTION, layer);
+            let max = max_links(layer);
+            let neighbours = self.select(&found, max);
+            for &n in &neighbours {
+                let links = &mut self.links[n as usize][layer];
+                links.push(node);
+                if links.len() > max {
+                    self.prune(n, layer);
+                }
+            }
+            self.links[node as usize][layer] = neighbours;
+            entry_points = found.iter().map(|s| s.node).collect();
+        }
+        if level > top {
+            self.entry = Some(node);
+        }
+        Ok(())
+    }
+
+    /// Exclude `id` from results. Its node stays in the graph for
+    /// navigation until the index is rebuilt.
+    pub fn remove(&mut self, id: &str) {
+        if let Some(&node) = self.by_id.get(id) {
+            if !self.removed[node as usize] {
+                self.removed[node as usize] = true;
+                self.deleted += 1;
+            }
+        }
+    }
+
+    /// The `k` live entries most similar to `query`, best first.
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(&str, f32)> {
+        search(self, query, k)
+    }
+
+    /// A new graph holding only the live entries.
+    fn rebuilt(&self) -> Result<Self> {
+        let mut index = Hnsw::new(&self.model, self.dim);
+        for node in (0..self.ids.len() as u32).filter(|&n| !self.removed[n as usize]) {
+            index.insert(&self.ids[node as usize], self.vector(node))?;
+        }
+        Ok(index)
+    }
+
+    fn vector(&self, node: u32) -> &[f32] {
+        let start = node as usize * self.dim;
+        &self.vectors[start..start + self.dim]
+    }
+
+    fn random_level(&mut self) -> usize {
+        // xorshift64*
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let bits = self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11;
+        let uniform = (bits as f64 + 1.0) / (1u64 << 53) as f64;
+        ((-uniform.ln() / (M as f64).ln()) as usize).min(MAX_LEVEL)
+    }
+
+    /// Pick up to `max` neighbours among `candidates`, sorted by decreasing
+    /// similarity to the base node. A candidate closer to an already
+    /// selected neighbour than to the base is skipped at first, which keeps
+    /// links spread in all directions; skipped candidates fill any room left.
+    fn select(&self, candidates: &[Scored], max: usize) -> Vec<u32> {
+        let mut selected: Vec<u32> = Vec::with_capacity(max);
+        let mut skipped = Vec::new();
+        for c in candidates {
+            if selected.len() == max {
+                break;
+            }
+            let v = self.vector(c.node);
+            if selected.iter().all(|&s| self.sim(v, s) < c.sim) {
+                selected.push(c.node);
+            } else {
+                skipped.push(c.node);
+            }
+        }
+        for node in skipped {
+            if selected.len() == max {
+                break;
+            }
+            selected.push(node);
+        }
+        selected
+    }
+
+    fn prune(&mut self, node: u32, layer: usize) {
+        let base = self.vector(node);
+        let mut scored: Vec<Scored> = self.links[node as usize][layer]
+            .iter()
+            .map(|&n| Scored {
+                sim: self.sim(base, n),
+                node: n,
+            })
+            .collect();
+        scored.sort_by(|a, b| b.cmp(a));
+        self.links[node as usize][layer] = self.select(&scored, max_links(layer));
+    }
+
+    /// Write the graph in the snapshot layout read by [`Snapshot::open`]:
+    /// a header, then the vectors, the links of each node with their
+    /// offsets, the removal flags, and the ids with their offsets and their
+    /// order. Sections start on 8-byte boundaries.
+    fn write(&self, w: &mut impl Write, generation: u64) -> Result<()> {
+        let mut w = Counting { inner: w, pos: 0 };
+        let count = self.ids.len();
+        w.bytes(MAGIC)?;
+        w.u64(self.model.len() as u64)?;
+        w.bytes(self.model.as_bytes())?;
+        w.align()?;
+        let link_words =
+            |links: &Vec<Vec<u32>>| 1 + links.iter().map(|l| 1 + l.len()).sum::<usize>();
+        let links_len: usize = self.links.iter().map(link_words).sum();
+        for v in [
+            self.dim as u64,
+            count as u64,
+            self.entry.map_or(u64::MAX, u64::from),
+            self.rng,
+            generation,
+            links_len as u64,
+        ] {
+            w.u64(v)?;
+        }
+        for x in &self.vectors {
+            w.bytes(&x.to_le_bytes())?;
+        }
+        w.align()?;
+        let mut offset = 0;
+        for links in &self.links {
+            w.u64(offset as u64)?;
+            offset += link_words(links);
+        }
+        w.u64(offset as u64)?;
+        for links in &self.links {
+            w.u32(links.len() as u32)?;
+            for layer in links {
+                w.u32(layer.len() as u32)?;
+                for &n in layer {
+                    w.u32(n)?;
+                }
+            }
+        }
+        w.align()?;
+        for &removed in &self.removed {
+            w.bytes(&[removed as u8])?;
+        }
+        w.align()?;
+        let mut offset = 0;
+        for id in &self.ids {
+            w.u64(offset as u64)?;
+            offset += id.len();
+        }
+        w.u64(offset as u64)?;
+        let mut sorted: Vec<u32> = (0..count as u32).collect();
+        sorted.sort_by(|&a, &b| self.ids[a as usize].cmp(&self.ids[b as usize]));
+        for node in sorted {
+            w.u32(node)?;
+        }
+        w.align()?;
+        for id in &self.ids {
+            w.bytes(id.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl Graph for Hnsw {
+    fn node_count(&self) -> usize {
+        self.ids.len()
+    }
+
+    fn entry(&self) -> Option<u32> {
+        self.entry
+    }
+
+    fn top_layer(&self, node: u32) -> usize {
+        self.links[node as usize].len() - 1
+    }
+
+    fn neighbours(&self, node: u32, layer: usize, mut f: impl FnMut(u32)) {
+        for &n in &self.links[node as usize][layer] {
+            f(n);
+        }
+    }
+
+    fn sim(&self, query: &[f32], node: u32) -> f32 {
+        dot(query, self.vector(node))
+    }
+
+    fn is_removed(&self, node: u32) -> bool {
+        self.removed[node as usize]
+    }
+
+    fn id(&self, node: u32) -> &str {
+        &self.ids[node as usize]
+    }
+}
+
+/// A graph written by [`Hnsw::write`], memory-mapped so searching it only
+/// reads the pages of the nodes visited.
+struct Snapshot {
+    map: Mmap,
+    model: String,
+    dim: usize,
+    count: usize,
+    entry: Option<u32>,
+    rng: u64,
+    generation: u64,
+    /// Nodes flagged as removed.
+    deleted: usize,
+    // Byte offsets of the sections.
+    vectors: usize,
+    link_offsets: usize,
+    links: usize,
+    removed: usize,
+    id_offsets: usize,
+    sorted: usize,
+    ids: usize,
+}
+
+impl Snapshot {
+    /// Map the snapshot at `path`, or `None` when it has the previous format.
+    fn open(path: &Utf8Path) -> Result<Option<Self>> {
+        let file = File::open(path)?;
+        // SAFETY: snapshots are never modified in place. `save` renames a new
+        // file over them, which leaves existing mappings on the old one.
+        let map = unsafe { Mmap::map(&file)? };
+        match map.get(..MAGIC.len()) {
+            Some(magic) if magic == MAGIC => {}
+            Some(magic) if magic == MAGIC_V1 => return Ok(None),
+            _ => bail!("not a findx ANN index"),
+        }
+        let mut c = Cursor {
+            pos: MAGIC.len(),
+            len: map.len(),
+        };
+        let model_len = u64_at(&map, c.take(8)?) as usize;
+        let model = std::str::from_utf8(&map[c.take(model_len)?..c.pos])?.to_string();
+        c.align();
+        let mut header = [0u64; 6];
+        for v in &mut header {
+            *v = u64_at(&map, c.take(8)?);
+        }
+        let [dim, count, entry, rng, generation, links_len] = header;
+        let (dim, count) = (dim as usize, count as usize);
+        let size = |n: usize, width: usize| n.checked_mul(width).context("corrupt ANN index");
+        let vectors = c.take(size(count, dim * 4)?)?;
+        c.align();
+        let link_offsets = c.take(size(count + 1, 8)?)?;
+        let links = c.take(size(links_len as usize, 4)?)?;
+        c.align();
+        let removed = c.take(count)?;
+        c.align();
+        let id_offsets = c.take(size(count + 1, 8)?)?;
+        let sorted = c.take(size(count, 4)?)?;
+        c.align();
+        let ids = c.take(u64_at(&map, id_offsets + count * 8) as usize)?;
+        let deleted = map[removed..removed + count]
+            .iter()
+            .filter(|&&r| r != 0)
+            .count();
+        Ok(Some(Self {
+            model,
+            dim,
+            count,
+            entry: (entry != u64::MAX).then_some(entry as u32),
+            rng,
+            generation,
+            deleted,
+            vectors,
+            link_offsets,
+            links,
+            removed,
+            id_offsets,
+            sorted,
+            ids,
+            map,
+        }))
+    }
+
+    fn vector(&self, node: u32) -> &[u8] {
+        let start = self.vectors + node as usize * self.dim * 4;
+        &self.map[start..start + self.dim * 4]
+    }
+
+    /// Byte offset of the links of `node`, starting with its layer count.
+    fn links_of(&self, node: u32) -> usize {
+        self.links + 4 * u64_at(&self.map, self.link_offsets + 8 * node as usize) as usize
+    }
+
+    /// Whether `id` is a live entry, found by binary search over the ids in
+    /// order.
+    fn contains(&self, id: &str) -> bool {
+        let (mut lo, mut hi) = (0, self.count);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let node = u32_at(&self.map, self.sorted + 4 * mid);
+            match self.id(node).cmp(id) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => return !self.is_removed(node),
+            }
+        }
+        false
+    }
+}
+
+impl Graph for Snapshot {
+    fn node_count(&self) -> usize {
+        self.count
+    }
+
+    fn entry(&self) -> Option<u32> {
+        self.entry
+    }
+
+    fn top_layer(&self, node: u32) -> usize {
+        u32_at(&self.map, self.links_of(node)) as usize - 1
+    }
+
+    fn neighbours(&self, node: u32, layer: usize, mut f: impl FnMut(u32)) {
+        let mut at = self.links_of(node) + 4;
+        for _ in 0..layer {
+            at += 4 * (1 + u32_at(&self.map, at) as usize);
+        }
+        let len = u32_at(&self.map, at) as usize;
+        for i in 0..len {
+            f(u32_at(&self.map, at + 4 * (1 + i)));
+        }
+    }
+
+    fn sim(&self, query: &[f32], node: u32) -> f32 {
+        query
+            .iter()
+            .zip(self.vector(node).chunks_exact(4))
+            .map(|(a, b)| a * f32::from_le_bytes(b.try_into().unwrap()))
+            .sum()
+    }
+
+    fn is_removed(&self, node: u32) -> bool {
+        self.map[self.removed + node as usize] != 0
+    }
+
+    fn id(&self, node: u32) -> &str {
+        let at = self.id_offsets + 8 * node as usize;
+        let (start, end) = (u64_at(&self.map, at), u64_at(&self.map, at + 8));
+        std::str::from_utf8(&self.map[self.ids + start as usize..self.ids + end as usize])
+            .unwrap_or_default()
+    }
+}
+
+/// Changes logged since a snapshot was written.
+#[derive(Default)]
+struct Delta {
+    /// Entries added, with their vectors.
+    added: HashMap<String, Vec<f32>>,
+    /// Live snapshot entries removed.
+    removed: HashSet<String>,
+    records: usize,
+    /// Length of the complete records with the header, or `None` when there
+    /// is no log for the snapshot.
+    len: Option<u64>,
+}
+
+impl Delta {
+    /// Read the log at `path`, ignoring it unless it was started for
+    /// `snapshot`, and a trailing record cut short by a crash.
+    fn read(path: &Utf8Path, snapshot: &Snapshot) -> Result<Self> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let header = LOG_MAGIC.len() + 8;
+        if bytes.len() < header
+            || &bytes[..LOG_MAGIC.len()] != LOG_MAGIC
+            || u64_at(&bytes, LOG_MAGIC.len()) != snapshot.generation
+        {
+            return Ok(Self::default());
+        }
+        let mut delta = Self::default();
+        let mut c = Cursor {
+            pos: header,
+            len: bytes.len(),
+        };
+        while let Ok(tag) = c.take(1) {
+            let Ok(at) = c.take(8) else { break };
+            let Ok(start) = c.take(u64_at(&bytes, at) as usize) else {
+                break;
+            };
+            let id = std::str::from_utf8(&bytes[start..c.pos])?.to_string();
+            match bytes[tag] {
+                LOG_INSERT => {
+                    let Ok(start) = c.take(snapshot.dim * 4) else {
+                        break;
+                    };
+                    let vector = bytes[start..c.pos]
+                        .chunks_exact(4)
+                        .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                        .collect();
+                    if !delta.removed.remove(&id) {
+                        delta.added.insert(id, vector);
+                    }
+                }
+                LOG_REMOVE => {
+                    if delta.added.remove(&id).is_none() && snapshot.contains(&id) {
+                        delta.removed.insert(id);
+                    }
+                }
+                other => bail!("unknown ANN log record {other}"),
+            }
+            delta.records += 1;
+            delta.len = Some(c.pos as u64);
+        }
+        delta.len.get_or_insert(header as u64);
+        Ok(delta)
+    }
+}
+
+/// Position within a buffer of known length.
+struct Cursor {
+    pos: usize,
+    len: usize,
+}
+
+impl Cursor {
+    /// Start of the next `len` bytes, failing past the end of the buffer.
+    fn take(&mut self, len: usize) -> Result<usize> {
+        let start = self.pos;
+        self.pos = start
+            .checked_add(len)
+            .filter(|&end| end <= self.len)
+            .context("truncated ANN index")?;
+        Ok(start)
+    }
+
+    fn align(&mut self) {
+        self.pos = self.pos.next_multiple_of(8);
+    }
+}
+
+/// Writer keeping track of its position, to align sections.
+struct Counting<W> {
+    inner: W,
+    pos: usize,
+}
+
+impl<W: Write> Counting<W> {
+    fn bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.inner.write_all(bytes)?;
+        self.pos += bytes.len();
+        Ok(())
+    }
+
+    fn u32(&mut self, v: u32) -> Result<()> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn u64(&mut self, v: u64) -> Result<()> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn align(&mut self) -> Result<()> {
+        let pad = self.pos.next_multiple_of(8) - self.pos;
+        self.bytes(&[0; 8][..pad])
+    }
+}
+
+fn max_links(layer: usize) -> usize {
+    if layer == 0 {
+        2 * M
+    } else {
+        M
+    }
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+/// Check the magic bytes and return the model the index was built for.
+fn read_header(r: &mut impl Read) -> Result<String> {
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC && &magic != MAGIC_V1 {
+        bail!("not a findx ANN index");
+    }
+    let mut len = [0u8; 8];
+    r.read_exact(&mut len)?;
+    let mut bytes = vec![0u8; u64::from_le_bytes(len) as usize];
+    r.read_exact(&mut bytes)?;
+    Ok(String::from_utf8(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use tempfile::tempdir;
+
+    /// Deterministic unit vectors.
+    fn unit_vectors(dim: usize) -> impl Iterator<Item = Vec<f32>> {
+        let mut state = 42u64;
+        std::iter::repeat_with(move || {
+            let v: Vec<f32> = (0..dim)
+                .map(|_| {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    ((state >> 33) as f32 / (1u64 << 31) as f32) - 0.5
+                })
+                .collect();
+            let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+            v.into_iter().map(|x| x / norm).collect()
+        })
+    }
+
+    fn vectors(n: usize, dim: usize) -> Vec<Vec<f32>> {
+        unit_vectors(dim).take(n).collect()
+    }
+
+    #[test]
+    fn search_recalls_exact_neighbours() -> Result<()> {
+        let data = vectors(1000, 24);
+        let mut index = Hnsw::new("m", 24);
+        for (i, v) in data.iter().enumerate() {
+            index.insert(&format!("c{i}"), v)?;
+        }
+        let mut found = 0;
+        for q in vectors(20, 24) {
+            let mut exact: Vec<(usize, f32)> = data
+                .iter()
+                .enumerate()
+                .map(|(i, v)| (i, q.iter().zip(v).map(|(a, b)| a * b).sum()))
+                .collect();
+            exact.sort_by(|a, b| b.1.total_cmp(&a.1));
+            let expected: HashSet<String> =
+                exact[..10].iter().map(|(i, _)| format!("c{i}")).collect();
+            found += index
+                .search(&q, 10)
+                .iter()
+                .filter(|(id, _)| expected.contains(*id))
+                .count();
+        }
+        assert!(found >= 190, "recall {found}/200");
+        Ok(())
+    }
+
+    #[test]
+    fn sync_follows_the_embeddings_table() -> Result<()> {
+        let tmp = tempdir()?;
+        let dir = Utf8Path::from_path(tmp.path()).unwrap().join("ann");
+        let conn = db::open(Utf8Path::new(":memory:"))?;
+        let data = vectors(50, 8);
+        for (i, v) in data.iter().enumerate() {
+            conn.execute(
+                "INSERT INTO embeddings (chunk_id, model_id, dim, vec) VALUES (?1, 'm', 8, ?2)",
+                params![format!("c{i}"), embed::encode_vector(v)],
+            )?;
+        }
+        sync(&conn, &dir, "m")?;
+        let index = load(&dir, "m")?.unwrap();
+        assert_eq!(index.len(), 50);
+        assert_eq!(index.search(&data[7], 1)[0].0, "c7");
+
+        conn.execute("DELETE FROM embeddings WHERE chunk_id='c7'", [])?;
+        sync(&conn, &dir, "m")?;
+        let index = load(&dir, "m")?.unwrap();
+        assert_eq!(index.len(), 49);
+        assert!(index.search(&data[7], 5).iter().all(|(id, _)| *id != "c7"));
+
+        conn.execute("DELETE FROM embeddings", [])?;
+        sync(&conn, &dir, "m")?;
+        assert!(load(&dir, "m")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn sync_logs_changes_until_it_compacts() -> Result<()> {
+        let tmp = tempdir()?;
+        let dir = Utf8Path::from_path(tmp.path()).unwrap().join("ann");
+        let conn = db::open(Utf8Path::new(":memory:"))?;
+        let data = vectors(1200, 8);
+        let store = |ids: std::ops::Range<usize>| -> Result<()> {
+            for i in ids {
+                conn.execute(
+                    "INSERT INTO embeddings (chunk_id, model_id, dim, vec) VALUES (?1, 'm', 8, ?2)",
+                    params![format!("c{i}"), embed::encode_vector(&data[i])],
+                )?;
+            }
+            Ok(())
+        };
+        store(0..100)?;
+        sync(&conn, &dir, "m")?;
+        let snapshot = fs::read(index_path(&dir, "m"))?;
+
+        // Small changes are logged and leave the snapshot untouched.
+        store(100..110)?;
+        conn.execute("DELETE FROM embeddings WHERE chunk_id='c3'", [])?;
+        sync(&conn, &dir, "m")?;
+        assert_eq!(fs::read(index_path(&dir, "m"))?, snapshot);
+        let index = load(&dir, "m")?.unwrap();
+        assert_eq!(index.len(), 109);
+        assert_eq!(index.search(&data[105], 1)[0].0, "c105");
+        assert!(!index.contains("c3"));
+        assert!(index.search(&data[3], 5).iter().all(|(id, _)| *id != "c3"));
+
+        // A record cut short by a crash is ignored, then overwritten.
+        let log = log_path(&dir, "m");
+        OpenOptions::new()
+            .append(true)
+            .open(&log)?
+            .write_all(&[LOG_INSERT, 2, 0])?;
+        assert_eq!(load(&dir, "m")?.unwrap().len(), 109);
+        store(110..111)?;
+        sync(&conn, &dir, "m")?;
+        assert!(load(&dir, "m")?.unwrap().contains("c110"));
+
+        // Past the threshold the log is folded into a new snapshot.
+        store(111..1200)?;
+        sync(&conn, &dir, "m")?;
+        assert_ne!(fs::read(index_path(&dir, "m"))?, snapshot);
+        assert_eq!(fs::metadata(&log)?.len(), (LOG_MAGIC.len() + 8) as u64);
+        let index = load(&dir, "m")?.unwrap();
+        assert_eq!(index.len(), 1199);
+        assert_eq!(index.search(&data[1150], 1)[0].0, "c1150");
+        assert_eq!(index.search(&data[105], 1)[0].0, "c105");
+        assert!(!index.contains("c3"));
+        Ok(())
+    }
+
+    /// Open and query an index the way a `findx search` process does. Run
+    /// with `cargo test --release ann::tests::query_latency -- --ignored
+    /// --nocapture`, sizing the index with `FINDX_ANN_BENCH_CHUNKS`.
+    #[test]
+    #[ignore]
+    fn query_latency() -> Result<()> {
+        let n: usize = std::env::var("FINDX_ANN_BENCH_CHUNKS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(100_000);
+        let dim = 384;
+        let tmp = tempdir()?;
+        let dir = Utf8Path::from_path(tmp.path()).unwrap().join("ann");
+        let mut index = Hnsw::new("m", dim);
+        for (i, v) in unit_vectors(dim).take(n).enumerate() {
+            index.insert(&format!("c{i}"), &v)?;
+        }
+        save(&dir, &index)?;
+        drop(index);
+
+        let mut times = Vec::new();
+        for q in unit_vectors(dim).skip(n).take(20) {
+            let start = std::time::Instant::now();
+            let index = load(&dir, "m")?.unwrap();
+            assert_eq!(index.search(&q, 10).len(), 10);
+            times.push(start.elapsed());
+        }
+        times.sort();
+        let median = times[times.len() / 2];
+        eprintln!(
+            "{n} chunks: median {median:?}, max {:?}",
+            times[times.len() - 1]
+        );
+        assert!(median < std::time::Duration::from_millis(100));
+        Ok(())
+    }
+}
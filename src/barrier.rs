@@ -0,0 +1,245 @@
+//! Completion barrier waiting for the extraction pipeline to drain after a scan.
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use crossbeam_channel::{select, Receiver};
+use serde::Serialize;
+
+use crate::bus::{Envelope, EventBus};
+use crate::config::Config;
+use crate::events::{MirrorEvent, SourceEvent};
+
+/// Outcome of the files requested for extraction by a scan.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ScanSummary {
+    /// Files extracted and written to the mirror.
+    pub extracted: u64,
+    /// Files whose extraction or mirroring failed.
+    pub failed: u64,
+    /// Files left untouched because their content was already extracted.
+    pub skipped: u64,
+}
+
+/// How long the pipeline may go without publishing anything before a wait
+/// gives up on it. Extracting one large document can take minutes.
+const STALL_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Tracks the files of a scan until each one has been extracted and mirrored,
+/// has failed, or was skipped.
+pub struct ScanBarrier {
+    source: Receiver<Envelope<SourceEvent>>,
+    mirror: Receiver<Envelope<MirrorEvent>>,
+    allow_offline_hydration: bool,
+    stall_timeout: Duration,
+}
+
+impl ScanBarrier {
+    /// Subscribe to the bus. Must be called before the scan publishes its
+    /// `SyncDelta` so no pipeline event is missed.
+    pub fn new(bus: &EventBus, cfg: &Config) -> Self {
+        Self {
+            source: bus.subscribe_source(),
+            mirror: bus.subscribe_mirror(),
+            allow_offline_hydration: cfg.allow_offline_hydration,
+            stall_timeout: STALL_TIMEOUT,
+        }
+    }
+
+    /// Give up waiting once no pipeline event arrived for `timeout`.
+    pub fn stall_timeout(mut self, timeout: Duration) -> Self {
+        self.stall_timeout = timeout;
+        self
+    }
+
+    /// Block until the pipeline has settled every file added or modified by
+    /// the scan. Must be called once the scan has returned; when it published
+    /// no `SyncDelta` there is nothing to wait for. Fails when the pipeline
+    /// publishes nothing for the stall timeout, as when a stage has died.
+    pub fn wait(self) -> Result<ScanSummary> {
+        let mut summary = ScanSummary::default();
+        // The scan publishes synchronously, so its delta is already queued.
+        let mut pending: HashSet<String> = loop {
+            let Ok(env) = self.source.try_recv() else {
+                return Ok(summary);
+            };
+            if let SourceEvent::SyncDelta {
+                added, modified, ..
+            } = env.data
+            {
+                break added
+                    .into_iter()
+                    .chain(modified)
+                    .filter(|f| !f.is_offline || self.allow_offline_hydration)
+                    .map(|f| f.file_uid)
+                    .collect();
+            }
+        };
+
+        // Extraction and mirror events travel on separate topics, so either
+        // side of a completed extraction may be observed first.
+        let mut completed: HashSet<String> = HashSet::new();
+        let mut mirrored: HashMap<String, bool> = HashMap::new();
+        while !pending.is_empty() {
+            select! {
+                recv(self.source) -> env => match env?.data {
+                    SourceEvent::ExtractionCompleted { file_uid, .. } => {
+                        if let Some(ok) = mirrored.remove(&file_uid) {
+                            settle(&mut pending, &mut summary, &file_uid, ok);
+                        } else if pending.contains(&file_uid) {
+                            completed.insert(file_uid);
+                        }
+                    }
+                    SourceEvent::ExtractionFailed { file_uid, .. } => {
+                        settle(&mut pending, &mut summary, &file_uid, false);
+                    }
+                    SourceEvent::ExtractionSkipped { file_uid } if pending.remove(&file_uid) => {
+                        summary.skipped += 1;
+                    }
+                    _ => {}
+                },
+                recv(self.mirror) -> env => {
+                    let outcome = match env?.data {
+                        MirrorEvent::MirrorDocUpserted { file_uid, .. } => Some((file_uid, true)),
+                        MirrorEvent::MirrorDocDeleted { file_uid } => Some((file_uid, false)),
+                        _ => None,
+                    };
+                    if let Some((file_uid, ok)) = outcome {
+                        if completed.remove(&file_uid) {
+                            settle(&mut pending, &mut summary, &file_uid, ok);
+                        } else if pending.contains(&file_uid) {
+                            mirrored.insert(file_uid, ok);
+                        }
+                    }
+                }
+                default(self.stall_timeout) => bail!(
+                    "pipeline stalled: {} files still pending after {}s without progress",
+                    pending.len(),
+                    self.stall_timeout.as_secs()
+                ),
+            }
+        }
+        Ok(summary)
+    }
+}
+
+fn settle(pending: &mut HashSet<String>, summary: &mut ScanSummary, file_uid: &str, ok: bool) {
+    if pending.remove(file_uid) {
+        if ok {
+            summary.extracted += 1;
+        } else {
+            summary.failed += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ExtractConfig;
+    use crate::{db, extract, fs, metadata, mirror};
+    use camino::Utf8PathBuf;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    };
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn wait_counts_extracted_failed_and_skipped() -> Result<()> {
+        let tmp = tempdir()?;
+        let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
+        let cfg = Config {
+            roots: vec![root.join("docs")],
+            include: vec!["**/*.txt".into(), "**/*.pdf".into()],
+            default_language: "en".into(),
+            extract: ExtractConfig {
+                pool_size: 2,
+                jobs_bound: 16,
+            },
+            ..Config::for_test(&root)
+        };
+        let docs = &cfg.roots[0];
+        std::fs::create_dir_all(docs)?;
+        std::fs::write(docs.join("a.txt"), "alpha")?;
+        std::fs::write(docs.join("b.txt"), "beta")?;
+        // No extractor_cmd is configured, so the PDF fails to extract.
+        std::fs::write(docs.join("c.pdf"), "%PDF")?;
+
+        let bus = EventBus::new(&cfg.bus.bounds, Arc::new(Mutex::new(db::open(&cfg.db)?)));
+        let stop = Arc::new(AtomicBool::new(false));
+        for stage in [metadata::run, extract::run_pool, mirror::run] {
+            let bus_run = bus.clone();
+            let cfg_run = cfg.clone();
+            let stop_run = stop.clone();
+            std::thread::spawn(move || stage(bus_run, &cfg_run, &stop_run).unwrap());
+        }
+        std::thread::sleep(Duration::from_millis(200));
+
+        let barrier = ScanBarrier::new(&bus, &cfg);
+        fs::cold_scan(&cfg, &bus, &mut fs::FsState::default())?;
+        let summary = barrier.wait()?;
+        assert_eq!(
+            summary,
+            ScanSummary {
+                extracted: 2,
+                failed: 1,
+                skipped: 0
+            }
+        );
+        let docs_rows: i64 =
+            db::open(&cfg.db)?.query_row("SELECT COUNT(*) FROM documents", [], |r| r.get(0))?;
+        assert_eq!(docs_rows, 2);
+
+        // A fresh scan of unchanged content does not extract again, but
+        // retries the failed extraction.
+        let barrier = ScanBarrier::new(&bus, &cfg);
+        fs::cold_scan(&cfg, &bus, &mut fs::FsState::default())?;
+        let summary = barrier.wait()?;
+        assert_eq!(
+            summary,
+            ScanSummary {
+                extracted: 0,
+                failed: 1,
+                skipped: 2
+            }
+        );
+        let attempts: i64 = db::open(&cfg.db)?.query_row(
+            "SELECT attempt FROM extract_jobs WHERE status='failed'",
+            [],
+            |r| r.get(0),
+        )?;
+        assert_eq!(attempts, 2);
+
+        // A scan without changes publishes nothing and returns immediately.
+        let mut state = fs::FsState::default();
+        fs::cold_scan(&cfg, &bus, &mut state)?;
+        let barrier = ScanBarrier::new(&bus, &cfg);
+        fs::cold_scan(&cfg, &bus, &mut state)?;
+        assert_eq!(barrier.wait()?, ScanSummary::default());
+
+        stop.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    #[test]
+    fn wait_fails_when_the_pipeline_stalls() -> Result<()> {
+        let tmp = tempdir()?;
+        let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
+        let cfg = Config {
+            include: vec!["**/*.txt".into()],
+            ..Config::for_test(&root)
+        };
+        std::fs::write(root.join("a.txt"), "alpha")?;
+        let bus = EventBus::new(&cfg.bus.bounds, Arc::new(Mutex::new(db::open(&cfg.db)?)));
+
+        // No stage runs, so the scanned file is never extracted.
+        let barrier = ScanBarrier::new(&bus, &cfg).stall_timeout(Duration::from_millis(100));
+        fs::cold_scan(&cfg, &bus, &mut fs::FsState::default())?;
+        let err = barrier.wait().unwrap_err();
+        assert!(err.to_string().contains("1 files still pending"), "{err}");
+        Ok(())
+    }
+}
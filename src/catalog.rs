@@ -0,0 +1,215 @@
+//! Catalog writer persisting extraction output into the `documents` table.
+
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::chunk::TextChunk;
+use crate::events::PageBlock;
+
+/// Separator placed between pages in `documents.content_txt`, matching the
+/// form feed used by extractors to delimit pages.
+pub const PAGE_SEPARATOR: char = '\x0c';
+
+/// Store the extracted text of `file_uid` in `documents`, replacing any
+/// previous extraction. Returns the `files.id` of the document, or `None` when
+/// the file is not cataloged.
+pub fn write_document(
+    conn: &Connection,
+    file_uid: &str,
+    extractor: &str,
+    extractor_version: &str,
+    pages: &[PageBlock],
+    lang: Option<&str>,
+) -> Result<Option<i64>> {
+    let file_id: Option<i64> = conn
+        .query_row(
+            "SELECT id FROM files WHERE inode_hint=?1",
+            params![file_uid],
+            |r| r.get(0),
+        )
+        .optional()?;
+    let Some(file_id) = file_id else {
+        return Ok(None);
+    };
+    let content = pages
+        .iter()
+        .map(|p| p.text.as_str())
+        .collect::<Vec<_>>()
+        .join(&PAGE_SEPARATOR.to_string());
+    conn.execute(
+        "INSERT INTO documents (file_id, extractor, extractor_version, lang, page_count, content_txt, updated_ts) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) \
+         ON CONFLICT(file_id) DO UPDATE SET extractor=excluded.extractor, extractor_version=excluded.extractor_version, \
+         lang=excluded.lang, page_count=excluded.page_count, content_txt=excluded.content_txt, updated_ts=excluded.updated_ts",
+        params![
+            file_id,
+            extractor,
+            extractor_version,
+            lang,
+            pages.len() as i64,
+            content,
+            now()
+        ],
+    )?;
+    Ok(Some(file_id))
+}
+
+/// Replace the stored chunks of the document `file_id`, deleting the
+/// embeddings of the chunks that are gone. Returns the ids of those chunks.
+pub fn write_chunks(conn: &Connection, file_id: i64, chunks: &[TextChunk]) -> Result<Vec<String>> {
+    let kept: HashSet<&str> = chunks.iter().map(|c| c.chunk_id.as_str()).collect();
+    let gone: Vec<String> = conn
+        .prepare("SELECT chunk_id FROM chunks WHERE file_id=?1")?
+        .query_map(params![file_id], |r| r.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|id| !kept.contains(id.as_str()))
+        .collect();
+    let mut stmt = conn.prepare("DELETE FROM embeddings WHERE chunk_id=?1")?;
+    for id in &gone {
+        stmt.execute(params![id])?;
+    }
+    conn.execute("DELETE FROM chunks WHERE file_id=?1", params![file_id])?;
+    let mut stmt = conn.prepare(
+        "INSERT OR REPLACE INTO chunks (file_id, chunk_id, start_byte, end_byte, page_from, page_to, section_path, token_count, text, \
+         line_from, col_from, line_to, col_to) VALUES (?1, ?2, ?3, ?4, ?5, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+    )?;
+    for c in chunks {
+        stmt.execute(params![
+            file_id,
+            c.chunk_id,
+            c.start_byte as i64,
+            c.end_byte as i64,
+            c.page_no,
+            c.section,
+            c.tokens as i64,
+            c.text,
+            c.line_from as i64,
+            c.col_from as i64,
+            c.line_to as i64,
+            c.col_to as i64
+        ])?;
+    }
+    Ok(gone)
+}
+
+/// Remove the stored document, chunks and embeddings of `file_uid`.
+pub fn delete_document(conn: &Connection, file_uid: &str) -> Result<()> {
+    conn.execute(
+        "DELETE FROM embeddings WHERE chunk_id IN (SELECT c.chunk_id FROM chunks c \
+         JOIN files f ON f.id=c.file_id WHERE f.inode_hint=?1)",
+        params![file_uid],
+    )?;
+    conn.execute(
+        "DELETE FROM chunks WHERE file_id IN (SELECT id FROM files WHERE inode_hint=?1)",
+        params![file_uid],
+    )?;
+    conn.execute(
+        "DELETE FROM documents WHERE file_id IN (SELECT id FROM files WHERE inode_hint=?1)",
+        params![file_uid],
+    )?;
+    Ok(())
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::EventBus;
+    use crate::config::Config;
+    use crate::{db, extract, fs, index, metadata, mirror, search};
+    use camino::{Utf8Path, Utf8PathBuf};
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    };
+    use std::time::{Duration, Instant};
+    use tempfile::tempdir;
+
+    #[test]
+    fn write_document_upserts_pages() -> Result<()> {
+        let conn = db::open(Utf8Path::new(":memory:"))?;
+        conn.execute(
+            "INSERT INTO files (realpath, size, mtime_ns, fast_sig, is_offline, attrs, inode_hint, status, created_ts, updated_ts) VALUES ('a.txt',0,0,'sig',0,0,'f1','active',0,0)",
+            [],
+        )?;
+        let page = |no: u32, text: &str| PageBlock {
+            page_no: no,
+            text: text.into(),
+            start: 0,
+            end: text.chars().count(),
+        };
+        let id = write_document(&conn, "f1", "builtin", "", &[page(1, "one")], None)?;
+        assert!(id.is_some());
+        write_document(
+            &conn,
+            "f1",
+            "docling",
+            "2",
+            &[page(1, "one"), page(2, "two")],
+            Some("en"),
+        )?;
+        let (extractor, pages, lang, content): (String, i64, String, String) = conn.query_row(
+            "SELECT extractor, page_count, lang, content_txt FROM documents",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+        )?;
+        assert_eq!(extractor, "docling");
+        assert_eq!(pages, 2);
+        assert_eq!(lang, "en");
+        assert_eq!(content, "one\x0ctwo");
+        assert_eq!(write_document(&conn, "missing", "b", "", &[], None)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn keyword_hits_after_pipeline() -> Result<()> {
+        let tmp = tempdir()?;
+        let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
+        let cfg = Config {
+            roots: vec![root.join("docs")],
+            include: vec!["**/*.txt".into()],
+            default_language: "en".into(),
+            ..Config::for_test(&root)
+        };
+        std::fs::create_dir_all(&cfg.roots[0])?;
+        std::fs::write(cfg.roots[0].join("a.txt"), "the quick brown fox")?;
+
+        let bus = EventBus::new(&cfg.bus.bounds, Arc::new(Mutex::new(db::open(&cfg.db)?)));
+        let stop = Arc::new(AtomicBool::new(false));
+        for stage in [metadata::run, extract::run_pool, mirror::run] {
+            let bus_run = bus.clone();
+            let cfg_run = cfg.clone();
+            let stop_run = stop.clone();
+            std::thread::spawn(move || stage(bus_run, &cfg_run, &stop_run).unwrap());
+        }
+        std::thread::sleep(Duration::from_millis(200));
+
+        let mut state = fs::FsState::default();
+        fs::cold_scan(&cfg, &bus, &mut state)?;
+        let conn = db::open(&cfg.db)?;
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            let count: i64 = conn.query_row("SELECT COUNT(*) FROM documents", [], |r| r.get(0))?;
+            if count == 1 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        stop.store(true, Ordering::SeqCst);
+
+        index::reindex_all(&cfg, None)?;
+        let res = search::keyword(&cfg, "fox", 10, &search::SearchOptions::default())?;
+        assert_eq!(res.results.len(), 1);
+        Ok(())
+    }
+}
@@ -1,15 +1,414 @@
+//! Chunking of extracted text, shared by the mirror and the index so both
+//! refer to the same chunk ids and offsets.
+
+use std::collections::HashMap;
+
 use anyhow::Result;
-use blake3::Hasher;
-use rusqlite::{params, Connection};
+use rusqlite::Connection;
+use sha2::{Digest, Sha256};
 
-use crate::config::Config;
+use crate::catalog::{self, PAGE_SEPARATOR};
+use crate::config::{ChunkStrategy, ChunkingConfig, Config};
+use crate::events::PageBlock;
 use crate::util::log;
 
+/// A chunk of one page of a document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextChunk {
+    pub chunk_id: String,
+    pub page_no: u32,
+    /// Character offsets within the page text.
+    pub start_char: usize,
+    pub end_char: usize,
+    /// Byte offsets within the document text, whose pages are joined by
+    /// [`PAGE_SEPARATOR`] as in `documents.content_txt`.
+    pub start_byte: usize,
+    pub end_byte: usize,
+    /// 1-based line and column of the first character and of the last
+    /// non-whitespace character, counted over the whole document text with
+    /// columns in characters.
+    pub line_from: usize,
+    pub col_from: usize,
+    pub line_to: usize,
+    pub col_to: usize,
+    pub text: String,
+    pub tokens: usize,
+    /// Enclosing Markdown headings, outermost first, joined by ` > `. Only set
+    /// by the heading strategy.
+    pub section: Option<String>,
+}
+
+/// Splits the pages of a document into chunks according to `[chunking]`.
+#[derive(Debug, Clone)]
+pub struct Chunker {
+    strategy: ChunkStrategy,
+    max_tokens: usize,
+    overlap_tokens: usize,
+}
+
+/// A span of page text that is never split unless it exceeds `max_tokens`.
+#[derive(Debug, Clone, Copy)]
+struct Unit {
+    start: usize,
+    end: usize,
+    tokens: usize,
+}
+
+impl Chunker {
+    pub fn new(cfg: &ChunkingConfig) -> Self {
+        Self {
+            strategy: cfg.strategy,
+            max_tokens: cfg.max_tokens.max(1),
+            overlap_tokens: cfg.overlap_tokens,
+        }
+    }
+
+    /// Chunk `pages` of the document of catalog file `file_id` in order.
+    /// Chunks never span pages.
+    pub fn chunk(&self, file_id: i64, pages: &[PageBlock]) -> Vec<TextChunk> {
+        let mut out = Vec::new();
+        let mut seen: HashMap<String, usize> = HashMap::new();
+        let mut page_base = 0usize;
+        let mut page_pos = (1, 1);
+        for page in pages {
+            let text = page.text.as_str();
+            let lines = Lines::new(text, page_pos);
+            for (section, units) in self.sections(text) {
+                for (start, end) in self.pack(&units) {
+                    let chunk_text = &text[start..end];
+                    let (line_from, col_from) = lines.at(start);
+                    let (line_to, col_to) = lines.at(last_char(text, start, end));
+                    let start_char = text[..start].chars().count();
+                    let end_char = start_char + chunk_text.chars().count();
+                    let normalized = normalize(chunk_text);
+                    let occurrence = seen.entry(normalized.clone()).or_default();
+                    let id = chunk_id(file_id, &normalized, *occurrence);
+                    *occurrence += 1;
+                    out.push(TextChunk {
+                        chunk_id: id,
+                        page_no: page.page_no,
+                        start_char,
+                        end_char,
+                        start_byte: page_base + start,
+                        end_byte: page_base + end,
+                        line_from,
+                        col_from,
+                        line_to,
+                        col_to,
+                        text: chunk_text.to_string(),
+                        tokens: chunk_text.split_whitespace().count(),
+                        section: section.clone(),
+                    });
+                }
+            }
+            page_base += text.len() + PAGE_SEPARATOR.len_utf8();
+            // The page separator occupies one column.
+            let (line, col) = lines.at(text.len());
+            page_pos = (line, col + 1);
+        }
+        out
+    }
+
+    /// Split page text into groups of units that chunks may not cross, each
+    /// with its heading path.
+    fn sections(&self, text: &str) -> Vec<(Option<String>, Vec<Unit>)> {
+        let groups = match self.strategy {
+            ChunkStrategy::FixedTokens => vec![(None, words(text, 0, text.len()))],
+            ChunkStrategy::Sentence => vec![(None, sentences(text))],
+            ChunkStrategy::Paragraph => vec![(None, paragraphs(text, 0, text.len()))],
+            ChunkStrategy::Heading => headings(text)
+                .into_iter()
+                .map(|(path, start, end)| (path, paragraphs(text, start, end)))
+                .collect(),
+        };
+        groups
+            .into_iter()
+            .map(|(path, units)| (path, self.split_oversized(text, units)))
+            .filter(|(_, units)| units.iter().any(|u| u.tokens > 0))
+            .collect()
+    }
+
+    /// Replace units longer than `max_tokens` by their words.
+    fn split_oversized(&self, text: &str, units: Vec<Unit>) -> Vec<Unit> {
+        units
+            .into_iter()
+            .flat_map(|u| {
+                if u.tokens > self.max_tokens {
+                    words(text, u.start, u.end)
+                } else {
+                    vec![u]
+                }
+            })
+            .collect()
+    }
+
+    /// Greedily pack consecutive units into byte ranges of at most
+    /// `max_tokens`, starting each chunk with up to `overlap_tokens` taken from
+    /// the end of the previous one.
+    fn pack(&self, units: &[Unit]) -> Vec<(usize, usize)> {
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < units.len() {
+            let mut j = i;
+            let mut tokens = 0;
+            while j < units.len() && (j == i || tokens + units[j].tokens <= self.max_tokens) {
+                tokens += units[j].tokens;
+                j += 1;
+            }
+            out.push((units[i].start, units[j - 1].end));
+            if j == units.len() {
+                break;
+            }
+            let mut k = j;
+            let mut overlap = 0;
+            while k > i + 1 && overlap + units[k - 1].tokens <= self.overlap_tokens {
+                overlap += units[k - 1].tokens;
+                k -= 1;
+            }
+            i = k;
+        }
+        out
+    }
+}
+
+/// Line and column lookup for offsets within one page, continuing from the
+/// position where the page starts in the document.
+struct Lines<'a> {
+    text: &'a str,
+    /// Byte offset of each line start within the page.
+    starts: Vec<usize>,
+    first: (usize, usize),
+}
+
+impl<'a> Lines<'a> {
+    fn new(text: &'a str, first: (usize, usize)) -> Self {
+        let starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self {
+            text,
+            starts,
+            first,
+        }
+    }
+
+    /// 1-based line and column of the byte offset `at`.
+    fn at(&self, at: usize) -> (usize, usize) {
+        let idx = self.starts.partition_point(|&s| s <= at) - 1;
+        let col = self.text[self.starts[idx]..at].chars().count();
+        if idx == 0 {
+            (self.first.0, self.first.1 + col)
+        } else {
+            (self.first.0 + idx, col + 1)
+        }
+    }
+}
+
+/// Byte offset of the last non-whitespace character of `text[start..end]`,
+/// or `start` when there is none.
+fn last_char(text: &str, start: usize, end: usize) -> usize {
+    text[start..end]
+        .trim_end()
+        .char_indices()
+        .last()
+        .map_or(start, |(i, _)| start + i)
+}
+
+/// Each word of `text[start..end]` with its trailing whitespace. The first
+/// unit also covers any leading whitespace.
+fn words(text: &str, start: usize, end: usize) -> Vec<Unit> {
+    let slice = &text[start..end];
+    let mut starts: Vec<usize> = Vec::new();
+    let mut prev_ws = true;
+    for (i, c) in slice.char_indices() {
+        let ws = c.is_whitespace();
+        if prev_ws && !ws {
+            starts.push(i);
+        }
+        prev_ws = ws;
+    }
+    if starts.is_empty() {
+        return Vec::new();
+    }
+    starts[0] = 0;
+    let mut units: Vec<Unit> = starts
+        .windows(2)
+        .map(|w| Unit {
+            start: start + w[0],
+            end: start + w[1],
+            tokens: 1,
+        })
+        .collect();
+    units.push(Unit {
+        start: start + starts[starts.len() - 1],
+        end,
+        tokens: 1,
+    });
+    units
+}
+
+/// Split `text[start..end]` into units ending at each boundary returned by
+/// `boundaries`, which yields byte offsets relative to the slice.
+fn split_at(text: &str, start: usize, end: usize, boundaries: Vec<usize>) -> Vec<Unit> {
+    let mut units = Vec::new();
+    let mut from = start;
+    for b in boundaries.into_iter().map(|b| start + b).chain([end]) {
+        if b > from {
+            units.push(Unit {
+                start: from,
+                end: b,
+                tokens: text[from..b].split_whitespace().count(),
+            });
+            from = b;
+        }
+    }
+    units
+}
+
+/// Sentences end after `.`, `!`, `?` or their CJK forms followed by
+/// whitespace, and at blank lines.
+fn sentences(text: &str) -> Vec<Unit> {
+    let mut boundaries = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let next = chars.peek().map(|&(_, n)| n);
+        let ends = match c {
+            '。' | '！' | '？' => true,
+            '.' | '!' | '?' => next.is_none_or(char::is_whitespace),
+            '\n' => next == Some('\n'),
+            _ => false,
+        };
+        if ends {
+            // Keep the following whitespace with the sentence it ends.
+            let mut b = i + c.len_utf8();
+            while let Some(&(j, n)) = chars.peek() {
+                if !n.is_whitespace() {
+                    break;
+                }
+                b = j + n.len_utf8();
+                chars.next();
+            }
+            boundaries.push(b);
+        }
+    }
+    split_at(text, 0, text.len(), boundaries)
+}
+
+/// Paragraphs of `text[start..end]` end at blank lines.
+fn paragraphs(text: &str, start: usize, end: usize) -> Vec<Unit> {
+    let slice = &text[start..end];
+    let mut boundaries = Vec::new();
+    let mut offset = 0;
+    let mut blank_run = false;
+    for line in slice.split_inclusive('\n') {
+        let blank = line.trim().is_empty();
+        if blank_run && !blank {
+            boundaries.push(offset);
+        }
+        blank_run = blank;
+        offset += line.len();
+    }
+    split_at(text, start, end, boundaries)
+}
+
+/// Split text into sections starting at Markdown headings, returning the
+/// heading path and byte range of each.
+fn headings(text: &str) -> Vec<(Option<String>, usize, usize)> {
+    let mut sections = Vec::new();
+    let mut stack: Vec<(usize, String)> = Vec::new();
+    let mut current: Option<String> = None;
+    let mut from = 0;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        if let Some((level, title)) = heading(line) {
+            if offset > from {
+                sections.push((current.clone(), from, offset));
+            }
+            stack.retain(|(l, _)| *l < level);
+            stack.push((level, title.to_string()));
+            current = Some(
+                stack
+                    .iter()
+                    .map(|(_, t)| t.as_str())
+                    .collect::<Vec<_>>()
+                    .join(" > "),
+            );
+            from = offset;
+        }
+        offset += line.len();
+    }
+    if text.len() > from {
+        sections.push((current, from, text.len()));
+    }
+    sections
+}
+
+/// Parse a Markdown ATX heading line into its level and title.
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let trimmed = line.trim_start();
+    let level = trimmed.chars().take_while(|&c| c == '#').count();
+    if !(1..=6).contains(&level) {
+        return None;
+    }
+    let rest = &trimmed[level..];
+    if !rest.starts_with([' ', '\t']) {
+        return None;
+    }
+    let title = rest.trim().trim_end_matches('#').trim_end();
+    (!title.is_empty()).then_some((level, title))
+}
+
+/// Identifier of a chunk, derived from the catalog file id and the normalized
+/// chunk text only, so text that survives a rename or an edit elsewhere in the
+/// document keeps its id and its embeddings. The file id rather than the
+/// `file_uid` is used because the catalog keeps it for a path across atomic
+/// saves, which replace the file by a new inode. `occurrence` tells apart
+/// repeated identical chunks within a document.
+pub fn chunk_id(file_id: i64, normalized: &str, occurrence: usize) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(file_id.to_be_bytes());
+    hasher.update([0]);
+    hasher.update(normalized.as_bytes());
+    hasher.update([0]);
+    hasher.update(occurrence.to_be_bytes());
+    format!("ch:{:x}", hasher.finalize())
+}
+
+/// Hash of the normalized chunk text, keying cached embeddings so identical
+/// text is embedded once whatever document it appears in.
+pub fn text_hash(text: &str) -> String {
+    format!("{:x}", Sha256::digest(normalize(text).as_bytes()))
+}
+
+/// Chunk text with line endings and runs of whitespace collapsed to single
+/// spaces and the ends trimmed.
+pub fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Split `documents.content_txt` back into its pages.
+pub fn split_pages(content: &str) -> Vec<PageBlock> {
+    let mut pages = Vec::new();
+    let mut offset = 0usize;
+    for (i, p) in content.split(PAGE_SEPARATOR).enumerate() {
+        let len = p.chars().count();
+        pages.push(PageBlock {
+            page_no: (i + 1) as u32,
+            text: p.to_string(),
+            start: offset,
+            end: offset + len,
+        });
+        offset += len + 1;
+    }
+    pages
+}
+
 /// Chunk all active documents in the database.
 pub fn chunk_all(conn: &Connection, cfg: &Config) -> Result<()> {
+    let chunker = Chunker::new(&cfg.chunking);
     let mut stmt = conn.prepare(
-        "SELECT f.id, f.realpath, IFNULL(d.content_txt,'' ) FROM files f \
-         JOIN documents d ON f.id=d.file_id WHERE f.status='active'",
+        "SELECT f.id, f.realpath, IFNULL(d.content_txt, '') \
+         FROM files f JOIN documents d ON f.id=d.file_id WHERE f.status='active'",
     )?;
     let rows = stmt.query_map([], |row| {
         Ok((
@@ -20,43 +419,149 @@ pub fn chunk_all(conn: &Connection, cfg: &Config) -> Result<()> {
     })?;
     for row in rows {
         let (file_id, path, content) = row?;
-        let count = chunk_document(conn, file_id, &path, &content)?;
-        log::append(cfg, &format!("chunks\t{}\t{}", path, count))?;
+        let chunks = chunker.chunk(file_id, &split_pages(&content));
+        catalog::write_chunks(conn, file_id, &chunks)?;
+        log::append(cfg, &format!("chunks\t{}\t{}", path, chunks.len()))?;
     }
     Ok(())
 }
 
-fn chunk_document(conn: &Connection, file_id: i64, path: &str, content: &str) -> Result<usize> {
-    conn.execute("DELETE FROM chunks WHERE file_id=?1", params![file_id])?;
-    let chunk_size = 2000; // bytes
-    let overlap = 200; // bytes
-    let mut start = 0;
-    let len = content.len();
-    let mut count = 0usize;
-    while start < len {
-        let mut end = std::cmp::min(start + chunk_size, len);
-        while end < len && !content.is_char_boundary(end) {
-            end += 1;
-        }
-        let text = &content[start..end];
-        let token_count = text.split_whitespace().count() as i64;
-        let mut hasher = Hasher::new();
-        hasher.update(path.as_bytes());
-        hasher.update(start.to_string().as_bytes());
-        hasher.update(end.to_string().as_bytes());
-        let chunk_id = hasher.finalize().to_hex().to_string();
-        conn.execute(
-            "INSERT INTO chunks (file_id, chunk_id, start_byte, end_byte, token_count, text) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-            params![file_id, chunk_id, start as i64, end as i64, token_count, text],
-        )?;
-        count += 1;
-        if end == len {
-            break;
-        }
-        start = end.saturating_sub(overlap);
-        while start > 0 && !content.is_char_boundary(start) {
-            start += 1;
-        }
-    }
-    Ok(count)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunker(strategy: ChunkStrategy, max_tokens: usize, overlap_tokens: usize) -> Chunker {
+        Chunker::new(&ChunkingConfig {
+            strategy,
+            max_tokens,
+            overlap_tokens,
+        })
+    }
+
+    fn texts(chunker: &Chunker, content: &str) -> Vec<String> {
+        chunker
+            .chunk(1, &split_pages(content))
+            .into_iter()
+            .map(|c| c.text)
+            .collect()
+    }
+
+    #[test]
+    fn chunk_id_deterministic() {
+        let a = chunk_id(1, &normalize("test\n"), 0);
+        let b = chunk_id(1, &normalize("test\r\n"), 0);
+        let c = chunk_id(1, &normalize("  test   \r\n"), 0);
+        assert_eq!(a, b);
+        assert_eq!(a, c);
+        assert_ne!(a, chunk_id(2, "test", 0));
+        assert_ne!(a, chunk_id(1, "test", 1));
+    }
+
+    #[test]
+    fn chunk_ids_survive_edits_elsewhere() {
+        let c = chunker(ChunkStrategy::Paragraph, 3, 0);
+        let ids = |content: &str| -> Vec<String> {
+            c.chunk(1, &split_pages(content))
+                .into_iter()
+                .map(|c| c.chunk_id)
+                .collect()
+        };
+        let before = ids("Alpha beta gamma.\n\nDelta epsilon.\n\nDelta epsilon.");
+        assert_eq!(before.len(), 3);
+        assert_ne!(before[1], before[2]);
+        let after = ids("A new intro.\n\nAlpha beta gamma.\n\nDelta epsilon.\n\nDelta epsilon.");
+        assert_eq!(&after[1..], &before[..]);
+        // Pages and offsets do not matter either.
+        let moved = ids("Intro\x0cAlpha beta gamma.\n\nDelta epsilon.\n\nDelta epsilon.");
+        assert_eq!(&moved[1..], &before[..]);
+    }
+
+    #[test]
+    fn fixed_tokens_overlap_and_offsets() {
+        let c = chunker(ChunkStrategy::FixedTokens, 3, 1);
+        assert_eq!(
+            texts(&c, "a b c d e"),
+            ["a b c ", "c d e"].map(String::from)
+        );
+
+        let content = "one two\x0cthree été four";
+        let chunks = chunker(ChunkStrategy::FixedTokens, 2, 0).chunk(1, &split_pages(content));
+        let spans: Vec<_> = chunks
+            .iter()
+            .map(|c| {
+                (
+                    c.page_no,
+                    c.start_char,
+                    c.end_char,
+                    &content[c.start_byte..c.end_byte],
+                )
+            })
+            .collect();
+        assert_eq!(
+            spans,
+            [
+                (1, 0, 7, "one two"),
+                (2, 0, 10, "three été "),
+                (2, 10, 14, "four"),
+            ]
+        );
+    }
+
+    #[test]
+    fn chunks_record_line_and_column_ranges() {
+        let content = "fn main() {\n    run();\n}\n\nfn run() {}\x0cnext page";
+        let chunks = chunker(ChunkStrategy::Paragraph, 6, 0).chunk(1, &split_pages(content));
+        let got: Vec<_> = chunks
+            .iter()
+            .map(|c| (c.page_no, c.line_from, c.col_from, c.line_to, c.col_to))
+            .collect();
+        assert_eq!(got, [(1, 1, 1, 3, 1), (1, 5, 1, 5, 11), (2, 5, 13, 5, 21)]);
+    }
+
+    #[test]
+    fn sentence_and_paragraph_strategies_keep_units_whole() {
+        let text = "First one here. Second one. Third sentence is longer than the rest.";
+        assert_eq!(
+            texts(&chunker(ChunkStrategy::Sentence, 7, 0), text),
+            [
+                "First one here. Second one. ",
+                "Third sentence is longer than the rest."
+            ]
+            .map(String::from)
+        );
+
+        let text = "Para one\nstill one.\n\nPara two.\n\n\nPara three is here.";
+        assert_eq!(
+            texts(&chunker(ChunkStrategy::Paragraph, 6, 0), text),
+            [
+                "Para one\nstill one.\n\nPara two.\n\n\n",
+                "Para three is here."
+            ]
+            .map(String::from)
+        );
+        // A paragraph longer than the limit falls back to word windows.
+        assert_eq!(
+            texts(&chunker(ChunkStrategy::Paragraph, 2, 0), "a b c"),
+            ["a b ", "c"].map(String::from)
+        );
+    }
+
+    #[test]
+    fn heading_strategy_records_sections() {
+        let text = "Preamble.\n# Intro\nHello.\n## Scope\nDetails here.\n# Usage\nRun it.\n";
+        let chunks = chunker(ChunkStrategy::Heading, 100, 0).chunk(1, &split_pages(text));
+        let got: Vec<_> = chunks
+            .iter()
+            .map(|c| (c.section.as_deref(), c.text.as_str()))
+            .collect();
+        assert_eq!(
+            got,
+            [
+                (None, "Preamble.\n"),
+                (Some("Intro"), "# Intro\nHello.\n"),
+                (Some("Intro > Scope"), "## Scope\nDetails here.\n"),
+                (Some("Usage"), "# Usage\nRun it.\n"),
+            ]
+        );
+    }
 }
@@ -99,21 +99,128 @@ pub struct QueryArgs {
     #[arg(value_name = "QUERY")]
     pub query: String,
 
+    /// Return chunks instead of whole files
     #[arg(long, default_value_t = false)]
     pub chunks: bool,
+
+    /// Include a snippet of the matching text with highlighted terms
+    #[arg(long, default_value_t = false)]
+    pub snippets: bool,
+
+    /// Maximum length of a snippet in characters
+    #[arg(long, value_name = "N", default_value_t = 200)]
+    pub snippet_chars: usize,
+
+    /// Embedding model to query, among those already embedded; defaults to
+    /// the active model
+    #[arg(long, value_name = "NAME")]
+    pub model: Option<String>,
+
+    /// Score every stored embedding instead of using the approximate
+    /// nearest neighbour index
+    #[arg(long, default_value_t = false)]
+    pub exact: bool,
+
+    /// How hybrid search merges keyword and semantic results
+    #[arg(long, value_enum, default_value = "rrf")]
+    pub fusion: Fusion,
+
+    /// Keyword candidates fetched for hybrid search
+    #[arg(long, value_name = "N", default_value_t = 100)]
+    pub keyword_depth: usize,
+
+    /// Semantic candidates fetched for hybrid search
+    #[arg(long, value_name = "N", default_value_t = 100)]
+    pub semantic_depth: usize,
+
+    /// Constant k of reciprocal rank fusion, which scores 1 / (k + rank)
+    #[arg(long, value_name = "K", default_value_t = 60.0)]
+    pub rrf_k: f32,
+
+    /// Weight of keyword results in hybrid search
+    #[arg(long, value_name = "W", default_value_t = 1.0)]
+    pub keyword_weight: f32,
+
+    /// Weight of semantic results in hybrid search
+    #[arg(long, value_name = "W", default_value_t = 1.0)]
+    pub semantic_weight: f32,
+
+    /// How chunk scores make up a file's score in semantic and hybrid search
+    #[arg(long, value_enum, default_value = "max")]
+    pub aggregate: Aggregate,
+
+    /// Chunks summed per file by `--aggregate top-sum`
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    pub top_n: usize,
+
+    /// Chunk candidates grouped into files in semantic and hybrid search
+    #[arg(long, value_name = "N", default_value_t = 200)]
+    pub chunk_depth: usize,
+
+    /// Best-matching chunks listed with each file
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    pub chunks_per_file: usize,
+
+    /// Only files under this path or starting with this prefix; repeat for
+    /// several
+    #[arg(long = "path", value_name = "PREFIX")]
+    pub paths: Vec<String>,
+
+    /// Only files with one of these extensions
+    #[arg(long = "ext", value_delimiter = ',', value_name = "EXTS")]
+    pub exts: Vec<String>,
+
+    /// Only files of one of these MIME types; `type/*` matches every subtype
+    #[arg(long = "mime", value_delimiter = ',', value_name = "TYPES")]
+    pub mimes: Vec<String>,
+
+    /// Only documents detected in one of these languages
+    #[arg(long = "lang", value_delimiter = ',', value_name = "CODES")]
+    pub langs: Vec<String>,
+
+    /// Only files modified at or after this time (YYYY-MM-DD or RFC 3339)
+    #[arg(long, value_name = "TIME")]
+    pub modified_after: Option<String>,
+
+    /// Only files modified before this time (YYYY-MM-DD or RFC 3339)
+    #[arg(long, value_name = "TIME")]
+    pub modified_before: Option<String>,
+
+    /// Only files of at least this many bytes
+    #[arg(long, value_name = "BYTES")]
+    pub min_size: Option<u64>,
+
+    /// Only files of at most this many bytes
+    #[arg(long, value_name = "BYTES")]
+    pub max_size: Option<u64>,
 }
 
-#[derive(Copy, Clone, Debug, ValueEnum)]
+#[derive(Copy, Clone, Debug, Default, ValueEnum)]
 pub enum QueryMode {
     Keyword,
     Semantic,
+    #[default]
     Hybrid,
 }
 
-impl Default for QueryMode {
-    fn default() -> Self {
-        QueryMode::Hybrid
-    }
+#[derive(Copy, Clone, Debug, Default, ValueEnum)]
+pub enum Fusion {
+    /// Reciprocal rank fusion
+    #[default]
+    Rrf,
+    /// Weighted sum of min-max normalized scores
+    Linear,
+}
+
+#[derive(Copy, Clone, Debug, Default, ValueEnum)]
+pub enum Aggregate {
+    /// Score of the best chunk
+    #[default]
+    Max,
+    /// Sum of the scores of the best `--top-n` chunks
+    TopSum,
+    /// Mean score of the matching chunks
+    Mean,
 }
 
 #[derive(Args, Debug, Default)]
@@ -1,12 +1,235 @@
+use std::collections::HashMap;
 use std::fs;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use camino::Utf8PathBuf;
 use serde::Deserialize;
 
-#[derive(Debug, Deserialize, Clone)]
+/// Settings of the embedding stage. The model, service and paths can also be
+/// set through `EMBEDDING_*` environment variables, which take precedence;
+/// see [`EmbeddingConfig::apply_env`].
+#[derive(Deserialize, Clone)]
 pub struct EmbeddingConfig {
     pub provider: String,
+    /// Embedding model id. Defaults to the `url` of an external service, or
+    /// to the default fastembed model.
+    pub model: Option<String>,
+    /// URL of an OpenAI-compatible embedding service. Chunks are embedded
+    /// locally with fastembed when unset.
+    pub url: Option<String>,
+    /// Bearer token sent to the external service.
+    pub api_key: Option<String>,
+    /// Payload format of the external service.
+    #[serde(default)]
+    pub api: ExternalApi,
+    /// Timeout of each request to the external service.
+    #[serde(default = "default_embed_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Retries of a request that failed to connect, timed out or was
+    /// answered with 429 or a 5xx status.
+    #[serde(default = "default_embed_max_retries")]
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for each following one unless
+    /// the service sends `Retry-After`.
+    #[serde(default = "default_embed_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+    /// Where fastembed caches the models it downloads.
+    #[serde(default = "default_embed_cache_dir")]
+    pub cache_dir: Utf8PathBuf,
+    /// Directory of user-provided ONNX models, one subdirectory per model
+    /// name, tried before fastembed's own models.
+    #[serde(default = "default_embed_models_dir")]
+    pub models_dir: Utf8PathBuf,
+    /// Number of embedding workers.
+    #[serde(default = "default_embed_pool_size")]
+    pub pool_size: usize,
+    /// Number of chunks sent to the embedder per call.
+    #[serde(default = "default_embed_batch_size")]
+    pub batch_size: usize,
+    /// Capacity of the queue of chunks waiting for a worker.
+    #[serde(default = "default_jobs_bound")]
+    pub jobs_bound: usize,
+    /// Encoding of the vectors stored in the catalog.
+    #[serde(default)]
+    pub quantization: Quantization,
+    /// Query and passage templates by model id, replacing the built-in ones.
+    #[serde(default)]
+    pub prompts: HashMap<String, PromptTemplates>,
+}
+
+/// Templates wrapping a text before it is embedded, with `{text}` standing
+/// for the text.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct PromptTemplates {
+    /// Template of search queries.
+    pub query: Option<String>,
+    /// Template of chunk texts.
+    pub passage: Option<String>,
+}
+
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        Self {
+            provider: "disabled".into(),
+            model: None,
+            url: None,
+            api_key: None,
+            api: ExternalApi::OpenAi,
+            timeout_secs: default_embed_timeout_secs(),
+            max_retries: default_embed_max_retries(),
+            retry_backoff_ms: default_embed_retry_backoff_ms(),
+            cache_dir: default_embed_cache_dir(),
+            models_dir: default_embed_models_dir(),
+            pool_size: default_embed_pool_size(),
+            batch_size: default_embed_batch_size(),
+            jobs_bound: default_jobs_bound(),
+            quantization: Quantization::None,
+            prompts: HashMap::new(),
+        }
+    }
+}
+
+// Written by hand so logging the configuration does not leak the API key.
+impl std::fmt::Debug for EmbeddingConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EmbeddingConfig")
+            .field("provider", &self.provider)
+            .field("model", &self.model)
+            .field("url", &self.url)
+            .field("api_key", &self.api_key.as_ref().map(|_| "<redacted>"))
+            .field("api", &self.api)
+            .field("timeout_secs", &self.timeout_secs)
+            .field("max_retries", &self.max_retries)
+            .field("retry_backoff_ms", &self.retry_backoff_ms)
+            .field("cache_dir", &self.cache_dir)
+            .field("models_dir", &self.models_dir)
+            .field("pool_size", &self.pool_size)
+            .field("batch_size", &self.batch_size)
+            .field("jobs_bound", &self.jobs_bound)
+            .field("quantization", &self.quantization)
+            .field("prompts", &self.prompts)
+            .finish()
+    }
+}
+
+impl EmbeddingConfig {
+    /// Override settings with the environment variables that are set:
+    /// `EMBEDDING_MODEL`, `EMBEDDING_URL`, `EMBEDDING_API_KEY`,
+    /// `EMBEDDING_BATCH_SIZE`, `EMBEDDING_TIMEOUT_SECS`, `EMBEDDING_CACHE_DIR`
+    /// and `EMBEDDING_MODELS_DIR`.
+    pub fn apply_env(&mut self) -> Result<()> {
+        self.apply_vars(|name| std::env::var(name).ok())
+    }
+
+    fn apply_vars(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<()> {
+        if let Some(model) = var("EMBEDDING_MODEL") {
+            self.model = Some(model);
+        }
+        if let Some(url) = var("EMBEDDING_URL") {
+            self.url = Some(url);
+        }
+        if let Some(key) = var("EMBEDDING_API_KEY") {
+            self.api_key = Some(key);
+        }
+        if let Some(size) = var("EMBEDDING_BATCH_SIZE") {
+            self.batch_size = size
+                .parse()
+                .with_context(|| format!("invalid EMBEDDING_BATCH_SIZE `{size}`"))?;
+        }
+        if let Some(secs) = var("EMBEDDING_TIMEOUT_SECS") {
+            self.timeout_secs = secs
+                .parse()
+                .with_context(|| format!("invalid EMBEDDING_TIMEOUT_SECS `{secs}`"))?;
+        }
+        if let Some(dir) = var("EMBEDDING_CACHE_DIR") {
+            self.cache_dir = dir.into();
+        }
+        if let Some(dir) = var("EMBEDDING_MODELS_DIR") {
+            self.models_dir = dir.into();
+        }
+        Ok(())
+    }
+}
+
+fn default_embed_timeout_secs() -> u64 {
+    30
+}
+
+fn default_embed_max_retries() -> u32 {
+    5
+}
+
+fn default_embed_retry_backoff_ms() -> u64 {
+    500
+}
+
+/// Request and response shapes understood by the external embedder.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ExternalApi {
+    /// OpenAI `/v1/embeddings`: `{"input": [...]}` answered with
+    /// `{"data": [{"index": 0, "embedding": [...]}]}`.
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAi,
+    /// Ollama `/api/embed`: `{"model": "...", "input": [...]}` answered with
+    /// `{"embeddings": [[...]]}`.
+    Ollama,
+    /// Hugging Face Text Embeddings Inference `/embed`: `{"inputs": [...]}`
+    /// answered with `[[...]]`.
+    Tei,
+}
+
+fn default_embed_cache_dir() -> Utf8PathBuf {
+    Utf8PathBuf::from(".findx/fastembed_cache")
+}
+
+fn default_embed_models_dir() -> Utf8PathBuf {
+    Utf8PathBuf::from("models")
+}
+
+/// How embedding vectors are encoded in the catalog. Quantized vectors keep
+/// a full-precision copy in `vectors.db` for rescoring, created only once a
+/// vector is quantized.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Quantization {
+    /// Four-byte floats, as produced by the embedder.
+    #[default]
+    None,
+    /// One signed byte per component and a per-vector scale.
+    Int8,
+    /// One bit per component, set when it is positive.
+    Binary,
+}
+
+impl Quantization {
+    /// Name recorded in `embeddings.quantization`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Quantization::None => "none",
+            Quantization::Int8 => "int8",
+            Quantization::Binary => "binary",
+        }
+    }
+
+    /// Inverse of [`Quantization::as_str`].
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "none" => Ok(Quantization::None),
+            "int8" => Ok(Quantization::Int8),
+            "binary" => Ok(Quantization::Binary),
+            other => bail!("unknown quantization `{other}`"),
+        }
+    }
+}
+
+fn default_embed_pool_size() -> usize {
+    1
+}
+
+fn default_embed_batch_size() -> usize {
+    32
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -37,19 +260,11 @@ impl Default for BusBounds {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct BusConfig {
     pub bounds: BusBounds,
 }
 
-impl Default for BusConfig {
-    fn default() -> Self {
-        Self {
-            bounds: BusBounds::default(),
-        }
-    }
-}
-
 #[derive(Debug, Deserialize, Clone)]
 pub struct ExtractConfig {
     pub pool_size: usize,
@@ -109,6 +324,75 @@ fn default_files_tombstone_days() -> u64 {
     30
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct IndexConfig {
+    /// ISO 639-1 codes of the languages given their own analyzed fields.
+    /// Changing the list requires a reindex.
+    #[serde(default = "default_index_languages")]
+    pub languages: Vec<String>,
+    /// Drop each language's stop words at index and query time. Changing it
+    /// requires a reindex.
+    #[serde(default)]
+    pub stop_words: bool,
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        Self {
+            languages: default_index_languages(),
+            stop_words: false,
+        }
+    }
+}
+
+fn default_index_languages() -> Vec<String> {
+    vec!["en".into(), "fr".into()]
+}
+
+/// How extracted text is split into chunks.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkStrategy {
+    /// Windows of `max_tokens` whitespace-separated tokens.
+    FixedTokens,
+    /// Whole sentences packed up to `max_tokens`.
+    Sentence,
+    /// Whole paragraphs packed up to `max_tokens`.
+    Paragraph,
+    /// Paragraphs packed up to `max_tokens` without crossing a Markdown
+    /// heading; chunks record their enclosing headings.
+    Heading,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ChunkingConfig {
+    #[serde(default = "default_chunk_strategy")]
+    pub strategy: ChunkStrategy,
+    #[serde(default = "default_chunk_max_tokens")]
+    pub max_tokens: usize,
+    /// Tokens repeated from the end of a chunk at the start of the next one.
+    #[serde(default)]
+    pub overlap_tokens: usize,
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        Self {
+            strategy: default_chunk_strategy(),
+            max_tokens: default_chunk_max_tokens(),
+            overlap_tokens: 0,
+        }
+    }
+}
+
+fn default_chunk_strategy() -> ChunkStrategy {
+    ChunkStrategy::FixedTokens
+}
+
+fn default_chunk_max_tokens() -> usize {
+    200
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub db: Utf8PathBuf,
@@ -136,6 +420,10 @@ pub struct Config {
     pub extract: ExtractConfig,
     #[serde(default)]
     pub retention: RetentionConfig,
+    #[serde(default)]
+    pub index: IndexConfig,
+    #[serde(default)]
+    pub chunking: ChunkingConfig,
 }
 
 impl Default for Config {
@@ -159,13 +447,13 @@ impl Default for Config {
             guard_interval_secs: 180,
             default_language: "auto".into(),
             extractor_cmd: default_extractor_cmd(),
-            embedding: EmbeddingConfig {
-                provider: "disabled".into(),
-            },
+            embedding: EmbeddingConfig::default(),
             mirror: MirrorConfig::default(),
             bus: BusConfig::default(),
             extract: ExtractConfig::default(),
             retention: RetentionConfig::default(),
+            index: IndexConfig::default(),
+            chunking: ChunkingConfig::default(),
         }
     }
 }
@@ -176,6 +464,35 @@ impl Config {
         let cfg: Self = toml::from_str(&content)?;
         Ok(cfg)
     }
+
+    /// Configuration for tests: the catalog, index and mirror live under
+    /// `root`, which is also the only source root, and the bus and
+    /// extraction pool are kept small.
+    #[cfg(test)]
+    pub(crate) fn for_test(root: &camino::Utf8Path) -> Self {
+        Self {
+            db: root.join("catalog.db"),
+            tantivy_index: root.join("idx"),
+            roots: vec![root.to_path_buf()],
+            include: vec![],
+            exclude: vec![],
+            extractor_cmd: String::new(),
+            mirror: MirrorConfig {
+                root: root.join("raw"),
+            },
+            bus: BusConfig {
+                bounds: BusBounds {
+                    source_fs: 16,
+                    mirror_text: 16,
+                },
+            },
+            extract: ExtractConfig {
+                pool_size: 1,
+                jobs_bound: 16,
+            },
+            ..Self::default()
+        }
+    }
 }
 
 fn default_extractor_cmd() -> String {
@@ -200,4 +517,37 @@ mod tests {
         assert_eq!(cfg.retention.jobs_failed_days, 14);
         assert_eq!(cfg.retention.files_tombstone_days, 30);
     }
+
+    #[test]
+    fn embedding_settings_from_toml_and_env() -> Result<()> {
+        let mut embedding: EmbeddingConfig = toml::from_str(
+            r#"
+            provider = "builtin"
+            model = "BAAI/bge-small-en-v1.5"
+            cache_dir = "cache"
+            api_key = "secret"
+            "#,
+        )?;
+        assert_eq!(embedding.url, None);
+        assert_eq!(embedding.timeout_secs, 30);
+        assert_eq!(embedding.models_dir, Utf8PathBuf::from("models"));
+        assert!(!format!("{embedding:?}").contains("secret"));
+
+        embedding.apply_vars(|name| match name {
+            "EMBEDDING_URL" => Some("http://localhost:8080/v1/embeddings".into()),
+            "EMBEDDING_BATCH_SIZE" => Some("8".into()),
+            _ => None,
+        })?;
+        assert_eq!(embedding.model.as_deref(), Some("BAAI/bge-small-en-v1.5"));
+        assert_eq!(
+            embedding.url.as_deref(),
+            Some("http://localhost:8080/v1/embeddings")
+        );
+        assert_eq!(embedding.batch_size, 8);
+        assert_eq!(embedding.cache_dir, Utf8PathBuf::from("cache"));
+        assert!(embedding
+            .apply_vars(|name| (name == "EMBEDDING_TIMEOUT_SECS").then(|| "soon".into()))
+            .is_err());
+        Ok(())
+    }
 }
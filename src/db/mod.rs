@@ -1,4 +1,4 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{Context, Result};
 use camino::Utf8Path;
@@ -12,6 +12,9 @@ pub fn open(path: &Utf8Path) -> Result<Connection> {
     }
     tracing::debug!(%path, "opening database");
     let conn = Connection::open(path.as_str()).with_context(|| format!("open db at {path}"))?;
+    // Pipeline stages each hold their own connection; wait for competing
+    // writers instead of failing with SQLITE_BUSY.
+    conn.busy_timeout(Duration::from_secs(5))?;
     conn.execute_batch(
         r#"
         PRAGMA journal_mode=WAL;
@@ -58,7 +61,11 @@ pub fn open(path: &Utf8Path) -> Result<Connection> {
           page_to INTEGER,
           section_path TEXT,
           token_count INTEGER,
-          text BLOB NOT NULL
+          text BLOB NOT NULL,
+          line_from INTEGER,
+          col_from INTEGER,
+          line_to INTEGER,
+          col_to INTEGER
         );
         CREATE INDEX IF NOT EXISTS chunks_file ON chunks(file_id);
         CREATE TABLE IF NOT EXISTS embeddings (
@@ -66,6 +73,7 @@ pub fn open(path: &Utf8Path) -> Result<Connection> {
           model_id TEXT NOT NULL,
           dim INTEGER NOT NULL,
           vec BLOB NOT NULL,
+          text_hash TEXT,
           PRIMARY KEY(chunk_id, model_id)
         );
         CREATE TABLE IF NOT EXISTS events (
@@ -101,9 +109,88 @@ pub fn open(path: &Utf8Path) -> Result<Connection> {
         CREATE INDEX IF NOT EXISTS mirror_chunks_file ON mirror_chunks(file_uid);
         "#,
     )?;
+    // Columns added after the initial schema, for catalogs created earlier.
+    for column in ["line_from", "col_from", "line_to", "col_to"] {
+        ensure_column(&conn, "chunks", column, "INTEGER")?;
+    }
+    ensure_column(&conn, "embeddings", "text_hash", "TEXT")?;
+    ensure_column(
+        &conn,
+        "embeddings",
+        "quantization",
+        "TEXT NOT NULL DEFAULT 'none'",
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS embeddings_text ON embeddings(text_hash, model_id)",
+        [],
+    )?;
     Ok(conn)
 }
 
+/// Attach `vectors.db`, which holds the full-precision copies of quantized
+/// embeddings next to the catalog so they do not weigh on it, as the
+/// `vectors` schema of `conn`. The file is only created when `create` is set,
+/// so catalogs that never quantized embeddings go without it. Returns whether
+/// the schema is attached. Must be called outside of a transaction.
+pub fn attach_vectors(conn: &Connection, create: bool) -> Result<bool> {
+    let attached: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_database_list WHERE name='vectors'",
+        [],
+        |r| r.get(0),
+    )?;
+    if attached {
+        return Ok(true);
+    }
+    let vectors = match conn.path() {
+        None | Some("") => ":memory:".to_string(),
+        Some(path) => Utf8Path::new(path).with_file_name("vectors.db").to_string(),
+    };
+    if !create && vectors != ":memory:" && !Utf8Path::new(&vectors).exists() {
+        return Ok(false);
+    }
+    conn.execute("ATTACH DATABASE ?1 AS vectors", params![vectors])?;
+    conn.execute_batch(
+        r#"
+        PRAGMA vectors.journal_mode=WAL;
+        CREATE TABLE IF NOT EXISTS vectors.full_vectors (
+          text_hash TEXT NOT NULL,
+          model_id TEXT NOT NULL,
+          vec BLOB NOT NULL,
+          PRIMARY KEY(text_hash, model_id)
+        );
+        "#,
+    )?;
+    Ok(true)
+}
+
+/// Add `column` to `table` unless it already exists.
+fn ensure_column(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+    let has_column = |conn: &Connection| -> Result<bool> {
+        let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+        let names = stmt.query_map([], |r| r.get::<_, String>(1))?;
+        for name in names {
+            if name? == column {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    };
+    if has_column(conn)? {
+        return Ok(());
+    }
+    let res = conn.execute(
+        &format!("ALTER TABLE {table} ADD COLUMN {column} {decl}"),
+        [],
+    );
+    // Another connection may have added it concurrently.
+    if let Err(e) = res {
+        if !has_column(conn)? {
+            return Err(e).with_context(|| format!("add column {table}.{column}"));
+        }
+    }
+    Ok(())
+}
+
 /// Insert a record into `ops_log`.
 pub fn log_op(
     conn: &Connection,
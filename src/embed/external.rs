@@ -0,0 +1,266 @@
+//! Client for external embedding services speaking the OpenAI, Ollama or
+//! Text Embeddings Inference formats.
+
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::blocking::{Client, Response};
+use reqwest::header::RETRY_AFTER;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::config::{EmbeddingConfig, ExternalApi};
+
+#[derive(Deserialize)]
+struct OpenAiResponse {
+    data: Vec<OpenAiItem>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiItem {
+    index: Option<usize>,
+    embedding: Vec<f32>,
+}
+
+#[derive(Deserialize)]
+struct OllamaResponse {
+    embeddings: Vec<Vec<f32>>,
+}
+
+pub struct ExternalEmbedder {
+    client: Client,
+    url: String,
+    api_key: Option<String>,
+    api: ExternalApi,
+    model: String,
+    batch_size: usize,
+    max_retries: u32,
+    backoff: Duration,
+}
+
+impl ExternalEmbedder {
+    /// Client for the service at `embedding.url`, asking it for `model`.
+    pub fn new(cfg: &EmbeddingConfig, model: &str) -> Result<Self> {
+        let url = cfg
+            .url
+            .clone()
+            .context("embedding.url is required for external embedding")?;
+        let client = Client::builder()
+            .timeout(Duration::from_secs(cfg.timeout_secs))
+            .build()
+            .context("failed to build HTTP client")?;
+        Ok(Self {
+            client,
+            url,
+            api_key: cfg.api_key.clone(),
+            api: cfg.api,
+            model: model.to_string(),
+            batch_size: cfg.batch_size.max(1),
+            max_retries: cfg.max_retries,
+            backoff: Duration::from_millis(cfg.retry_backoff_ms),
+        })
+    }
+
+    /// Embed `texts`, sending at most `embedding.batch_size` of them per
+    /// request. Vectors are returned in the order of `texts`.
+    pub fn embed(&self, texts: &[impl AsRef<str>]) -> Result<Vec<Vec<f32>>> {
+        let inputs: Vec<&str> = texts.iter().map(|t| t.as_ref()).collect();
+        let mut out = Vec::with_capacity(inputs.len());
+        for batch in inputs.chunks(self.batch_size) {
+            let vectors = self.embed_batch(batch)?;
+            if vectors.len() != batch.len() {
+                bail!(
+                    "external embedder returned {} vectors for {} inputs",
+                    vectors.len(),
+                    batch.len()
+                );
+            }
+            out.extend(vectors);
+        }
+        Ok(out)
+    }
+
+    fn embed_batch(&self, inputs: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let body = match self.api {
+            ExternalApi::OpenAi => json!({ "input": inputs, "model": self.model }),
+            ExternalApi::Ollama => json!({ "model": self.model, "input": inputs }),
+            ExternalApi::Tei => json!({ "inputs": inputs }),
+        };
+        let resp = self.send(&body)?;
+        let invalid = "invalid JSON from external embedder";
+        match self.api {
+            ExternalApi::OpenAi => order_by_index(resp.json::<OpenAiResponse>().context(invalid)?),
+            ExternalApi::Ollama => Ok(resp.json::<OllamaResponse>().context(invalid)?.embeddings),
+            ExternalApi::Tei => resp.json().context(invalid),
+        }
+    }
+
+    /// POST `body`, retrying with exponential backoff when the service cannot
+    /// be reached, times out, or answers 429 or a 5xx status.
+    fn send(&self, body: &Value) -> Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let mut rb = self.client.post(&self.url).json(body);
+            if let Some(key) = &self.api_key {
+                rb = rb.bearer_auth(key);
+            }
+            let (error, retry_after) = match rb.send() {
+                Ok(resp) if resp.status().is_success() => return Ok(resp),
+                Ok(resp) => {
+                    let status = resp.status();
+                    if status != StatusCode::TOO_MANY_REQUESTS && !status.is_server_error() {
+                        let text = resp.text().unwrap_or_default();
+                        bail!("external embedder returned {status}: {text}");
+                    }
+                    let retry_after = resp
+                        .headers()
+                        .get(RETRY_AFTER)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.trim().parse().ok())
+                        .map(Duration::from_secs);
+                    (anyhow!("external embedder returned {status}"), retry_after)
+                }
+                Err(e) if e.is_timeout() || e.is_connect() => {
+                    (anyhow!(e).context("failed to call external embedder"), None)
+                }
+                Err(e) => return Err(e).context("failed to call external embedder"),
+            };
+            if attempt == self.max_retries {
+                return Err(error.context(format!("giving up after {} attempts", attempt + 1)));
+            }
+            let delay = retry_after.unwrap_or(self.backoff.saturating_mul(1 << attempt.min(16)));
+            tracing::warn!(attempt, ?delay, error = %error, "retrying external embedder");
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+}
+
+/// Vectors of an OpenAI response in input order. Items without an `index`
+/// keep their position.
+fn order_by_index(resp: OpenAiResponse) -> Result<Vec<Vec<f32>>> {
+    let mut out: Vec<Option<Vec<f32>>> = vec![None; resp.data.len()];
+    for (pos, item) in resp.data.into_iter().enumerate() {
+        let index = item.index.unwrap_or(pos);
+        match out.get_mut(index) {
+            Some(slot @ None) => *slot = Some(item.embedding),
+            _ => bail!("external embedder returned an invalid or repeated index {index}"),
+        }
+    }
+    Ok(out.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread::JoinHandle;
+
+    /// Answer one request per connection with each of `responses` in turn,
+    /// returning the request bodies once all were served.
+    fn stub(responses: Vec<(u16, &'static str)>) -> (String, JoinHandle<Vec<Value>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/embed", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                bodies.push(read_body(&stream));
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} Stub\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+            bodies
+        });
+        (url, handle)
+    }
+
+    fn read_body(stream: &TcpStream) -> Value {
+        let mut reader = BufReader::new(stream);
+        let mut len = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            if let Some(v) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                len = v.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn config(url: String, api: ExternalApi) -> EmbeddingConfig {
+        EmbeddingConfig {
+            url: Some(url),
+            api,
+            batch_size: 2,
+            max_retries: 2,
+            retry_backoff_ms: 1,
+            ..EmbeddingConfig::default()
+        }
+    }
+
+    #[test]
+    fn openai_requests_are_batched_retried_and_reordered() -> Result<()> {
+        let (url, server) = stub(vec![
+            (503, "{}"),
+            (
+                200,
+                r#"{"data":[{"index":1,"embedding":[2.0]},{"index":0,"embedding":[1.0]}]}"#,
+            ),
+            (200, r#"{"data":[{"index":0,"embedding":[3.0]}]}"#),
+        ]);
+        let embedder = ExternalEmbedder::new(&config(url, ExternalApi::OpenAi), "m")?;
+        let vectors = embedder.embed(&["a", "b", "c"])?;
+        assert_eq!(vectors, [[1.0], [2.0], [3.0]]);
+        let bodies = server.join().unwrap();
+        assert_eq!(bodies.len(), 3);
+        assert_eq!(bodies[0], json!({ "input": ["a", "b"], "model": "m" }));
+        assert_eq!(bodies[1], bodies[0]);
+        assert_eq!(bodies[2], json!({ "input": ["c"], "model": "m" }));
+        Ok(())
+    }
+
+    #[test]
+    fn ollama_and_tei_payloads() -> Result<()> {
+        let (url, server) = stub(vec![(200, r#"{"embeddings":[[1.0],[2.0]]}"#)]);
+        let embedder = ExternalEmbedder::new(&config(url, ExternalApi::Ollama), "m")?;
+        assert_eq!(embedder.embed(&["a", "b"])?, [[1.0], [2.0]]);
+        let bodies = server.join().unwrap();
+        assert_eq!(bodies[0], json!({ "model": "m", "input": ["a", "b"] }));
+
+        let (url, server) = stub(vec![(200, "[[1.0],[2.0]]")]);
+        let embedder = ExternalEmbedder::new(&config(url, ExternalApi::Tei), "m")?;
+        assert_eq!(embedder.embed(&["a", "b"])?, [[1.0], [2.0]]);
+        let bodies = server.join().unwrap();
+        assert_eq!(bodies[0], json!({ "inputs": ["a", "b"] }));
+        Ok(())
+    }
+
+    #[test]
+    fn client_errors_fail_at_once_and_server_errors_after_retries() -> Result<()> {
+        let (url, server) = stub(vec![(400, r#"{"error":"bad input"}"#)]);
+        let embedder = ExternalEmbedder::new(&config(url, ExternalApi::OpenAi), "m")?;
+        let err = embedder.embed(&["a"]).unwrap_err();
+        assert!(err.to_string().contains("bad input"), "{err}");
+        assert_eq!(server.join().unwrap().len(), 1);
+
+        let (url, server) = stub(vec![(500, "{}"), (502, "{}"), (429, "{}")]);
+        let embedder = ExternalEmbedder::new(&config(url, ExternalApi::OpenAi), "m")?;
+        let err = embedder.embed(&["a"]).unwrap_err();
+        assert!(format!("{err:#}").contains("after 3 attempts"), "{err:#}");
+        assert_eq!(server.join().unwrap().len(), 3);
+        Ok(())
+    }
+}
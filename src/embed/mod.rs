@@ -0,0 +1,1046 @@
+//! Chunk embeddings: local and external embedders, the embedding cache and
+//! the embedding stage.
+
+use anyhow::{anyhow, bail, Context, Result};
+use camino::Utf8Path;
+use fastembed::{
+    EmbeddingModel, InitOptionsUserDefined, TextEmbedding, TextInitOptions, TokenizerFiles,
+    UserDefinedEmbeddingModel,
+};
+use once_cell::sync::Lazy;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::{fs, sync::Mutex};
+
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, TrySendError};
+
+use crate::bus::{Envelope, EventBus};
+use crate::config::{Config, EmbeddingConfig, Quantization};
+use crate::events::MirrorEvent;
+use crate::util::dashboard;
+use crate::util::log;
+use crate::{ann, chunk, db};
+
+mod external;
+pub mod prompt;
+pub mod quant;
+
+pub use external::ExternalEmbedder;
+pub use prompt::Prompts;
+
+/// Local embedder backed by fastembed.
+pub struct LocalEmbedder {
+    model: Mutex<TextEmbedding>,
+}
+
+impl LocalEmbedder {
+    /// Load `model_name` from `embedding.models_dir`, or else from fastembed,
+    /// downloading it into `embedding.cache_dir` the first time.
+    pub fn new(cfg: &EmbeddingConfig, model_name: &str) -> Result<Self> {
+        let model = if let Some(m) = load_local_model(&cfg.models_dir, model_name)? {
+            m
+        } else {
+            let parsed = model_name
+                .parse::<EmbeddingModel>()
+                .map_err(|_| anyhow!("unsupported embedding model '{}'", model_name))?;
+            fs::create_dir_all(&cfg.cache_dir)
+                .context("failed to create fastembed cache directory")?;
+            TextEmbedding::try_new(
+                TextInitOptions::new(parsed)
+                    .with_cache_dir(cfg.cache_dir.clone().into())
+                    .with_show_download_progress(true),
+            )
+            .with_context(|| {
+                format!(
+                    "failed to initialize fastembed TextEmbedding for '{}'",
+                    model_name
+                )
+            })?
+        };
+        Ok(Self {
+            model: Mutex::new(model),
+        })
+    }
+
+    pub fn embed(&self, texts: &[impl AsRef<str>]) -> Result<Vec<Vec<f32>>> {
+        let docs: Vec<String> = texts.iter().map(|t| t.as_ref().to_string()).collect();
+        let mut model = self.model.lock().unwrap();
+        let embs = model
+            .embed(docs, None)
+            .context("fastembed inference failed")?;
+        Ok(embs)
+    }
+
+    pub fn print_supported() {
+        eprintln!(
+            "fastembed supported models: {:?}",
+            TextEmbedding::list_supported_models()
+        );
+    }
+}
+
+fn load_local_model(models_dir: &Utf8Path, name: &str) -> Result<Option<TextEmbedding>> {
+    let base = models_dir.join(name);
+    if !base.exists() {
+        return Ok(None);
+    }
+
+    let onnx = {
+        let standard = base.join("model.onnx");
+        if standard.exists() {
+            standard
+        } else {
+            let quant = base.join("model_uint8.onnx");
+            if quant.exists() {
+                quant
+            } else {
+                return Ok(None);
+            }
+        }
+    };
+
+    let tokenizer = TokenizerFiles {
+        tokenizer_file: fs::read(base.join("tokenizer.json"))
+            .context("failed to read tokenizer.json")?,
+        config_file: fs::read(base.join("config.json")).context("failed to read config.json")?,
+        special_tokens_map_file: fs::read(base.join("special_tokens_map.json"))
+            .context("failed to read special_tokens_map.json")?,
+        tokenizer_config_file: fs::read(base.join("tokenizer_config.json"))
+            .context("failed to read tokenizer_config.json")?,
+    };
+
+    let ud_model = UserDefinedEmbeddingModel::new(
+        fs::read(&onnx).context("failed to read model file")?,
+        tokenizer,
+    );
+    let model = TextEmbedding::try_new_from_user_defined(ud_model, InitOptionsUserDefined::new())
+        .context("failed to initialize local TextEmbedding")?;
+    Ok(Some(model))
+}
+
+pub enum Embedder {
+    Local(Box<LocalEmbedder>),
+    External(ExternalEmbedder),
+}
+
+impl Embedder {
+    /// Embedder for `model`, served by `embedding.url` when it is set and by
+    /// fastembed otherwise.
+    pub fn new(cfg: &EmbeddingConfig, model: &str) -> Result<Self> {
+        if cfg.url.is_some() {
+            Ok(Self::External(ExternalEmbedder::new(cfg, model)?))
+        } else {
+            Ok(Self::Local(Box::new(LocalEmbedder::new(cfg, model)?)))
+        }
+    }
+
+    pub fn embed(&self, texts: &[impl AsRef<str>]) -> Result<Vec<Vec<f32>>> {
+        match self {
+            Embedder::Local(m) => m.embed(texts),
+            Embedder::External(m) => m.embed(texts),
+        }
+    }
+}
+
+/// Id of the active embedding model, recorded with every stored vector:
+/// `embedding.model` when set, else the default fastembed model. An external
+/// service at `embedding.url` must be given its model, as vectors of
+/// different models behind one URL would otherwise share an id.
+pub fn active_model(cfg: &EmbeddingConfig) -> Result<String> {
+    match (&cfg.model, &cfg.url) {
+        (Some(model), _) => Ok(model.clone()),
+        (None, Some(_)) => bail!("embedding.model is required when embedding.url is set"),
+        (None, None) => Ok(EmbeddingModel::MxbaiEmbedLargeV1.to_string()),
+    }
+}
+
+static EMBEDDERS: Lazy<Mutex<HashMap<String, Arc<Embedder>>>> = Lazy::new(Default::default);
+
+fn get_embedder(cfg: &EmbeddingConfig, model: &str) -> Result<Arc<Embedder>> {
+    let mut embedders = EMBEDDERS.lock().unwrap();
+    if let Some(embedder) = embedders.get(model) {
+        return Ok(embedder.clone());
+    }
+    let embedder = Arc::new(Embedder::new(cfg, model)?);
+    embedders.insert(model.to_string(), embedder.clone());
+    Ok(embedder)
+}
+
+/// Embed a single text with `model`, returning its vector representation.
+pub fn embed_text(cfg: &EmbeddingConfig, model: &str, text: &str) -> Result<Vec<f32>> {
+    let res = embed_batch(cfg, model, &[text])?;
+    Ok(res.into_iter().next().unwrap())
+}
+
+/// Embed a search query with `model`, wrapped in the model's query prompt.
+pub fn embed_query(cfg: &EmbeddingConfig, model: &str, query: &str) -> Result<Vec<f32>> {
+    embed_text(cfg, model, &Prompts::for_model(cfg, model).query(query))
+}
+
+/// Embed a batch of texts with `model`.
+pub fn embed_batch(
+    cfg: &EmbeddingConfig,
+    model: &str,
+    texts: &[impl AsRef<str>],
+) -> Result<Vec<Vec<f32>>> {
+    get_embedder(cfg, model)?.embed(texts)
+}
+
+/// Serialize `vector` as stored in `embeddings.vec`: little-endian `f32`s.
+pub fn encode_vector(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|f| f.to_le_bytes()).collect()
+}
+
+/// Inverse of [`encode_vector`].
+pub fn decode_vector(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+/// Full-precision vector of a stored embedding: the row's own `vec` when it
+/// is not quantized, else its copy in `vectors.full_vectors`.
+pub fn stored_vector(
+    conn: &Connection,
+    model_id: &str,
+    vec: &[u8],
+    quantization: &str,
+    text_hash: Option<&str>,
+) -> Result<Vec<f32>> {
+    if Quantization::parse(quantization)? == Quantization::None {
+        return Ok(decode_vector(vec));
+    }
+    full_vector(conn, model_id, text_hash)
+}
+
+/// Full-precision copy of the quantized `model_id` embedding of the text
+/// hashed to `text_hash`.
+pub fn full_vector(conn: &Connection, model_id: &str, text_hash: Option<&str>) -> Result<Vec<f32>> {
+    let bytes: Option<Vec<u8>> = if db::attach_vectors(conn, false)? {
+        conn.prepare_cached(
+            "SELECT vec FROM vectors.full_vectors WHERE text_hash=?1 AND model_id=?2",
+        )?
+        .query_row(params![text_hash, model_id], |r| r.get(0))
+        .optional()?
+    } else {
+        None
+    };
+    let bytes = bytes.ok_or_else(|| {
+        anyhow!("no full-precision vector stored for a quantized `{model_id}` embedding")
+    })?;
+    Ok(decode_vector(&bytes))
+}
+
+/// Outcome of [`embed_chunks`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct EmbedSummary {
+    /// Chunks whose vector was found under the hash of their text.
+    pub reused: u64,
+    /// Chunks whose text was sent to the embedder.
+    pub computed: u64,
+}
+
+/// Model and storage settings of [`embed_chunks`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmbedParams {
+    pub model_id: String,
+    /// Most distinct texts passed to the embedder at once.
+    pub batch_size: usize,
+    pub quantization: Quantization,
+    pub prompts: Prompts,
+}
+
+impl Default for EmbedParams {
+    fn default() -> Self {
+        Self {
+            model_id: String::new(),
+            batch_size: 32,
+            quantization: Quantization::None,
+            prompts: Prompts::default(),
+        }
+    }
+}
+
+impl EmbedParams {
+    /// Settings of `model` under the embedding configuration `cfg`.
+    pub fn new(cfg: &EmbeddingConfig, model: &str) -> Self {
+        Self {
+            model_id: model.to_string(),
+            batch_size: cfg.batch_size,
+            quantization: cfg.quantization,
+            prompts: Prompts::for_model(cfg, model),
+        }
+    }
+}
+
+/// Store a `params.model_id` embedding for the chunks in `chunk_ids`, or for
+/// every chunk in the catalog when `None`.
+///
+/// Vectors are cached by the hash of the normalized chunk text: a chunk whose
+/// text was already embedded with the model, under any chunk id, reuses that
+/// vector. Only the remaining distinct texts are passed to `embed`, at most
+/// `params.batch_size` at a time, and each batch is stored in its own
+/// transaction so the embedder never runs while the catalog is locked. Ids no
+/// longer in `chunks` are ignored.
+///
+/// New vectors are stored with `params.quantization`, keeping a
+/// full-precision copy per text hash in `vectors.full_vectors` when they are
+/// quantized.
+///
+/// Chunk texts are wrapped in the passage prompt of `params.prompts` before
+/// they are hashed and embedded, so changing the template embeds them again.
+pub fn embed_chunks(
+    conn: &Connection,
+    params: &EmbedParams,
+    chunk_ids: Option<&[String]>,
+    mut embed: impl FnMut(&[String]) -> Result<Vec<Vec<f32>>>,
+) -> Result<EmbedSummary> {
+    let model_id = params.model_id.as_str();
+    let quantization = params.quantization;
+    let mut summary = EmbedSummary::default();
+    let select = "SELECT c.chunk_id, c.text, e.text_hash FROM chunks c \
+                  LEFT JOIN embeddings e ON e.chunk_id=c.chunk_id AND e.model_id=?1";
+    let chunks: Vec<(String, String, Option<String>)> = {
+        let row = |r: &rusqlite::Row| Ok((r.get(0)?, r.get(1)?, r.get(2)?));
+        match chunk_ids {
+            None => {
+                let mut stmt = conn.prepare(select)?;
+                let rows = stmt.query_map(params![model_id], row)?;
+                rows.collect::<rusqlite::Result<_>>()?
+            }
+            Some(ids) => {
+                let mut stmt = conn.prepare(&format!("{select} WHERE c.chunk_id=?2"))?;
+                let mut chunks = Vec::new();
+                for id in ids {
+                    chunks.extend(stmt.query_row(params![model_id, id], row).optional()?);
+                }
+                chunks
+            }
+        }
+    };
+    let insert_sql = "INSERT OR REPLACE INTO embeddings (chunk_id, model_id, dim, vec, text_hash, quantization) \
+                      VALUES (?1, ?2, ?3, ?4, ?5, ?6)";
+
+    if quantization != Quantization::None {
+        db::attach_vectors(conn, true)?;
+    }
+
+    // Distinct texts still to embed, with the chunks sharing each.
+    let mut pending: Vec<(String, String, Vec<String>)> = Vec::new();
+    let mut pending_by_hash: HashMap<String, usize> = HashMap::new();
+    let tx = conn.unchecked_transaction()?;
+    {
+        let mut cached = tx.prepare(
+            "SELECT dim, vec, quantization FROM embeddings WHERE text_hash=?1 AND model_id=?2 LIMIT 1",
+        )?;
+        let mut insert = tx.prepare(insert_sql)?;
+        for (chunk_id, text, stored_hash) in chunks {
+            let text = params.prompts.passage(&text);
+            let hash = chunk::text_hash(&text);
+            if stored_hash.as_deref() == Some(hash.as_str()) {
+                summary.reused += 1;
+                continue;
+            }
+            let hit: Option<(i64, Vec<u8>, String)> = cached
+                .query_row(params![hash, model_id], |r| {
+                    Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+                })
+                .optional()?;
+            if let Some((dim, vec, stored_quantization)) = hit {
+                insert.execute(params![
+                    chunk_id,
+                    model_id,
+                    dim,
+                    vec,
+                    hash,
+                    stored_quantization
+                ])?;
+                summary.reused += 1;
+                continue;
+            }
+            match pending_by_hash.get(&hash) {
+                Some(&i) => pending[i].2.push(chunk_id),
+                None => {
+                    pending_by_hash.insert(hash.clone(), pending.len());
+                    pending.push((hash, text, vec![chunk_id]));
+                }
+            }
+        }
+    }
+    tx.commit()?;
+
+    let mut dim: Option<usize> = conn
+        .query_row(
+            "SELECT dim FROM embeddings WHERE model_id=?1 LIMIT 1",
+            params![model_id],
+            |r| r.get::<_, i64>(0),
+        )
+        .optional()?
+        .map(|d| d as usize);
+    for batch in pending.chunks(params.batch_size.max(1)) {
+        let texts: Vec<String> = batch.iter().map(|(_, text, _)| text.clone()).collect();
+        let vectors = embed(&texts)?;
+        if vectors.len() != texts.len() {
+            bail!(
+                "embedder returned {} vectors for {} texts",
+                vectors.len(),
+                texts.len()
+            );
+        }
+        for vector in &vectors {
+            let expected = *dim.get_or_insert(vector.len());
+            if vector.len() != expected {
+                bail!(
+                    "model `{model_id}` returned a {}-dimensional vector but its stored \
+                     embeddings have {expected} dimensions",
+                    vector.len()
+                );
+            }
+        }
+        let tx = conn.unchecked_transaction()?;
+        {
+            let mut insert = tx.prepare(insert_sql)?;
+            let mut insert_full = (quantization != Quantization::None)
+                .then(|| {
+                    tx.prepare(
+                        "INSERT OR REPLACE INTO vectors.full_vectors (text_hash, model_id, vec) VALUES (?1, ?2, ?3)",
+                    )
+                })
+                .transpose()?;
+            for ((hash, _, chunk_ids), vector) in batch.iter().zip(vectors) {
+                let code = quant::encode(quantization, &vector);
+                for chunk_id in chunk_ids {
+                    insert.execute(params![
+                        chunk_id,
+                        model_id,
+                        vector.len() as i64,
+                        code,
+                        hash,
+                        quantization.as_str()
+                    ])?;
+                }
+                if let Some(insert_full) = &mut insert_full {
+                    insert_full.execute(params![hash, model_id, encode_vector(&vector)])?;
+                }
+                summary.computed += 1;
+                summary.reused += chunk_ids.len() as u64 - 1;
+            }
+        }
+        tx.commit()?;
+    }
+    Ok(summary)
+}
+
+/// Re-encode the `model_id` embeddings stored with another quantization than
+/// `quantization`, so changing the setting does not require embedding again.
+/// Vectors quantized without a full-precision copy are left as they are.
+/// Returns the number of embeddings re-encoded.
+pub fn requantize(conn: &Connection, model_id: &str, quantization: Quantization) -> Result<usize> {
+    // Rows predating text hashes are hashed from their chunk text.
+    let rows: Vec<(String, Vec<u8>, String, Option<String>)> = {
+        let mut stmt = conn.prepare(
+            "SELECT e.chunk_id, e.vec, e.quantization, e.text_hash, c.text FROM embeddings e \
+             LEFT JOIN chunks c ON c.chunk_id=e.chunk_id WHERE e.model_id=?1 AND e.quantization!=?2",
+        )?;
+        let rows = stmt.query_map(params![model_id, quantization.as_str()], |r| {
+            let text_hash: Option<String> = r.get(3)?;
+            let text: Option<String> = r.get(4)?;
+            let hash = text_hash.or_else(|| text.map(|t| chunk::text_hash(&t)));
+            Ok((r.get(0)?, r.get(1)?, r.get(2)?, hash))
+        })?;
+        rows.collect::<rusqlite::Result<_>>()?
+    };
+    if rows.is_empty() {
+        return Ok(0);
+    }
+    // Quantized rows are read back from their full-precision copies.
+    db::attach_vectors(conn, quantization != Quantization::None)?;
+    let mut count = 0;
+    let tx = conn.unchecked_transaction()?;
+    {
+        let mut update = tx.prepare(
+            "UPDATE embeddings SET vec=?3, quantization=?4, text_hash=?5 WHERE chunk_id=?1 AND model_id=?2",
+        )?;
+        let mut insert_full = (quantization != Quantization::None)
+            .then(|| {
+                tx.prepare(
+                    "INSERT OR IGNORE INTO vectors.full_vectors (text_hash, model_id, vec) VALUES (?1, ?2, ?3)",
+                )
+            })
+            .transpose()?;
+        for (chunk_id, vec, stored, text_hash) in rows {
+            let Some(hash) = text_hash else {
+                continue;
+            };
+            let Ok(vector) = stored_vector(&tx, model_id, &vec, &stored, Some(&hash)) else {
+                continue;
+            };
+            if let Some(insert_full) = &mut insert_full {
+                insert_full.execute(params![hash, model_id, encode_vector(&vector)])?;
+            }
+            let code = quant::encode(quantization, &vector);
+            update.execute(params![
+                chunk_id,
+                model_id,
+                code,
+                quantization.as_str(),
+                hash
+            ])?;
+            count += 1;
+        }
+    }
+    tx.commit()?;
+    Ok(count)
+}
+
+/// Embed every chunk of the catalog not embedded yet, returning `None` when
+/// the embedding provider is disabled. Used after a full index rebuild,
+/// which embeds in one sweep rather than running the embedding stage.
+pub fn embed_all(cfg: &Config) -> Result<Option<EmbedSummary>> {
+    if cfg.embedding.provider == "disabled" {
+        return Ok(None);
+    }
+    let conn = db::open(&cfg.db)?;
+    let model = active_model(&cfg.embedding)?;
+    let quantization = cfg.embedding.quantization;
+    let summary = embed_chunks(
+        &conn,
+        &EmbedParams::new(&cfg.embedding, &model),
+        None,
+        |texts| embed_batch(&cfg.embedding, &model, texts),
+    )?;
+    let requantized = requantize(&conn, &model, quantization)?;
+    if requantized > 0 {
+        tracing::info!(
+            requantized,
+            quantization = quantization.as_str(),
+            "embeddings re-encoded"
+        );
+    }
+    tracing::info!(
+        reused = summary.reused,
+        computed = summary.computed,
+        "embeddings stored"
+    );
+    ann::sync(&conn, &ann::dir(cfg), &model)?;
+    log::append(
+        cfg,
+        &format!(
+            "embeddings\treused={}\tcomputed={}",
+            summary.reused, summary.computed
+        ),
+    )?;
+    Ok(Some(summary))
+}
+
+/// Run the embedding stage. Chunks announced by `MirrorChunkUpserted` are
+/// queued for a pool of `embedding.pool_size` workers, each embedding up to
+/// `embedding.batch_size` queued chunks per call and storing them in one
+/// transaction. Keyword indexing does not wait for it: when the queue is full
+/// the chunk is not queued, and idle workers later pull the chunks still
+/// missing a vector from the catalog. New vectors reach the ANN index from a
+/// thread of their own. The stage does nothing when the embedding provider is
+/// disabled.
+pub fn run(bus: EventBus, cfg: &Config, stop: &AtomicBool) -> Result<()> {
+    let rx = bus.subscribe_mirror();
+    serve(rx, cfg, stop)
+}
+
+/// Start the embedding stage on its own thread. It subscribes before
+/// returning, so events published afterwards are not missed.
+pub fn spawn(bus: EventBus, cfg: Config, stop: Arc<AtomicBool>) -> JoinHandle<Result<()>> {
+    let rx = bus.subscribe_mirror();
+    thread::spawn(move || serve(rx, &cfg, &stop))
+}
+
+fn serve(rx: Receiver<Envelope<MirrorEvent>>, cfg: &Config, stop: &AtomicBool) -> Result<()> {
+    if cfg.embedding.provider == "disabled" {
+        return Ok(());
+    }
+    let model = active_model(&cfg.embedding)?;
+    let dirty = Arc::new(AtomicBool::new(false));
+    let overflow = Arc::new(AtomicBool::new(false));
+    let (job_tx, job_rx) = bounded::<String>(cfg.embedding.jobs_bound);
+    for _ in 0..cfg.embedding.pool_size {
+        let rx = job_rx.clone();
+        let cfg_w = cfg.clone();
+        let embedding = cfg.embedding.clone();
+        let model = model.clone();
+        let dirty = dirty.clone();
+        let overflow = overflow.clone();
+        thread::spawn(move || {
+            let embed = |texts: &[String]| embed_batch(&embedding, &model, texts);
+            if let Err(e) = worker_loop(rx, cfg_w, &model, &dirty, &overflow, embed) {
+                tracing::error!(error = %e, "embedding worker stopped");
+            }
+        });
+    }
+    let done = Arc::new(AtomicBool::new(false));
+    let syncer = {
+        let (cfg, model, dirty, done) = (cfg.clone(), model.clone(), dirty.clone(), done.clone());
+        thread::spawn(move || sync_loop(&cfg, &model, &dirty, &done))
+    };
+
+    while !stop.load(Ordering::SeqCst) {
+        match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(env) => match env.data {
+                MirrorEvent::MirrorChunkUpserted { chunk_id, .. } => {
+                    if let Some(d) = dashboard::get() {
+                        d.add_embed_backlog(1);
+                    }
+                    if let Err(TrySendError::Full(_)) = job_tx.try_send(chunk_id) {
+                        overflow.store(true, Ordering::SeqCst);
+                    }
+                }
+                // The catalog dropped the embeddings of deleted chunks; the
+                // next sync tombstones them in the ANN index.
+                MirrorEvent::MirrorChunkDeleted { .. } | MirrorEvent::MirrorDocDeleted { .. } => {
+                    dirty.store(true, Ordering::SeqCst);
+                }
+                MirrorEvent::MirrorDocUpserted { .. } => {}
+            },
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+    syncer
+        .join()
+        .map_err(|_| anyhow!("ann sync thread panicked"))?
+}
+
+/// Bring the ANN index of `model` up to date with the stored vectors every
+/// `commit_interval_secs` while `dirty` is raised, the same cadence as
+/// keyword commits, and once more when `done` is raised.
+fn sync_loop(cfg: &Config, model: &str, dirty: &AtomicBool, done: &AtomicBool) -> Result<()> {
+    let conn = db::open(&cfg.db)?;
+    let interval = Duration::from_secs(cfg.commit_interval_secs);
+    let mut last_sync = Instant::now();
+    loop {
+        let finished = done.load(Ordering::SeqCst);
+        if finished || last_sync.elapsed() >= interval {
+            last_sync = Instant::now();
+            if dirty.swap(false, Ordering::SeqCst) {
+                if let Err(e) = ann::sync(&conn, &ann::dir(cfg), model) {
+                    tracing::warn!(error = %e, "failed to update ann index");
+                }
+            }
+        }
+        if finished {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Embed queued chunks until the queue closes. Each batch takes whatever is
+/// queued, up to the batch size, so batches grow with the backlog. When the
+/// queue is empty and `overflow` was raised because chunks could not be
+/// queued, the worker pulls the chunks without a vector from the catalog
+/// instead. `dirty` is raised once vectors were stored. A batch that fails is
+/// logged and left without vectors, for the next pull or `embed_all` to catch
+/// up on.
+fn worker_loop(
+    rx: Receiver<String>,
+    cfg: Config,
+    model: &str,
+    dirty: &AtomicBool,
+    overflow: &AtomicBool,
+    mut embed: impl FnMut(&[String]) -> Result<Vec<Vec<f32>>>,
+) -> Result<()> {
+    let conn = db::open(&cfg.db)?;
+    let batch_size = cfg.embedding.batch_size.max(1);
+    let params = EmbedParams::new(&cfg.embedding, model);
+    let mut store = |batch: &[String], backlog: usize| match embed_chunks(
+        &conn,
+        &params,
+        Some(batch),
+        &mut embed,
+    ) {
+        Ok(summary) => {
+            dirty.store(true, Ordering::SeqCst);
+            if let Some(d) = dashboard::get() {
+                d.inc_embedded(batch.len() as u64);
+            }
+            tracing::debug!(
+                reused = summary.reused,
+                computed = summary.computed,
+                backlog,
+                "embedded chunks"
+            );
+            true
+        }
+        Err(e) => {
+            tracing::warn!(chunks = batch.len(), error = %e, "failed to embed chunks");
+            false
+        }
+    };
+    loop {
+        let first = match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(chunk_id) => chunk_id,
+            Err(RecvTimeoutError::Timeout) => {
+                if overflow.swap(false, Ordering::SeqCst) {
+                    // Stop at the first failure rather than retrying it
+                    // forever; the next overflow pulls again.
+                    loop {
+                        let batch = match unembedded(&conn, model, batch_size) {
+                            Ok(batch) => batch,
+                            Err(e) => {
+                                tracing::warn!(error = %e, "failed to list chunks to embed");
+                                break;
+                            }
+                        };
+                        if batch.is_empty() || !store(&batch, 0) {
+                            break;
+                        }
+                    }
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        let mut batch = vec![first];
+        while batch.len() < batch_size {
+            match rx.try_recv() {
+                Ok(chunk_id) => batch.push(chunk_id),
+                Err(_) => break,
+            }
+        }
+        store(&batch, rx.len());
+    }
+}
+
+/// Up to `limit` chunks with no `model` vector.
+fn unembedded(conn: &Connection, model: &str, limit: usize) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT c.chunk_id FROM chunks c \
+         LEFT JOIN embeddings e ON e.chunk_id=c.chunk_id AND e.model_id=?1 \
+         WHERE e.chunk_id IS NULL LIMIT ?2",
+    )?;
+    let ids = stmt
+        .query_map(params![model, limit as i64], |r| r.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use camino::Utf8Path;
+
+    #[test]
+    fn external_service_requires_a_model() -> Result<()> {
+        let mut cfg = EmbeddingConfig {
+            url: Some("http://localhost:8080/v1/embeddings".into()),
+            ..EmbeddingConfig::default()
+        };
+        let err = active_model(&cfg).unwrap_err();
+        assert!(err.to_string().contains("embedding.model"), "{err}");
+        cfg.model = Some("nomic-embed-text".into());
+        assert_eq!(active_model(&cfg)?, "nomic-embed-text");
+        Ok(())
+    }
+
+    #[test]
+    fn embed_chunks_reuses_vectors_by_text_hash() -> Result<()> {
+        let conn = db::open(Utf8Path::new(":memory:"))?;
+        let add_chunk = |id: &str, text: &str| {
+            conn.execute(
+                "INSERT INTO chunks (file_id, chunk_id, start_byte, end_byte, text) VALUES (1, ?1, 0, 0, ?2)",
+                params![id, text],
+            )
+        };
+        add_chunk("a", "hello world")?;
+        add_chunk("b", "hello\n  world ")?;
+        add_chunk("c", "goodbye")?;
+        let mut sent = Vec::new();
+        let mut embed = |texts: &[String]| -> Result<Vec<Vec<f32>>> {
+            sent.extend(texts.iter().cloned());
+            Ok(texts.iter().map(|t| vec![t.len() as f32, 1.0]).collect())
+        };
+        let model = |model_id: &str| EmbedParams {
+            model_id: model_id.into(),
+            ..EmbedParams::default()
+        };
+
+        let summary = embed_chunks(&conn, &model("m"), None, &mut embed)?;
+        assert_eq!(
+            summary,
+            EmbedSummary {
+                reused: 1,
+                computed: 2
+            }
+        );
+        let summary = embed_chunks(&conn, &model("m"), None, &mut embed)?;
+        assert_eq!(summary.computed, 0);
+        assert_eq!(summary.reused, 3);
+
+        // The same text in another document reuses the cached vector, while
+        // another model embeds it afresh.
+        add_chunk("d", "goodbye")?;
+        assert_eq!(
+            embed_chunks(&conn, &model("m"), None, &mut embed)?.computed,
+            0
+        );
+        assert_eq!(
+            embed_chunks(&conn, &model("other"), None, &mut embed)?.computed,
+            2
+        );
+        assert_eq!(sent, ["hello world", "goodbye", "hello world", "goodbye"]);
+        let (dim, hash): (i64, String) = conn.query_row(
+            "SELECT dim, text_hash FROM embeddings WHERE chunk_id='d' AND model_id='m'",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )?;
+        assert_eq!(dim, 2);
+        assert_eq!(hash, chunk::text_hash("goodbye"));
+
+        // A model that changes dimension is refused rather than mixed.
+        add_chunk("e", "fresh text")?;
+        let err = embed_chunks(&conn, &model("m"), None, |texts: &[String]| {
+            Ok(texts.iter().map(|_| vec![0.0; 3]).collect())
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("3-dimensional"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn passage_prompt_is_embedded_and_hashed() -> Result<()> {
+        let conn = db::open(Utf8Path::new(":memory:"))?;
+        conn.execute(
+            "INSERT INTO chunks (file_id, chunk_id, start_byte, end_byte, text) VALUES (1, 'a', 0, 0, 'cats')",
+            [],
+        )?;
+        let mut cfg = EmbeddingConfig::default();
+        let mut sent = Vec::new();
+        let mut embed = |texts: &[String]| -> Result<Vec<Vec<f32>>> {
+            sent.extend(texts.iter().cloned());
+            Ok(texts.iter().map(|_| vec![1.0]).collect())
+        };
+        let e5 = EmbedParams {
+            model_id: "m".into(),
+            prompts: Prompts::for_model(&cfg, "intfloat/multilingual-e5-small"),
+            ..EmbedParams::default()
+        };
+        embed_chunks(&conn, &e5, None, &mut embed)?;
+        assert_eq!(embed_chunks(&conn, &e5, None, &mut embed)?.computed, 0);
+
+        // A new template makes the stored vector stale.
+        cfg.prompts.insert(
+            "intfloat/multilingual-e5-small".into(),
+            crate::config::PromptTemplates {
+                query: None,
+                passage: Some("document: {text}".into()),
+            },
+        );
+        let custom = EmbedParams {
+            prompts: Prompts::for_model(&cfg, "intfloat/multilingual-e5-small"),
+            ..e5
+        };
+        embed_chunks(&conn, &custom, None, &mut embed)?;
+        assert_eq!(sent, ["passage: cats", "document: cats"]);
+        let hash: String = conn.query_row(
+            "SELECT text_hash FROM embeddings WHERE chunk_id='a'",
+            [],
+            |r| r.get(0),
+        )?;
+        assert_eq!(hash, chunk::text_hash("document: cats"));
+        Ok(())
+    }
+
+    #[test]
+    fn quantized_embeddings_keep_a_full_precision_copy() -> Result<()> {
+        let conn = db::open(Utf8Path::new(":memory:"))?;
+        for (id, text) in [("a", "alpha"), ("b", "beta")] {
+            conn.execute(
+                "INSERT INTO chunks (file_id, chunk_id, start_byte, end_byte, text) VALUES (1, ?1, 0, 0, ?2)",
+                params![id, text],
+            )?;
+        }
+        let vector = |text: &str| vec![text.len() as f32, 1.0, -0.5];
+        embed_chunks(
+            &conn,
+            &EmbedParams {
+                model_id: "m".into(),
+                quantization: Quantization::Int8,
+                ..EmbedParams::default()
+            },
+            None,
+            |texts| Ok(texts.iter().map(|t| vector(t)).collect()),
+        )?;
+        let stored = |conn: &Connection| -> Result<(usize, String, Vec<f32>)> {
+            let (vec, quantization, hash): (Vec<u8>, String, String) = conn.query_row(
+                "SELECT vec, quantization, text_hash FROM embeddings WHERE chunk_id='a'",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+            )?;
+            let full = stored_vector(conn, "m", &vec, &quantization, Some(&hash))?;
+            Ok((vec.len(), quantization, full))
+        };
+        assert_eq!(stored(&conn)?, (4 + 3, "int8".into(), vector("alpha")));
+
+        assert_eq!(requantize(&conn, "m", Quantization::Binary)?, 2);
+        assert_eq!(stored(&conn)?, (1, "binary".into(), vector("alpha")));
+        assert_eq!(requantize(&conn, "m", Quantization::None)?, 2);
+        assert_eq!(stored(&conn)?, (12, "none".into(), vector("alpha")));
+        assert_eq!(requantize(&conn, "m", Quantization::None)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn vectors_db_is_only_created_for_quantized_embeddings() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let root = Utf8Path::from_path(tmp.path()).unwrap();
+        let conn = db::open(&root.join("catalog.db"))?;
+        conn.execute(
+            "INSERT INTO chunks (file_id, chunk_id, start_byte, end_byte, text) VALUES (1, 'a', 0, 0, 'alpha')",
+            [],
+        )?;
+        let params = EmbedParams {
+            model_id: "m".into(),
+            ..EmbedParams::default()
+        };
+        embed_chunks(&conn, &params, None, |texts| {
+            Ok(texts.iter().map(|_| vec![1.0, 0.5]).collect())
+        })?;
+        assert!(!root.join("vectors.db").exists());
+        assert!(full_vector(&conn, "m", Some("hash")).is_err());
+        assert!(!root.join("vectors.db").exists());
+
+        assert_eq!(requantize(&conn, "m", Quantization::Int8)?, 1);
+        assert!(root.join("vectors.db").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn worker_embeds_queued_chunks_in_batches() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let root = Utf8Path::from_path(tmp.path()).unwrap();
+        let mut cfg = Config::for_test(root);
+        cfg.embedding.batch_size = 2;
+        let conn = db::open(&cfg.db)?;
+        let (tx, rx) = crossbeam_channel::unbounded();
+        for i in 0..5 {
+            conn.execute(
+                "INSERT INTO chunks (file_id, chunk_id, start_byte, end_byte, text) VALUES (1, ?1, 0, 0, ?2)",
+                params![format!("c{i}"), format!("text {i}")],
+            )?;
+            tx.send(format!("c{i}"))?;
+        }
+        // Chunks deleted before a worker reaches them are skipped.
+        tx.send("gone".to_string())?;
+        drop(tx);
+
+        let mut batches = Vec::new();
+        let dirty = AtomicBool::new(false);
+        let overflow = AtomicBool::new(false);
+        worker_loop(
+            rx,
+            cfg.clone(),
+            "m",
+            &dirty,
+            &overflow,
+            |texts: &[String]| {
+                batches.push(texts.len());
+                Ok(texts.iter().map(|_| vec![0.5]).collect())
+            },
+        )?;
+        assert_eq!(batches, [2, 2, 1]);
+        assert!(dirty.load(Ordering::SeqCst));
+        let stored: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM embeddings WHERE model_id=?1",
+            params!["m"],
+            |r| r.get(0),
+        )?;
+        assert_eq!(stored, 5);
+
+        // A failed batch stores nothing and does not stop the worker.
+        for id in ["f1", "f2", "f3"] {
+            conn.execute(
+                "INSERT INTO chunks (file_id, chunk_id, start_byte, end_byte, text) VALUES (1, ?1, 0, 0, ?1)",
+                params![id],
+            )?;
+        }
+        let (tx, rx) = crossbeam_channel::unbounded();
+        for id in ["f1", "f2", "f3"] {
+            tx.send(id.to_string())?;
+        }
+        drop(tx);
+        worker_loop(
+            rx,
+            cfg.clone(),
+            "m",
+            &dirty,
+            &overflow,
+            |texts: &[String]| {
+                if texts.iter().any(|t| t == "f1") {
+                    bail!("service unavailable");
+                }
+                Ok(texts.iter().map(|_| vec![0.5]).collect())
+            },
+        )?;
+        let missing: Vec<String> = conn
+            .prepare(
+                "SELECT c.chunk_id FROM chunks c LEFT JOIN embeddings e \
+                 ON e.chunk_id=c.chunk_id AND e.model_id='m' WHERE e.chunk_id IS NULL",
+            )?
+            .query_map([], |r| r.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(missing, ["f1", "f2"]);
+
+        // A catalog the worker cannot open is reported instead of panicking.
+        let (_tx, rx) = crossbeam_channel::unbounded();
+        let bad = Config {
+            db: cfg.db.join("catalog.db"),
+            ..cfg
+        };
+        assert!(worker_loop(rx, bad, "m", &dirty, &overflow, |_: &[String]| Ok(vec![])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn idle_worker_pulls_chunks_that_overflowed_the_queue() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let root = Utf8Path::from_path(tmp.path()).unwrap();
+        let cfg = Config::for_test(root);
+        let conn = db::open(&cfg.db)?;
+        for i in 0..3 {
+            conn.execute(
+                "INSERT INTO chunks (file_id, chunk_id, start_byte, end_byte, text) VALUES (1, ?1, 0, 0, ?1)",
+                params![format!("c{i}")],
+            )?;
+        }
+        // None of the chunks was queued, but the stage noted it dropped some.
+        let (tx, rx) = crossbeam_channel::unbounded::<String>();
+        let dirty = Arc::new(AtomicBool::new(false));
+        let overflow = Arc::new(AtomicBool::new(true));
+        let worker = {
+            let (dirty, overflow) = (dirty.clone(), overflow.clone());
+            thread::spawn(move || {
+                worker_loop(rx, cfg, "m", &dirty, &overflow, |texts: &[String]| {
+                    Ok(texts.iter().map(|_| vec![0.5]).collect())
+                })
+            })
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !unembedded(&conn, "m", 10)?.is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        drop(tx);
+        worker.join().unwrap()?;
+        assert!(unembedded(&conn, "m", 10)?.is_empty());
+        assert!(dirty.load(Ordering::SeqCst));
+        assert!(!overflow.load(Ordering::SeqCst));
+        Ok(())
+    }
+}
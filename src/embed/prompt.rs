@@ -0,0 +1,130 @@
+//! Query and passage prompts expected by asymmetric embedding models.
+
+use crate::config::EmbeddingConfig;
+
+/// Placeholder replaced by the text in a template.
+const TEXT: &str = "{text}";
+
+const BGE_EN_QUERY: &str = "Represent this sentence for searching relevant passages: {text}";
+
+/// Templates of known models, by model name prefix without its organization:
+/// `(name prefix, query template, passage template)`. The first match wins.
+const BUILTIN: &[(&str, &str, &str)] = &[
+    ("multilingual-e5-", "query: {text}", "passage: {text}"),
+    ("e5-", "query: {text}", "passage: {text}"),
+    ("bge-small-en", BGE_EN_QUERY, TEXT),
+    ("bge-base-en", BGE_EN_QUERY, TEXT),
+    ("bge-large-en", BGE_EN_QUERY, TEXT),
+    (
+        "bge-small-zh",
+        "为这个句子生成表示以用于检索相关文章：{text}",
+        TEXT,
+    ),
+    (
+        "bge-large-zh",
+        "为这个句子生成表示以用于检索相关文章：{text}",
+        TEXT,
+    ),
+    ("snowflake-arctic-embed-m-v2", "query: {text}", TEXT),
+    ("snowflake-arctic-embed-l-v2", "query: {text}", TEXT),
+    ("snowflake-arctic-embed-", BGE_EN_QUERY, TEXT),
+    ("mxbai-embed-large-v1", BGE_EN_QUERY, TEXT),
+    (
+        "nomic-embed-text-",
+        "search_query: {text}",
+        "search_document: {text}",
+    ),
+    (
+        "modernbert-embed-",
+        "search_query: {text}",
+        "search_document: {text}",
+    ),
+];
+
+/// How queries and chunk texts are wrapped before a model embeds them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prompts {
+    query: String,
+    passage: String,
+}
+
+impl Default for Prompts {
+    /// Texts embedded as they are.
+    fn default() -> Self {
+        Self {
+            query: TEXT.into(),
+            passage: TEXT.into(),
+        }
+    }
+}
+
+impl Prompts {
+    /// Templates for `model`: those set under `[embedding.prompts."<model>"]`,
+    /// falling back for each one to the built-in template of a known model,
+    /// else to the bare text.
+    pub fn for_model(cfg: &EmbeddingConfig, model: &str) -> Self {
+        let name = model.rsplit('/').next().unwrap_or(model).to_lowercase();
+        let mut prompts = BUILTIN
+            .iter()
+            .find(|(prefix, _, _)| name.starts_with(prefix))
+            .map(|(_, query, passage)| Self {
+                query: query.to_string(),
+                passage: passage.to_string(),
+            })
+            .unwrap_or_default();
+        if let Some(configured) = cfg.prompts.get(model) {
+            if let Some(query) = &configured.query {
+                prompts.query = query.clone();
+            }
+            if let Some(passage) = &configured.passage {
+                prompts.passage = passage.clone();
+            }
+        }
+        prompts
+    }
+
+    /// `text` as a search query.
+    pub fn query(&self, text: &str) -> String {
+        self.query.replace(TEXT, text)
+    }
+
+    /// `text` as a document passage.
+    pub fn passage(&self, text: &str) -> String {
+        self.passage.replace(TEXT, text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PromptTemplates;
+
+    #[test]
+    fn builtin_and_configured_templates() {
+        let mut cfg = EmbeddingConfig::default();
+        let e5 = Prompts::for_model(&cfg, "intfloat/multilingual-e5-small");
+        assert_eq!(e5.query("cats"), "query: cats");
+        assert_eq!(e5.passage("cats"), "passage: cats");
+        let arctic = Prompts::for_model(&cfg, "snowflake/snowflake-arctic-embed-xs");
+        assert_eq!(
+            arctic.query("cats"),
+            "Represent this sentence for searching relevant passages: cats"
+        );
+        assert_eq!(arctic.passage("cats"), "cats");
+        assert_eq!(
+            Prompts::for_model(&cfg, "Qdrant/all-MiniLM-L6-v2-onnx"),
+            Prompts::default()
+        );
+
+        cfg.prompts.insert(
+            "intfloat/multilingual-e5-small".into(),
+            PromptTemplates {
+                query: Some("Instruct: find notes\nQuery: {text}".into()),
+                passage: None,
+            },
+        );
+        let e5 = Prompts::for_model(&cfg, "intfloat/multilingual-e5-small");
+        assert_eq!(e5.query("cats"), "Instruct: find notes\nQuery: cats");
+        assert_eq!(e5.passage("cats"), "passage: cats");
+    }
+}
@@ -0,0 +1,92 @@
+//! Quantized encodings of embedding vectors, as stored in `embeddings.vec`.
+
+use crate::config::Quantization;
+
+use super::{decode_vector, encode_vector};
+
+/// Encode `vector` for storage. Int8 codes start with the little-endian `f32`
+/// scale mapping the largest component to 127; binary codes pack the sign
+/// bits eight to a byte, least significant bit first.
+pub fn encode(quantization: Quantization, vector: &[f32]) -> Vec<u8> {
+    match quantization {
+        Quantization::None => encode_vector(vector),
+        Quantization::Int8 => {
+            let max = vector.iter().fold(0f32, |m, x| m.max(x.abs()));
+            let scale = if max > 0.0 { max / 127.0 } else { 1.0 };
+            let mut code = scale.to_le_bytes().to_vec();
+            code.extend(
+                vector
+                    .iter()
+                    .map(|x| (x / scale).round().clamp(-127.0, 127.0) as i8 as u8),
+            );
+            code
+        }
+        Quantization::Binary => {
+            let mut code = vec![0u8; vector.len().div_ceil(8)];
+            for (i, x) in vector.iter().enumerate() {
+                if *x > 0.0 {
+                    code[i / 8] |= 1 << (i % 8);
+                }
+            }
+            code
+        }
+    }
+}
+
+/// Dot product of `query` with the vector encoded in `code`. Exact for
+/// unquantized codes; an estimate good enough to pick candidates otherwise.
+pub fn score(quantization: Quantization, query: &[f32], code: &[u8]) -> f32 {
+    match quantization {
+        Quantization::None => dot(query, &decode_vector(code)),
+        Quantization::Int8 => {
+            let scale = f32::from_le_bytes([code[0], code[1], code[2], code[3]]);
+            let sum: f32 = query
+                .iter()
+                .zip(&code[4..])
+                .map(|(q, c)| q * (*c as i8) as f32)
+                .sum();
+            sum * scale
+        }
+        Quantization::Binary => query
+            .iter()
+            .enumerate()
+            .map(|(i, q)| {
+                if (code[i / 8] >> (i % 8)) & 1 == 1 {
+                    *q
+                } else {
+                    -q
+                }
+            })
+            .sum(),
+    }
+}
+
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantized_scores_approximate_the_dot_product() {
+        let vector = [0.5, -0.25, 0.0, 1.0, -0.75, 0.1, 0.2, -0.3, 0.9];
+        let query = [0.1, 0.2, -0.3, 0.4, -0.5, 0.6, 0.7, -0.8, 0.9];
+        let exact = dot(&query, &vector);
+
+        let none = encode(Quantization::None, &vector);
+        assert_eq!(none.len(), 36);
+        assert_eq!(score(Quantization::None, &query, &none), exact);
+
+        let int8 = encode(Quantization::Int8, &vector);
+        assert_eq!(int8.len(), 4 + 9);
+        assert!((score(Quantization::Int8, &query, &int8) - exact).abs() < 0.01);
+
+        let binary = encode(Quantization::Binary, &vector);
+        assert_eq!(binary, [0b0110_1001, 0b1]);
+        // Query components are added where the vector is positive and
+        // subtracted elsewhere.
+        assert!((score(Quantization::Binary, &query, &binary) - 4.1).abs() < 1e-5);
+    }
+}
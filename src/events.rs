@@ -68,6 +68,10 @@ pub enum SourceEvent {
         file_uid: String,
         error: String,
     },
+    /// Extraction was not run because this content was already processed.
+    ExtractionSkipped {
+        file_uid: String,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
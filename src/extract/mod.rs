@@ -1,6 +1,10 @@
 //! Document content extraction via worker pool and external command.
 
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
 use std::{fs, process::Command};
 
@@ -9,7 +13,7 @@ use camino::{Utf8Path, Utf8PathBuf};
 use crossbeam_channel::{bounded, Receiver, RecvTimeoutError};
 use rusqlite::{params, Connection};
 
-use crate::bus::EventBus;
+use crate::bus::{Envelope, EventBus};
 use crate::config::Config;
 use crate::db;
 use crate::events::{PageBlock, SourceEvent};
@@ -17,9 +21,27 @@ use crate::events::{PageBlock, SourceEvent};
 const PLAINTEXT_EXTS: &[&str] = &["txt", "md", "rs", "toml", "json", "cpp", "c", "h", "hpp"];
 
 /// Run the extraction worker pool. Workers consume `ExtractionRequested` events
-/// and emit `ExtractionCompleted` or `ExtractionFailed` events.
+/// and emit `ExtractionCompleted` or `ExtractionFailed` events, or
+/// `ExtractionSkipped` when the same content was already extracted. Content
+/// whose extraction failed is extracted again.
 pub fn run_pool(bus: EventBus, cfg: &Config, stop: &AtomicBool) -> Result<()> {
     let rx_events = bus.subscribe_source();
+    serve_pool(rx_events, bus, cfg, stop)
+}
+
+/// Start the extraction worker pool on its own thread. It subscribes before
+/// returning, so events published afterwards are not missed.
+pub fn spawn(bus: EventBus, cfg: Config, stop: Arc<AtomicBool>) -> JoinHandle<Result<()>> {
+    let rx_events = bus.subscribe_source();
+    thread::spawn(move || serve_pool(rx_events, bus, &cfg, &stop))
+}
+
+fn serve_pool(
+    rx_events: Receiver<Envelope<SourceEvent>>,
+    bus: EventBus,
+    cfg: &Config,
+    stop: &AtomicBool,
+) -> Result<()> {
     let (job_tx, job_rx) = bounded::<String>(cfg.extract.jobs_bound);
 
     for _ in 0..cfg.extract.pool_size {
@@ -27,17 +49,16 @@ pub fn run_pool(bus: EventBus, cfg: &Config, stop: &AtomicBool) -> Result<()> {
         let bus_w = bus.clone();
         let cfg_w = cfg.clone();
         let db_path = cfg.db.clone();
-        std::thread::spawn(move || worker_loop(rx, bus_w, cfg_w, db_path));
+        thread::spawn(move || worker_loop(rx, bus_w, cfg_w, db_path));
     }
 
     while !stop.load(Ordering::SeqCst) {
         match rx_events.recv_timeout(Duration::from_millis(100)) {
-            Ok(env) => match env.data {
-                SourceEvent::ExtractionRequested { file_uid } => {
+            Ok(env) => {
+                if let SourceEvent::ExtractionRequested { file_uid } = env.data {
                     let _ = job_tx.send(file_uid);
                 }
-                _ => {}
-            },
+            }
             Err(RecvTimeoutError::Timeout) => continue,
             Err(RecvTimeoutError::Disconnected) => break,
         }
@@ -75,11 +96,16 @@ fn worker_loop(rx: Receiver<String>, bus: EventBus, cfg: Config, db_path: Utf8Pa
         };
         let inserted = conn
             .execute(
-                "INSERT INTO extract_jobs (file_uid, content_hash, status, attempt, started_ts) VALUES (?1, ?2, 'running', 1, ?3) ON CONFLICT(file_uid, content_hash) DO NOTHING",
+                "INSERT INTO extract_jobs (file_uid, content_hash, status, attempt, started_ts) VALUES (?1, ?2, 'running', 1, ?3) \
+                 ON CONFLICT(file_uid, content_hash) DO UPDATE SET status='running', attempt=attempt+1, \
+                 started_ts=excluded.started_ts, finished_ts=NULL, error=NULL WHERE status='failed'",
                 params![file_uid, content_hash, started_ts],
             )
             .unwrap_or(0);
         if inserted == 0 {
+            let _ = bus.publish_source(SourceEvent::ExtractionSkipped {
+                file_uid: file_uid.clone(),
+            });
             continue;
         }
         match extract_one(&conn, &cfg, &bus, &file_uid, &content_hash, &path) {
@@ -213,7 +239,6 @@ fn now() -> i64 {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::{BusBounds, BusConfig, ExtractConfig, MirrorConfig, RetentionConfig};
     use std::sync::{atomic::AtomicBool, Arc};
     use std::time::Duration;
     use tempfile::tempdir;
@@ -226,36 +251,8 @@ mod tests {
         std::fs::write(&file_path, "αβγ\x0cδεζ")?;
 
         let cfg = crate::config::Config {
-            db: root.join("catalog.db"),
-            tantivy_index: Utf8PathBuf::from("idx"),
-            roots: vec![root.clone()],
             include: vec!["**/*.txt".into()],
-            exclude: vec![],
-            max_file_size_mb: 200,
-            follow_symlinks: false,
-            include_hidden: false,
-            allow_offline_hydration: false,
-            commit_interval_secs: 45,
-            guard_interval_secs: 180,
-            default_language: "auto".into(),
-            extractor_cmd: String::new(),
-            embedding: crate::config::EmbeddingConfig {
-                provider: "disabled".into(),
-            },
-            mirror: MirrorConfig {
-                root: Utf8PathBuf::from("raw"),
-            },
-            bus: BusConfig {
-                bounds: BusBounds {
-                    source_fs: 16,
-                    mirror_text: 16,
-                },
-            },
-            extract: ExtractConfig {
-                pool_size: 1,
-                jobs_bound: 16,
-            },
-            retention: RetentionConfig::default(),
+            ..crate::config::Config::for_test(&root)
         };
 
         let conn = db::open(&cfg.db)?;
@@ -312,36 +309,8 @@ mod tests {
         std::fs::write(&file_path, "hello")?;
 
         let cfg = crate::config::Config {
-            db: root.join("catalog.db"),
-            tantivy_index: Utf8PathBuf::from("idx"),
-            roots: vec![root.clone()],
             include: vec!["**/*.txt".into()],
-            exclude: vec![],
-            max_file_size_mb: 200,
-            follow_symlinks: false,
-            include_hidden: false,
-            allow_offline_hydration: false,
-            commit_interval_secs: 45,
-            guard_interval_secs: 180,
-            default_language: "auto".into(),
-            extractor_cmd: String::new(),
-            embedding: crate::config::EmbeddingConfig {
-                provider: "disabled".into(),
-            },
-            mirror: MirrorConfig {
-                root: Utf8PathBuf::from("raw"),
-            },
-            bus: BusConfig {
-                bounds: BusBounds {
-                    source_fs: 16,
-                    mirror_text: 16,
-                },
-            },
-            extract: ExtractConfig {
-                pool_size: 1,
-                jobs_bound: 16,
-            },
-            retention: RetentionConfig::default(),
+            ..crate::config::Config::for_test(&root)
         };
 
         let conn = db::open(&cfg.db)?;
@@ -0,0 +1,79 @@
+//! File extensions and MIME types derived from file names.
+
+/// MIME type of each known extension.
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("pdf", "application/pdf"),
+    ("doc", "application/msword"),
+    (
+        "docx",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    ),
+    ("xls", "application/vnd.ms-excel"),
+    (
+        "xlsx",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    ),
+    ("ppt", "application/vnd.ms-powerpoint"),
+    (
+        "pptx",
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+    ),
+    ("odt", "application/vnd.oasis.opendocument.text"),
+    ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+    ("odp", "application/vnd.oasis.opendocument.presentation"),
+    ("epub", "application/epub+zip"),
+    ("rtf", "application/rtf"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("txt", "text/plain"),
+    ("log", "text/plain"),
+    ("md", "text/markdown"),
+    ("markdown", "text/markdown"),
+    ("csv", "text/csv"),
+    ("tsv", "text/tab-separated-values"),
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("adoc", "text/asciidoc"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("bmp", "image/bmp"),
+    ("webp", "image/webp"),
+];
+
+/// Lowercase extension of the file name in `path`, without the dot.
+pub fn extension(path: &str) -> Option<String> {
+    let name = path.rsplit(['/', '\\']).next().unwrap_or(path);
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && !ext.is_empty() => Some(ext.to_lowercase()),
+        _ => None,
+    }
+}
+
+/// MIME type guessed from the extension of `path`.
+pub fn mime(path: &str) -> Option<&'static str> {
+    let ext = extension(path)?;
+    MIME_TYPES
+        .iter()
+        .find(|(e, _)| *e == ext)
+        .map(|(_, mime)| *mime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extensions_and_mime_types() {
+        assert_eq!(extension("/docs/Report.PDF").as_deref(), Some("pdf"));
+        assert_eq!(extension("C:\\notes\\a.tar.gz").as_deref(), Some("gz"));
+        assert_eq!(extension("/home/me/.bashrc"), None);
+        assert_eq!(extension("/home/me.d/Makefile"), None);
+        assert_eq!(mime("/docs/Report.PDF"), Some("application/pdf"));
+        assert_eq!(mime("notes.md"), Some("text/markdown"));
+        assert_eq!(mime("archive.zzz"), None);
+    }
+}
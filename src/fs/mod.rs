@@ -1,18 +1,22 @@
 #[cfg(windows)]
 use std::os::windows::fs::MetadataExt;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::{Duration, Instant, UNIX_EPOCH};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::bus::EventBus;
 use crate::config::Config;
+use crate::db;
 use crate::events::{FileMeta, FileMove, SourceEvent};
 use anyhow::Result;
 use camino::{Utf8Path, Utf8PathBuf};
 use globset::{Glob, GlobSet, GlobSetBuilder};
 use ignore::WalkBuilder;
-use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use rusqlite::Connection;
 
 /// In-memory state of previously seen files keyed by `file_uid`.
 #[derive(Default)]
@@ -34,66 +38,236 @@ struct FileInfo {
 /// Perform a full scan over configured roots and publish a `SyncDelta` event with
 /// additions, modifications, moves, and deletions compared to the previous state.
 pub fn cold_scan(cfg: &Config, bus: &EventBus, state: &mut FsState) -> Result<()> {
-    let include = build_glob_set(&cfg.include)?;
-    let exclude = build_glob_set(&cfg.exclude)?;
+    let current = scan_roots(cfg)?;
+    emit_delta(bus, &state.files, &current)?;
+    *state = FsState { files: current };
+    Ok(())
+}
+
+/// Reconcile `state` and the `files` table with the disk and publish a
+/// corrective `SyncDelta` for whatever drifted. Returns the number of drifted
+/// entries.
+///
+/// The catalog is used as the baseline, so both changes the watcher missed and
+/// deltas that never reached the catalog are corrected. Catalog rows outside
+/// the configured roots are left alone, and so are files whose row was updated
+/// after the scan started: the watcher reported them while the disk was being
+/// walked, and the walk may have seen them before or after that change.
+///
+/// `state` is locked only once the disk has been walked, and only drifted or
+/// missing entries are replaced, so the watcher may keep updating it meanwhile.
+pub fn guard_scan(
+    cfg: &Config,
+    bus: &EventBus,
+    conn: &Connection,
+    state: &Mutex<FsState>,
+) -> Result<usize> {
+    let started = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let scanned = scan_roots(cfg)?;
+    let (catalog, recent) = load_catalog(conn, &cfg.roots, started)?;
+    let mut current = scanned.clone();
+    current.retain(|uid, _| !recent.contains(uid));
+
+    let mut state = state.lock().unwrap();
+    let drift = emit_delta(bus, &catalog, &current)?;
+    for (uid, info) in scanned {
+        let drifted = current.contains_key(&uid)
+            && catalog
+                .get(&uid)
+                .is_none_or(|c| c.path != info.path || c.fast_sig != info.fast_sig);
+        if drifted || !state.files.contains_key(&uid) {
+            state.files.insert(uid, info);
+        }
+    }
+    for uid in catalog.keys().filter(|uid| !current.contains_key(*uid)) {
+        state.files.remove(uid);
+    }
+    Ok(drift)
+}
+
+/// Walk every configured root and collect the accepted files.
+fn scan_roots(cfg: &Config) -> Result<HashMap<String, FileInfo>> {
+    let filter = Filter::new(cfg)?;
     let mut current: HashMap<String, FileInfo> = HashMap::new();
 
     for root in &cfg.roots {
         if !root.exists() {
             anyhow::bail!("root path not found: {}", root);
         }
-        let walker = WalkBuilder::new(root)
-            .hidden(false)
-            .follow_links(cfg.follow_symlinks)
-            .build();
-        for dent in walker {
-            let dent = match dent {
-                Ok(d) => d,
-                Err(_) => continue,
-            };
-            if !dent.file_type().map(|ft| ft.is_file()).unwrap_or(false) {
-                continue;
-            }
-            let path = match Utf8Path::from_path(dent.path()) {
-                Some(p) => p.to_owned(),
-                None => continue,
-            };
-            if !cfg.include_hidden {
-                if path
-                    .file_name()
-                    .map(|n| n.starts_with('.'))
-                    .unwrap_or(false)
-                {
-                    continue;
-                }
-            }
-            let mirror_root = if cfg.mirror.root.is_absolute() {
-                cfg.mirror.root.clone()
-            } else {
-                root.join(&cfg.mirror.root)
-            };
-            if path.starts_with(&mirror_root) {
-                continue;
+        walk(cfg, &filter, root, root, None, |_| true, &mut current)?;
+    }
+    Ok(current)
+}
+
+/// Load the non-deleted `files` rows located under `roots` that were last
+/// updated before `before`, in seconds, along with the uids of those updated
+/// since.
+fn load_catalog(
+    conn: &Connection,
+    roots: &[Utf8PathBuf],
+    before: i64,
+) -> Result<(HashMap<String, FileInfo>, HashSet<String>)> {
+    let mut stmt = conn.prepare(
+        "SELECT inode_hint, realpath, size, mtime_ns, IFNULL(fast_sig, ''), is_offline, IFNULL(attrs, 0), updated_ts \
+         FROM files WHERE status != 'deleted' AND inode_hint IS NOT NULL",
+    )?;
+    let rows = stmt.query_map([], |r| {
+        let info = FileInfo {
+            file_uid: r.get(0)?,
+            path: Utf8PathBuf::from(r.get::<_, String>(1)?),
+            size: r.get::<_, i64>(2)? as u64,
+            mtime_ns: r.get(3)?,
+            fast_sig: r.get(4)?,
+            is_offline: r.get::<_, i64>(5)? != 0,
+            attrs: r.get::<_, i64>(6)? as u64,
+        };
+        Ok((info, r.get::<_, i64>(7)?))
+    })?;
+    let mut files = HashMap::new();
+    let mut recent = HashSet::new();
+    for row in rows {
+        let (info, updated_ts) = row?;
+        if !roots.iter().any(|root| info.path.starts_with(root)) {
+            continue;
+        }
+        if updated_ts >= before {
+            recent.insert(info.file_uid);
+        } else {
+            files.insert(info.file_uid.clone(), info);
+        }
+    }
+    Ok((files, recent))
+}
+
+/// Apply changed `paths` to `state` and publish the resulting `SyncDelta`.
+///
+/// Only the given paths are examined: files and directories that no longer
+/// exist drop every entry at or below them, directories are walked, and files
+/// are re-read from their parent directory so ignore rules still apply. A
+/// rename shows up as a removed path and a new path sharing a `file_uid`,
+/// which `emit_delta` reports as a move.
+fn apply_paths(
+    cfg: &Config,
+    bus: &EventBus,
+    state: &mut FsState,
+    paths: &HashSet<Utf8PathBuf>,
+) -> Result<()> {
+    let filter = Filter::new(cfg)?;
+    let mut current = state.files.clone();
+    current.retain(|_, info| !info.path.ancestors().any(|a| paths.contains(a)));
+
+    let mut by_parent: HashMap<(&Utf8Path, &Utf8Path), HashSet<&Utf8Path>> = HashMap::new();
+    for path in paths {
+        let Some(root) = cfg.roots.iter().find(|r| path.starts_with(r)) else {
+            continue;
+        };
+        match std::fs::metadata(path) {
+            Ok(meta) if meta.is_dir() => {
+                walk(cfg, &filter, root, path, None, |_| true, &mut current)?;
             }
-            if !include.is_match(path.as_std_path()) || exclude.is_match(path.as_std_path()) {
-                continue;
+            Ok(_) if filter.accept(cfg, root, path) => {
+                let parent = path.parent().unwrap_or(root);
+                by_parent.entry((root, parent)).or_default().insert(path);
             }
-            let info = gather_info(&path)?;
-            current.insert(info.file_uid.clone(), info);
+            _ => {}
         }
     }
+    for ((root, parent), files) in by_parent {
+        walk(
+            cfg,
+            &filter,
+            root,
+            parent,
+            Some(1),
+            |p| files.contains(p),
+            &mut current,
+        )?;
+    }
 
-    emit_delta(bus, state, &current)?;
+    emit_delta(bus, &state.files, &current)?;
     *state = FsState { files: current };
     Ok(())
 }
 
-/// Watch for filesystem changes and periodically rescan roots. Multiple rapid
-/// changes are coalesced into a single `SyncDelta` event via a 300ms debounce.
-pub fn watch(cfg: &Config, bus: EventBus, stop: &AtomicBool) -> Result<()> {
-    let mut state = FsState::default();
-    cold_scan(cfg, &bus, &mut state)?;
+/// Include and exclude rules deciding which files are cataloged.
+struct Filter {
+    include: GlobSet,
+    exclude: GlobSet,
+}
+
+impl Filter {
+    fn new(cfg: &Config) -> Result<Self> {
+        Ok(Self {
+            include: build_glob_set(&cfg.include)?,
+            exclude: build_glob_set(&cfg.exclude)?,
+        })
+    }
+
+    /// Whether the file at `path` under `root` should be cataloged.
+    fn accept(&self, cfg: &Config, root: &Utf8Path, path: &Utf8Path) -> bool {
+        if !cfg.include_hidden
+            && path
+                .file_name()
+                .map(|n| n.starts_with('.'))
+                .unwrap_or(false)
+        {
+            return false;
+        }
+        let mirror_root = if cfg.mirror.root.is_absolute() {
+            cfg.mirror.root.clone()
+        } else {
+            root.join(&cfg.mirror.root)
+        };
+        if path.starts_with(&mirror_root) {
+            return false;
+        }
+        self.include.is_match(path.as_std_path()) && !self.exclude.is_match(path.as_std_path())
+    }
+}
 
+/// Walk `dir` below `root` and record accepted files for which `keep` holds.
+fn walk(
+    cfg: &Config,
+    filter: &Filter,
+    root: &Utf8Path,
+    dir: &Utf8Path,
+    max_depth: Option<usize>,
+    keep: impl Fn(&Utf8Path) -> bool,
+    out: &mut HashMap<String, FileInfo>,
+) -> Result<()> {
+    let walker = WalkBuilder::new(dir)
+        .hidden(false)
+        .follow_links(cfg.follow_symlinks)
+        .max_depth(max_depth)
+        .build();
+    for dent in walker {
+        let dent = match dent {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+        if !dent.file_type().map(|ft| ft.is_file()).unwrap_or(false) {
+            continue;
+        }
+        let path = match Utf8Path::from_path(dent.path()) {
+            Some(p) => p.to_owned(),
+            None => continue,
+        };
+        if !keep(&path) || !filter.accept(cfg, root, &path) {
+            continue;
+        }
+        let info = gather_info(&path)?;
+        out.insert(info.file_uid.clone(), info);
+    }
+    Ok(())
+}
+
+/// Watch for filesystem changes. Paths reported by `notify` are collected and,
+/// once no event arrived for 300ms, applied to the in-memory state as a single
+/// `SyncDelta`. A full rescan runs only when the watcher reports an error or
+/// asks for a rescan, or when applying the paths fails. Every
+/// `guard_interval_secs` a guard rescan on its own thread reconciles the state
+/// and the catalog with the disk to recover from dropped events.
+pub fn watch(cfg: &Config, bus: EventBus, stop: &AtomicBool) -> Result<()> {
     let (tx, rx) = std::sync::mpsc::channel();
     let mut watcher = RecommendedWatcher::new(
         move |res| {
@@ -101,26 +275,83 @@ pub fn watch(cfg: &Config, bus: EventBus, stop: &AtomicBool) -> Result<()> {
         },
         notify::Config::default(),
     )?;
+    let mut roots = Vec::new();
     for root in &cfg.roots {
         watcher.watch(root.as_std_path(), RecursiveMode::Recursive)?;
+        let abs = root.canonicalize_utf8().unwrap_or_else(|_| root.clone());
+        roots.push((root.clone(), abs));
     }
 
+    // The watcher is registered first so nothing changed during the scan is
+    // lost. Starting from the catalog catches changes made while not watching.
+    let conn = db::open(&cfg.db)?;
+    let state = Mutex::new(FsState::default());
+    let drift = guard_scan(cfg, &bus, &conn, &state)?;
+    tracing::info!(drift, "initial scan");
+
+    let done = AtomicBool::new(false);
+    thread::scope(|s| {
+        if cfg.guard_interval_secs > 0 {
+            let (bus, state, done) = (&bus, &state, &done);
+            s.spawn(move || guard_loop(cfg, bus, &conn, state, stop, done));
+        }
+        let res = watch_loop(cfg, &bus, &rx, &roots, &state, stop);
+        done.store(true, Ordering::SeqCst);
+        res
+    })
+}
+
+/// Apply the paths reported by the watcher until `stop` is raised.
+fn watch_loop(
+    cfg: &Config,
+    bus: &EventBus,
+    rx: &std::sync::mpsc::Receiver<notify::Result<notify::Event>>,
+    roots: &[(Utf8PathBuf, Utf8PathBuf)],
+    state: &Mutex<FsState>,
+    stop: &AtomicBool,
+) -> Result<()> {
     let debounce = Duration::from_millis(300);
     let mut last_event: Option<Instant> = None;
+    let mut dirty: HashSet<Utf8PathBuf> = HashSet::new();
+    let mut rescan = false;
 
     while !stop.load(Ordering::SeqCst) {
         match rx.recv_timeout(Duration::from_millis(100)) {
-            Ok(Ok(_event)) => {
+            Ok(Ok(event)) if matches!(event.kind, EventKind::Access(_)) => {}
+            Ok(Ok(event)) => {
+                rescan |= event.need_rescan();
+                dirty.extend(
+                    event
+                        .paths
+                        .iter()
+                        .filter_map(|p| Utf8Path::from_path(p))
+                        .filter_map(|p| to_root_path(roots, p)),
+                );
+                last_event = Some(Instant::now());
+            }
+            Ok(Err(e)) => {
+                tracing::warn!(error = %e, "watcher error, scheduling rescan");
+                rescan = true;
                 last_event = Some(Instant::now());
             }
-            Ok(Err(_)) => {}
             Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
             Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
         }
 
         if let Some(t) = last_event {
             if t.elapsed() > debounce {
-                cold_scan(cfg, &bus, &mut state)?;
+                let mut state = state.lock().unwrap();
+                if !rescan {
+                    if let Err(e) = apply_paths(cfg, bus, &mut state, &dirty) {
+                        tracing::warn!(error = %e, "incremental update failed, rescanning");
+                        rescan = true;
+                    }
+                }
+                if rescan {
+                    cold_scan(cfg, bus, &mut state)?;
+                }
+                dirty.clear();
+                rescan = false;
                 last_event = None;
             }
         }
@@ -128,13 +359,62 @@ pub fn watch(cfg: &Config, bus: EventBus, stop: &AtomicBool) -> Result<()> {
     Ok(())
 }
 
-fn emit_delta(bus: &EventBus, state: &FsState, current: &HashMap<String, FileInfo>) -> Result<()> {
+/// Run a guard rescan every `guard_interval_secs` until `stop` or `done` is
+/// raised.
+fn guard_loop(
+    cfg: &Config,
+    bus: &EventBus,
+    conn: &Connection,
+    state: &Mutex<FsState>,
+    stop: &AtomicBool,
+    done: &AtomicBool,
+) {
+    let interval = Duration::from_secs(cfg.guard_interval_secs);
+    let mut last_guard = Instant::now();
+    while !stop.load(Ordering::SeqCst) && !done.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(100));
+        if last_guard.elapsed() < interval {
+            continue;
+        }
+        match guard_scan(cfg, bus, conn, state) {
+            Ok(drift) => tracing::info!(drift, "guard rescan"),
+            Err(e) => tracing::warn!(error = %e, "guard rescan failed"),
+        }
+        last_guard = Instant::now();
+    }
+}
+
+/// Express an event path in terms of the configured root it belongs to, so it
+/// matches the paths recorded by scans.
+fn to_root_path(roots: &[(Utf8PathBuf, Utf8PathBuf)], path: &Utf8Path) -> Option<Utf8PathBuf> {
+    for (root, abs) in roots {
+        if path.starts_with(root) {
+            return Some(path.to_owned());
+        }
+        if let Ok(rel) = path.strip_prefix(abs) {
+            return Some(if rel.as_str().is_empty() {
+                root.clone()
+            } else {
+                root.join(rel)
+            });
+        }
+    }
+    None
+}
+
+/// Publish the differences between `previous` and `current` as a `SyncDelta`.
+/// Returns the number of changed entries; nothing is published when it is zero.
+fn emit_delta(
+    bus: &EventBus,
+    previous: &HashMap<String, FileInfo>,
+    current: &HashMap<String, FileInfo>,
+) -> Result<usize> {
     let mut added = Vec::new();
     let mut modified = Vec::new();
     let mut moved = Vec::new();
 
     for info in current.values() {
-        if let Some(old) = state.files.get(&info.file_uid) {
+        if let Some(old) = previous.get(&info.file_uid) {
             if old.path != info.path {
                 moved.push(FileMove {
                     file_uid: info.file_uid.clone(),
@@ -149,15 +429,15 @@ fn emit_delta(bus: &EventBus, state: &FsState, current: &HashMap<String, FileInf
         }
     }
 
-    let deleted = state
-        .files
+    let deleted = previous
         .iter()
         .filter(|(uid, _)| !current.contains_key(*uid))
         .map(|(_, info)| to_meta(info))
         .collect::<Vec<_>>();
 
-    if added.is_empty() && modified.is_empty() && moved.is_empty() && deleted.is_empty() {
-        return Ok(());
+    let changed = added.len() + modified.len() + moved.len() + deleted.len();
+    if changed == 0 {
+        return Ok(0);
     }
 
     bus.publish_source(SourceEvent::SyncDelta {
@@ -166,7 +446,7 @@ fn emit_delta(bus: &EventBus, state: &FsState, current: &HashMap<String, FileInf
         moved,
         deleted,
     })?;
-    Ok(())
+    Ok(changed)
 }
 
 fn to_meta(info: &FileInfo) -> FileMeta {
@@ -283,15 +563,166 @@ fn compute_fast_sig(meta: &std::fs::Metadata) -> (String, bool, u64) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bus::EventBus;
     use crate::db;
-    use crate::{
-        bus::EventBus,
-        config::{BusBounds, BusConfig, ExtractConfig, MirrorConfig, RetentionConfig},
-    };
     use std::sync::{atomic::AtomicBool, Arc, Mutex};
     use std::time::Duration;
     use tempfile::tempdir;
 
+    fn names(files: &[FileMeta]) -> Vec<&str> {
+        let mut names: Vec<&str> = files.iter().filter_map(|f| f.path.file_name()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn apply_paths_updates_only_changed_paths() -> Result<()> {
+        let tmp = tempdir()?;
+        let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
+        let cfg = crate::config::Config {
+            include: vec!["**/*.txt".into()],
+            ..crate::config::Config::for_test(&root)
+        };
+        std::fs::write(root.join("a.txt"), b"a")?;
+        std::fs::write(root.join("b.txt"), b"b")?;
+        std::fs::create_dir_all(root.join("sub"))?;
+        std::fs::write(root.join("sub/c.txt"), b"c")?;
+        std::fs::write(root.join("sub/f.txt"), b"f")?;
+
+        let bus = EventBus::new(&cfg.bus.bounds, Arc::new(Mutex::new(db::open(&cfg.db)?)));
+        let rx = bus.subscribe_source();
+        let mut state = FsState::default();
+        cold_scan(&cfg, &bus, &mut state)?;
+        rx.recv()?;
+
+        std::fs::write(root.join("a.txt"), b"changed")?;
+        std::fs::write(root.join("d.txt"), b"d")?;
+        std::fs::rename(root.join("b.txt"), root.join("e.txt"))?;
+        std::fs::remove_file(root.join("sub/c.txt"))?;
+        let paths: HashSet<Utf8PathBuf> = ["a.txt", "d.txt", "b.txt", "e.txt", "sub/c.txt"]
+            .iter()
+            .map(|p| root.join(p))
+            .collect();
+        apply_paths(&cfg, &bus, &mut state, &paths)?;
+        match rx.try_recv()?.data {
+            SourceEvent::SyncDelta {
+                added,
+                modified,
+                moved,
+                deleted,
+            } => {
+                assert_eq!(names(&added), ["d.txt"]);
+                assert_eq!(names(&modified), ["a.txt"]);
+                assert_eq!(moved.len(), 1);
+                assert_eq!(moved[0].from, root.join("b.txt"));
+                assert_eq!(moved[0].to, root.join("e.txt"));
+                assert_eq!(names(&deleted), ["c.txt"]);
+            }
+            other => panic!("unexpected event {other:?}"),
+        }
+
+        // Renaming a directory moves every file below it.
+        std::fs::rename(root.join("sub"), root.join("moved"))?;
+        let paths: HashSet<Utf8PathBuf> = [root.join("sub"), root.join("moved")].into();
+        apply_paths(&cfg, &bus, &mut state, &paths)?;
+        match rx.try_recv()?.data {
+            SourceEvent::SyncDelta {
+                added,
+                moved,
+                deleted,
+                ..
+            } => {
+                assert!(added.is_empty() && deleted.is_empty());
+                assert_eq!(moved.len(), 1);
+                assert_eq!(moved[0].to, root.join("moved/f.txt"));
+            }
+            other => panic!("unexpected event {other:?}"),
+        }
+
+        // Paths outside the include rules publish nothing.
+        std::fs::write(root.join("notes.log"), b"x")?;
+        let paths: HashSet<Utf8PathBuf> = [root.join("notes.log")].into();
+        apply_paths(&cfg, &bus, &mut state, &paths)?;
+        assert!(rx.try_recv().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn guard_scan_corrects_state_and_catalog_drift() -> Result<()> {
+        let tmp = tempdir()?;
+        let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
+        let cfg = crate::config::Config {
+            include: vec!["**/*.txt".into()],
+            ..crate::config::Config::for_test(&root)
+        };
+        std::fs::write(root.join("a.txt"), b"a")?;
+        std::fs::write(root.join("b.txt"), b"b")?;
+
+        let bus = EventBus::new(&cfg.bus.bounds, Arc::new(Mutex::new(db::open(&cfg.db)?)));
+        let stop = Arc::new(AtomicBool::new(false));
+        let metadata = crate::metadata::spawn(bus.clone(), cfg.clone(), stop.clone());
+        let mut state = FsState::default();
+        cold_scan(&cfg, &bus, &mut state)?;
+        stop.store(true, Ordering::SeqCst);
+        metadata.join().unwrap()?;
+
+        // The catalog lost b.txt and kept a row for a file that is gone, and
+        // the watcher missed the creation of c.txt.
+        let conn = db::open(&cfg.db)?;
+        conn.execute(
+            "DELETE FROM files WHERE realpath=?1",
+            [root.join("b.txt").as_str()],
+        )?;
+        conn.execute(
+            "INSERT INTO files (realpath, size, mtime_ns, fast_sig, is_offline, attrs, inode_hint, status, created_ts, updated_ts) VALUES (?1,0,0,'sig',0,0,'ghost','active',0,0)",
+            [root.join("ghost.txt").as_str()],
+        )?;
+        std::fs::write(root.join("c.txt"), b"c")?;
+        // A row the metadata service updated after the scan started is left
+        // to the watcher, even though its file is gone.
+        conn.execute(
+            "INSERT INTO files (realpath, size, mtime_ns, fast_sig, is_offline, attrs, inode_hint, status, created_ts, updated_ts) VALUES (?1,0,0,'sig',0,0,'recent','active',0,?2)",
+            rusqlite::params![root.join("recent.txt").as_str(), i64::MAX],
+        )?;
+
+        let rx = bus.subscribe_source();
+        let state = Mutex::new(state);
+        assert_eq!(guard_scan(&cfg, &bus, &conn, &state)?, 3);
+        match rx.try_recv()?.data {
+            SourceEvent::SyncDelta {
+                added,
+                modified,
+                moved,
+                deleted,
+            } => {
+                assert_eq!(names(&added), ["b.txt", "c.txt"]);
+                assert!(modified.is_empty() && moved.is_empty());
+                assert_eq!(names(&deleted), ["ghost.txt"]);
+            }
+            other => panic!("unexpected event {other:?}"),
+        }
+        assert_eq!(state.lock().unwrap().files.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn event_paths_map_to_configured_roots() {
+        let roots = vec![(Utf8PathBuf::from("docs"), Utf8PathBuf::from("/data/docs"))];
+        assert_eq!(
+            to_root_path(&roots, Utf8Path::new("/data/docs/a/b.txt")),
+            Some(Utf8PathBuf::from("docs/a/b.txt"))
+        );
+        assert_eq!(
+            to_root_path(&roots, Utf8Path::new("docs/b.txt")),
+            Some(Utf8PathBuf::from("docs/b.txt"))
+        );
+        assert_eq!(
+            to_root_path(&roots, Utf8Path::new("/data/docs")),
+            Some(Utf8PathBuf::from("docs"))
+        );
+        assert_eq!(to_root_path(&roots, Utf8Path::new("/other/b.txt")), None);
+    }
+
     #[test]
     #[ignore]
     fn debounced_events_single_syncdelta() -> Result<()> {
@@ -300,36 +731,8 @@ mod tests {
         std::fs::write(root.join("a.txt"), b"hello")?;
 
         let cfg = crate::config::Config {
-            db: root.join("catalog.db"),
-            tantivy_index: Utf8PathBuf::from("idx"),
-            roots: vec![root.clone()],
             include: vec!["**/*.txt".into()],
-            exclude: vec![],
-            max_file_size_mb: 200,
-            follow_symlinks: false,
-            include_hidden: false,
-            allow_offline_hydration: false,
-            commit_interval_secs: 45,
-            guard_interval_secs: 180,
-            default_language: "auto".into(),
-            extractor_cmd: String::new(),
-            embedding: crate::config::EmbeddingConfig {
-                provider: "disabled".into(),
-            },
-            mirror: MirrorConfig {
-                root: Utf8PathBuf::from("raw"),
-            },
-            bus: BusConfig {
-                bounds: BusBounds {
-                    source_fs: 16,
-                    mirror_text: 16,
-                },
-            },
-            extract: ExtractConfig {
-                pool_size: 1,
-                jobs_bound: 16,
-            },
-            retention: RetentionConfig::default(),
+            ..crate::config::Config::for_test(&root)
         };
 
         let conn = db::open(&cfg.db)?;
@@ -0,0 +1,160 @@
+//! Bigram segmentation of Chinese, Japanese and Korean text.
+//!
+//! These scripts do not separate words with spaces, so the regex tokenizer
+//! emits each run of CJK characters as a single token. [`CjkBigramFilter`]
+//! splits such runs into overlapping character bigrams at consecutive
+//! positions, letting a query match any substring of a run as a phrase. Each
+//! character is also kept as a unigram at the position of the bigram it
+//! starts, as Lucene's `CJKBigramFilter` does with `outputUnigrams`, so
+//! one-character words can be searched.
+
+use tantivy::tokenizer::{Token, TokenFilter, TokenStream, Tokenizer};
+
+/// Regex class items covering the CJK scripts. Tokenizer patterns match runs
+/// of these separately from other letters so mixed text splits at script
+/// boundaries.
+pub const CJK_SCRIPTS: &str = r"\p{Han}\p{Hiragana}\p{Katakana}\p{Hangul}\x{30FC}";
+
+/// Returns true for characters handled by the CJK analyzer.
+pub fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{1100}'..='\u{11FF}'
+        | '\u{2E80}'..='\u{2FDF}'
+        | '\u{3040}'..='\u{30FF}'
+        | '\u{3130}'..='\u{318F}'
+        | '\u{31F0}'..='\u{31FF}'
+        | '\u{3400}'..='\u{4DBF}'
+        | '\u{4E00}'..='\u{9FFF}'
+        | '\u{A960}'..='\u{A97F}'
+        | '\u{AC00}'..='\u{D7FF}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{FF66}'..='\u{FF9F}'
+        | '\u{20000}'..='\u{3FFFF}')
+}
+
+/// Token filter replacing runs of CJK characters with their characters and
+/// overlapping bigrams. Single-character runs and other tokens pass through
+/// unchanged.
+#[derive(Clone, Copy, Default)]
+pub struct CjkBigramFilter;
+
+impl TokenFilter for CjkBigramFilter {
+    type Tokenizer<T: Tokenizer> = CjkBigramFilterWrapper<T>;
+
+    fn transform<T: Tokenizer>(self, tokenizer: T) -> CjkBigramFilterWrapper<T> {
+        CjkBigramFilterWrapper { inner: tokenizer }
+    }
+}
+
+#[derive(Clone)]
+pub struct CjkBigramFilterWrapper<T> {
+    inner: T,
+}
+
+impl<T: Tokenizer> Tokenizer for CjkBigramFilterWrapper<T> {
+    type TokenStream<'a> = CjkBigramTokenStream<T::TokenStream<'a>>;
+
+    fn token_stream<'a>(&'a mut self, text: &'a str) -> Self::TokenStream<'a> {
+        CjkBigramTokenStream {
+            tail: self.inner.token_stream(text),
+            pending: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+pub struct CjkBigramTokenStream<T> {
+    tail: T,
+    /// Tokens produced from the current input token, in reverse order.
+    pending: Vec<Token>,
+    /// Position of the next emitted token.
+    position: usize,
+}
+
+impl<T: TokenStream> CjkBigramTokenStream<T> {
+    fn split(&mut self) {
+        let token = self.tail.token();
+        let chars: Vec<(usize, char)> = token.text.char_indices().collect();
+        if chars.len() < 2 || !chars.iter().all(|&(_, c)| is_cjk(c)) {
+            self.pending.push(Token {
+                position: self.position,
+                ..token.clone()
+            });
+            self.position += 1;
+            return;
+        }
+        let len = token.text.len();
+        let end = |i: usize| chars.get(i).map_or(len, |&(b, _)| b);
+        for (i, &(start, _)) in chars.iter().enumerate().rev() {
+            let spans = [Some(end(i + 1)), (i + 1 < chars.len()).then(|| end(i + 2))];
+            for stop in spans.into_iter().flatten().rev() {
+                self.pending.push(Token {
+                    offset_from: token.offset_from + start,
+                    offset_to: token.offset_from + stop,
+                    position: self.position + i,
+                    text: token.text[start..stop].to_string(),
+                    position_length: 1,
+                });
+            }
+        }
+        self.position += chars.len();
+    }
+}
+
+impl<T: TokenStream> TokenStream for CjkBigramTokenStream<T> {
+    fn advance(&mut self) -> bool {
+        self.pending.pop();
+        if !self.pending.is_empty() {
+            return true;
+        }
+        if !self.tail.advance() {
+            return false;
+        }
+        self.split();
+        true
+    }
+
+    fn token(&self) -> &Token {
+        self.pending.last().unwrap_or_else(|| self.tail.token())
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        self.pending
+            .last_mut()
+            .unwrap_or_else(|| self.tail.token_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::tokenizer::{RegexTokenizer, TextAnalyzer};
+
+    #[test]
+    fn runs_become_unigrams_and_bigrams_at_consecutive_positions() {
+        let pattern = format!(r"[{CJK_SCRIPTS}]+|[\p{{L}}--[{CJK_SCRIPTS}]]+");
+        let mut analyzer = TextAnalyzer::builder(RegexTokenizer::new(&pattern).unwrap())
+            .filter(CjkBigramFilter)
+            .build();
+        let mut tokens = Vec::new();
+        analyzer
+            .token_stream("東京都 in日本 x 字")
+            .process(&mut |t| tokens.push((t.text.clone(), t.position)));
+        assert_eq!(
+            tokens,
+            [
+                ("東".to_string(), 0),
+                ("東京".to_string(), 0),
+                ("京".to_string(), 1),
+                ("京都".to_string(), 1),
+                ("都".to_string(), 2),
+                ("in".to_string(), 3),
+                ("日".to_string(), 4),
+                ("日本".to_string(), 4),
+                ("本".to_string(), 5),
+                ("x".to_string(), 6),
+                ("字".to_string(), 7),
+            ]
+        );
+    }
+}
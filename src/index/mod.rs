@@ -1,77 +1,103 @@
 //! Tantivy index builder for `findx`.
 
-use std::{fs, io, thread, time::Duration};
+mod cjk;
 
-use anyhow::Result;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::{fs, io};
+
+use anyhow::{anyhow, bail, Context, Result};
 use camino::Utf8Path;
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use rusqlite::{params, Connection, OptionalExtension};
+use tantivy::directory::MmapDirectory;
 use tantivy::schema::{
-    Field, Schema, SchemaBuilder, TextFieldIndexing, TextOptions, STORED, STRING,
+    Field, Schema, SchemaBuilder, TextFieldIndexing, TextOptions, FAST, STORED, STRING,
+};
+use tantivy::tokenizer::{
+    AsciiFoldingFilter, Language, LowerCaser, RegexTokenizer, RemoveLongFilter, Stemmer,
+    StopWordFilter, TextAnalyzer,
 };
-use tantivy::tokenizer::{LowerCaser, RegexTokenizer, RemoveLongFilter, TextAnalyzer};
-use tantivy::{doc, Index};
+use tantivy::{doc, Index, IndexWriter, TantivyDocument, Term};
 use walkdir::WalkDir;
 
-use crate::config::Config;
+use crate::bus::{Envelope, EventBus};
+use crate::config::{Config, IndexConfig};
+use crate::events::MirrorEvent;
 use crate::{
-    chunk, db,
+    chunk, db, filetype, mirror,
     util::{dashboard::Dashboard, log},
 };
-use rusqlite::params;
 
 /// Fields used in the Tantivy schema.
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct IndexFields {
     pub path: Field,
-    pub body_en: Field,
-    pub body_fr: Field,
+    pub file_uid: Field,
+    /// `body_<lang>` field of each indexed language, keyed by language code.
+    pub body: Vec<(String, Field)>,
     pub mime: Field,
+    pub ext: Field,
+    pub lang: Field,
     pub mtime_ns: Field,
     pub size: Field,
     pub file_id: Field,
 }
 
 impl IndexFields {
-    /// Build a `IndexFields` from an existing schema.
-    pub fn from_schema(schema: &Schema) -> Self {
-        Self {
-            path: schema.get_field("path").unwrap(),
-            body_en: schema.get_field("body_en").unwrap(),
-            body_fr: schema.get_field("body_fr").unwrap(),
-            mime: schema.get_field("mime").unwrap(),
-            mtime_ns: schema.get_field("mtime_ns").unwrap(),
-            size: schema.get_field("size").unwrap(),
-            file_id: schema.get_field("file_id").unwrap(),
+    /// Build a `IndexFields` from an existing schema, resolving the body field
+    /// of each of `languages`.
+    pub fn from_schema(schema: &Schema, languages: &[String]) -> Result<Self> {
+        Ok(Self {
+            path: field(schema, "path")?,
+            file_uid: field(schema, "file_uid")?,
+            body: lang_fields(schema, "body", languages)?,
+            mime: field(schema, "mime")?,
+            ext: field(schema, "ext")?,
+            lang: field(schema, "lang")?,
+            mtime_ns: field(schema, "mtime_ns")?,
+            size: field(schema, "size")?,
+            file_id: field(schema, "file_id")?,
+        })
+    }
+
+    pub fn filter(&self) -> FilterFields {
+        FilterFields {
+            path: self.path,
+            mime: self.mime,
+            ext: self.ext,
+            lang: self.lang,
+            mtime_ns: self.mtime_ns,
+            size: self.size,
         }
     }
 }
 
-fn build_schema() -> (Schema, IndexFields) {
+fn build_schema(languages: &[String]) -> (Schema, IndexFields) {
     let mut builder = SchemaBuilder::new();
     let path = builder.add_text_field("path", STRING | STORED);
-    let en_opts = TextOptions::default().set_indexing_options(
-        TextFieldIndexing::default()
-            .set_tokenizer("en")
-            .set_index_option(tantivy::schema::IndexRecordOption::WithFreqsAndPositions),
-    );
-    let fr_opts = TextOptions::default().set_indexing_options(
-        TextFieldIndexing::default()
-            .set_tokenizer("fr")
-            .set_index_option(tantivy::schema::IndexRecordOption::WithFreqsAndPositions),
-    );
-    let body_en = builder.add_text_field("body_en", en_opts);
-    let body_fr = builder.add_text_field("body_fr", fr_opts);
+    let file_uid = builder.add_text_field("file_uid", STRING | STORED);
+    let body = add_lang_fields(&mut builder, "body", languages);
     let mime = builder.add_text_field("mime", STRING | STORED);
-    let mtime_ns = builder.add_i64_field("mtime_ns", STORED);
-    let size = builder.add_i64_field("size", STORED);
+    let ext = builder.add_text_field("ext", STRING);
+    let lang = builder.add_text_field("lang", STRING);
+    let mtime_ns = builder.add_i64_field("mtime_ns", STORED | FAST);
+    let size = builder.add_i64_field("size", STORED | FAST);
     let file_id = builder.add_i64_field("file_id", STORED);
     let schema = builder.build();
     (
         schema.clone(),
         IndexFields {
             path,
-            body_en,
-            body_fr,
+            file_uid,
+            body,
             mime,
+            ext,
+            lang,
             mtime_ns,
             size,
             file_id,
@@ -80,74 +106,476 @@ fn build_schema() -> (Schema, IndexFields) {
 }
 
 /// Fields used for the chunk-level Tantivy schema.
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct ChunkFields {
     pub path: Field,
-    pub chunk_text_en: Field,
-    pub chunk_text_fr: Field,
+    pub file_uid: Field,
+    /// `chunk_text_<lang>` field of each indexed language, keyed by language
+    /// code.
+    pub chunk_text: Vec<(String, Field)>,
     pub chunk_id: Field,
     pub start_byte: Field,
     pub end_byte: Field,
     pub file_id: Field,
+    pub mime: Field,
+    pub ext: Field,
+    pub lang: Field,
+    pub mtime_ns: Field,
+    pub size: Field,
 }
 
 impl ChunkFields {
-    pub fn from_schema(schema: &Schema) -> Self {
-        Self {
-            path: schema.get_field("path").unwrap(),
-            chunk_text_en: schema.get_field("chunk_text_en").unwrap(),
-            chunk_text_fr: schema.get_field("chunk_text_fr").unwrap(),
-            chunk_id: schema.get_field("chunk_id").unwrap(),
-            start_byte: schema.get_field("start_byte").unwrap(),
-            end_byte: schema.get_field("end_byte").unwrap(),
-            file_id: schema.get_field("file_id").unwrap(),
+    pub fn from_schema(schema: &Schema, languages: &[String]) -> Result<Self> {
+        Ok(Self {
+            path: field(schema, "path")?,
+            file_uid: field(schema, "file_uid")?,
+            chunk_text: lang_fields(schema, "chunk_text", languages)?,
+            chunk_id: field(schema, "chunk_id")?,
+            start_byte: field(schema, "start_byte")?,
+            end_byte: field(schema, "end_byte")?,
+            file_id: field(schema, "file_id")?,
+            mime: field(schema, "mime")?,
+            ext: field(schema, "ext")?,
+            lang: field(schema, "lang")?,
+            mtime_ns: field(schema, "mtime_ns")?,
+            size: field(schema, "size")?,
+        })
+    }
+
+    pub fn filter(&self) -> FilterFields {
+        FilterFields {
+            path: self.path,
+            mime: self.mime,
+            ext: self.ext,
+            lang: self.lang,
+            mtime_ns: self.mtime_ns,
+            size: self.size,
         }
     }
 }
 
-fn build_chunk_schema() -> (Schema, ChunkFields) {
+fn build_chunk_schema(languages: &[String]) -> (Schema, ChunkFields) {
     let mut builder = SchemaBuilder::new();
     let path = builder.add_text_field("path", STRING | STORED);
-    let en_opts = TextOptions::default().set_indexing_options(
-        TextFieldIndexing::default()
-            .set_tokenizer("en")
-            .set_index_option(tantivy::schema::IndexRecordOption::WithFreqsAndPositions),
-    );
-    let fr_opts = TextOptions::default().set_indexing_options(
-        TextFieldIndexing::default()
-            .set_tokenizer("fr")
-            .set_index_option(tantivy::schema::IndexRecordOption::WithFreqsAndPositions),
-    );
-    let chunk_text_en = builder.add_text_field("chunk_text_en", en_opts);
-    let chunk_text_fr = builder.add_text_field("chunk_text_fr", fr_opts);
+    let file_uid = builder.add_text_field("file_uid", STRING | STORED);
+    let chunk_text = add_lang_fields(&mut builder, "chunk_text", languages);
     let chunk_id = builder.add_text_field("chunk_id", STRING | STORED);
     let start_byte = builder.add_i64_field("start_byte", STORED);
     let end_byte = builder.add_i64_field("end_byte", STORED);
     let file_id = builder.add_i64_field("file_id", STORED);
+    let mime = builder.add_text_field("mime", STRING);
+    let ext = builder.add_text_field("ext", STRING);
+    let lang = builder.add_text_field("lang", STRING);
+    let mtime_ns = builder.add_i64_field("mtime_ns", FAST);
+    let size = builder.add_i64_field("size", FAST);
     let schema = builder.build();
     (
         schema.clone(),
         ChunkFields {
             path,
-            chunk_text_en,
-            chunk_text_fr,
+            file_uid,
+            chunk_text,
             chunk_id,
             start_byte,
             end_byte,
             file_id,
+            mime,
+            ext,
+            lang,
+            mtime_ns,
+            size,
         },
     )
 }
 
-pub fn register_tokenizers(index: &Index) {
+/// File attributes searches can be restricted by, present in both the
+/// document and the chunk index.
+#[derive(Clone, Copy)]
+pub struct FilterFields {
+    /// Full path, matched by prefix.
+    pub path: Field,
+    pub mime: Field,
+    /// Lowercase extension without the dot.
+    pub ext: Field,
+    /// Detected language; absent when it is unknown.
+    pub lang: Field,
+    pub mtime_ns: Field,
+    pub size: Field,
+}
+
+/// Field `name` of an index, which indexes built by an earlier version may
+/// lack.
+fn field(schema: &Schema, name: &str) -> Result<Field> {
+    schema
+        .get_field(name)
+        .map_err(|_| anyhow!("index has no `{name}` field; run `findx index` to rebuild it"))
+}
+
+fn add_lang_fields(
+    builder: &mut SchemaBuilder,
+    prefix: &str,
+    languages: &[String],
+) -> Vec<(String, Field)> {
+    languages
+        .iter()
+        .map(|lang| {
+            let opts = TextOptions::default().set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer(lang)
+                    .set_index_option(tantivy::schema::IndexRecordOption::WithFreqsAndPositions),
+            );
+            let field = builder.add_text_field(&format!("{prefix}_{lang}"), opts);
+            (lang.clone(), field)
+        })
+        .collect()
+}
+
+fn lang_fields(
+    schema: &Schema,
+    prefix: &str,
+    languages: &[String],
+) -> Result<Vec<(String, Field)>> {
+    languages
+        .iter()
+        .map(|lang| {
+            let name = format!("{prefix}_{lang}");
+            let field = schema.get_field(&name).map_err(|_| {
+                anyhow!("index has no `{name}` field; run `findx index` to rebuild it")
+            })?;
+            Ok((lang.clone(), field))
+        })
+        .collect()
+}
+
+/// Add `text` to the field of `lang`, or to every language field when the
+/// document language is unknown or not indexed.
+fn add_lang_text(tdoc: &mut TantivyDocument, fields: &[(String, Field)], lang: &str, text: &str) {
+    match fields.iter().find(|(l, _)| l == lang) {
+        Some((_, field)) => tdoc.add_text(*field, text),
+        None => {
+            for (_, field) in fields {
+                tdoc.add_text(*field, text);
+            }
+        }
+    }
+}
+
+/// Register the analyzer of each configured language under its code.
+///
+/// Tokens come from a regex that keeps decimals such as `12.4.1` and dotted
+/// acronyms such as `C.c.Q.` whole. Runs of Chinese, Japanese or Korean
+/// characters are split into bigrams in every analyzer, so these scripts are
+/// searchable inside mixed text. Tokens are then lowercased, optionally
+/// filtered for stop words, stemmed, and finally folded to ASCII so accented
+/// and unaccented spellings match. `zh`, `ja` and `ko` have no stemmer and
+/// only use the bigrams.
+pub fn register_tokenizers(index: &Index, cfg: &IndexConfig) -> Result<()> {
     let manager = index.tokenizers();
-    let pattern = r"(?:\d+(?:\.\d+)+)|(?:(?:[A-Za-z]\.){2,}[A-Za-z]?)|\p{L}+|\p{N}+";
-    let base = TextAnalyzer::builder(RegexTokenizer::new(pattern).unwrap())
-        .filter(LowerCaser)
+    for lang in &cfg.languages {
+        let stemmer = match lang.as_str() {
+            "zh" | "ja" | "ko" => None,
+            code => match stemmer_language(code) {
+                Some(language) => Some(language),
+                None => bail!("unsupported index language `{lang}`"),
+            },
+        };
+        manager.register(lang, analyzer(stemmer, cfg.stop_words));
+    }
+    Ok(())
+}
+
+/// Snowball stemmer matching an ISO 639-1 language code.
+fn stemmer_language(code: &str) -> Option<Language> {
+    Some(match code {
+        "ar" => Language::Arabic,
+        "da" => Language::Danish,
+        "de" => Language::German,
+        "el" => Language::Greek,
+        "en" => Language::English,
+        "es" => Language::Spanish,
+        "fi" => Language::Finnish,
+        "fr" => Language::French,
+        "hu" => Language::Hungarian,
+        "it" => Language::Italian,
+        "nl" => Language::Dutch,
+        "no" => Language::Norwegian,
+        "pt" => Language::Portuguese,
+        "ro" => Language::Romanian,
+        "ru" => Language::Russian,
+        "sv" => Language::Swedish,
+        "ta" => Language::Tamil,
+        "tr" => Language::Turkish,
+        _ => return None,
+    })
+}
+
+fn analyzer(stemmer: Option<Language>, stop_words: bool) -> TextAnalyzer {
+    let cjk = cjk::CJK_SCRIPTS;
+    let pattern = format!(
+        r"(?:\d+(?:\.\d+)+)|(?:(?:[A-Za-z]\.){{2,}}[A-Za-z]?)|[{cjk}]+|[\p{{L}}--[{cjk}]]+|\p{{N}}+"
+    );
+    let mut builder = TextAnalyzer::builder(RegexTokenizer::new(&pattern).unwrap())
+        .filter(cjk::CjkBigramFilter)
         .filter(RemoveLongFilter::limit(40))
-        .build();
-    manager.register("en", base.clone());
-    manager.register("fr", base);
+        .filter_dynamic(LowerCaser);
+    let Some(lang) = stemmer else {
+        return builder.build();
+    };
+    // Stop word lists are written with accents, so they are matched before
+    // stemming and folding.
+    if let Some(stop_words) = stop_words.then(|| StopWordFilter::new(lang)).flatten() {
+        builder = builder.filter_dynamic(stop_words);
+    }
+    builder
+        .filter(Stemmer::new(lang))
+        .filter(AsciiFoldingFilter)
+        .build()
+}
+
+/// Catalog attributes of a document as stored in the document index.
+pub struct DocRecord<'a> {
+    pub file_id: i64,
+    pub file_uid: &'a str,
+    pub path: &'a str,
+    pub mime: &'a str,
+    pub mtime_ns: i64,
+    pub size: i64,
+    pub lang: &'a str,
+}
+
+/// Chunk attributes as stored in the chunk index.
+pub struct ChunkRecord<'a> {
+    pub chunk_id: &'a str,
+    pub start_byte: i64,
+    pub end_byte: i64,
+    pub text: &'a str,
+}
+
+fn make_doc(fields: &IndexFields, rec: &DocRecord, content: &str) -> TantivyDocument {
+    let mut tdoc = doc!(
+        fields.path => rec.path,
+        fields.file_uid => rec.file_uid,
+        fields.file_id => rec.file_id,
+    );
+    add_filter_values(&mut tdoc, fields.filter(), rec);
+    add_lang_text(&mut tdoc, &fields.body, rec.lang, content);
+    tdoc
+}
+
+fn make_chunk_doc(fields: &ChunkFields, rec: &DocRecord, chunk: &ChunkRecord) -> TantivyDocument {
+    let mut tdoc = doc!(
+        fields.path => rec.path,
+        fields.file_uid => rec.file_uid,
+        fields.chunk_id => chunk.chunk_id,
+        fields.start_byte => chunk.start_byte,
+        fields.end_byte => chunk.end_byte,
+        fields.file_id => rec.file_id,
+    );
+    add_filter_values(&mut tdoc, fields.filter(), rec);
+    add_lang_text(&mut tdoc, &fields.chunk_text, rec.lang, chunk.text);
+    tdoc
+}
+
+/// Add the attributes of `rec` searches filter on. Files cataloged without a
+/// MIME type get the one of their extension.
+fn add_filter_values(tdoc: &mut TantivyDocument, fields: FilterFields, rec: &DocRecord) {
+    let mime = match rec.mime {
+        "" => filetype::mime(rec.path).unwrap_or_default(),
+        mime => mime,
+    };
+    tdoc.add_text(fields.mime, mime);
+    if let Some(ext) = filetype::extension(rec.path) {
+        tdoc.add_text(fields.ext, ext);
+    }
+    if !rec.lang.is_empty() {
+        tdoc.add_text(fields.lang, rec.lang);
+    }
+    tdoc.add_i64(fields.mtime_ns, rec.mtime_ns);
+    tdoc.add_i64(fields.size, rec.size);
+}
+
+fn open_or_create(dir: &Utf8Path, schema: Schema, cfg: &IndexConfig) -> Result<Index> {
+    fs::create_dir_all(dir)?;
+    // The schema depends on `[index] languages`; an index built with other
+    // languages must be rebuilt.
+    let index = Index::open_or_create(MmapDirectory::open(dir.as_std_path())?, schema)
+        .with_context(|| format!("opening {dir}; run `findx index` to rebuild it"))?;
+    register_tokenizers(&index, cfg)?;
+    Ok(index)
+}
+
+/// Incremental index writer applying per-document updates to both the
+/// document index and the chunk index.
+pub struct Indexer {
+    conn: Connection,
+    cfg: Config,
+    fields: IndexFields,
+    chunk_fields: ChunkFields,
+    writer: IndexWriter,
+    chunk_writer: IndexWriter,
+}
+
+impl Indexer {
+    /// Open (or create) the indexes under `tantivy_index`.
+    pub fn open(cfg: &Config) -> Result<Self> {
+        let conn = db::open(&cfg.db)?;
+        let (schema, fields) = build_schema(&cfg.index.languages);
+        let index = open_or_create(&cfg.tantivy_index, schema, &cfg.index)?;
+        let (chunk_schema, chunk_fields) = build_chunk_schema(&cfg.index.languages);
+        let chunk_index =
+            open_or_create(&cfg.tantivy_index.join("chunks"), chunk_schema, &cfg.index)?;
+        Ok(Self {
+            conn,
+            cfg: cfg.clone(),
+            fields,
+            chunk_fields,
+            writer: index.writer(50_000_000)?,
+            chunk_writer: chunk_index.writer(50_000_000)?,
+        })
+    }
+
+    /// Replace the document and chunks of `file_uid` with its current mirror.
+    pub fn upsert(&mut self, file_uid: &str) -> Result<()> {
+        self.delete(file_uid);
+        let row = self
+            .conn
+            .query_row(
+                "SELECT f.id, f.realpath, f.mtime_ns, f.size, IFNULL(f.mime, ''), IFNULL(d.content_txt, '') \
+                 FROM files f LEFT JOIN documents d ON d.file_id=f.id WHERE f.inode_hint=?1",
+                params![file_uid],
+                |r| {
+                    Ok((
+                        r.get::<_, i64>(0)?,
+                        r.get::<_, String>(1)?,
+                        r.get::<_, i64>(2)?,
+                        r.get::<_, i64>(3)?,
+                        r.get::<_, String>(4)?,
+                        r.get::<_, String>(5)?,
+                    ))
+                },
+            )
+            .optional()?;
+        let Some((file_id, path, mtime_ns, size, mime, content)) = row else {
+            tracing::warn!(file_uid, "mirrored document has no catalog entry");
+            return Ok(());
+        };
+        let Some(dir) = mirror::doc_dir(&self.conn, &self.cfg, file_uid)? else {
+            return Ok(());
+        };
+        let meta = mirror::read_meta(&dir)?;
+        let chunks = mirror::read_chunks(&dir)?;
+        let rec = DocRecord {
+            file_id,
+            file_uid,
+            path: &path,
+            mime: &mime,
+            mtime_ns,
+            size,
+            lang: &meta.lang,
+        };
+
+        // Chunks may overlap, so the document body comes from the catalog.
+        for c in &chunks {
+            self.chunk_writer.add_document(make_chunk_doc(
+                &self.chunk_fields,
+                &rec,
+                &ChunkRecord {
+                    chunk_id: &c.chunk_id,
+                    start_byte: c.byte_span.start as i64,
+                    end_byte: c.byte_span.end as i64,
+                    text: &c.text,
+                },
+            ))?;
+        }
+        self.writer
+            .add_document(make_doc(&self.fields, &rec, &content))?;
+        tracing::debug!(file_uid, chunks = chunks.len(), "indexed document");
+        Ok(())
+    }
+
+    /// Remove the document and all chunks of `file_uid`.
+    pub fn delete(&mut self, file_uid: &str) {
+        self.writer
+            .delete_term(Term::from_field_text(self.fields.file_uid, file_uid));
+        self.chunk_writer
+            .delete_term(Term::from_field_text(self.chunk_fields.file_uid, file_uid));
+    }
+
+    /// Remove a single chunk.
+    pub fn delete_chunk(&mut self, chunk_id: &str) {
+        self.chunk_writer
+            .delete_term(Term::from_field_text(self.chunk_fields.chunk_id, chunk_id));
+    }
+
+    /// Commit pending changes of both indexes.
+    pub fn commit(&mut self) -> Result<()> {
+        // Chunks first so a document never becomes searchable ahead of them.
+        self.chunk_writer.commit()?;
+        self.writer.commit()?;
+        Ok(())
+    }
+}
+
+/// Run the incremental indexer, consuming `mirror.text` events and updating
+/// only the affected documents and chunks.
+///
+/// A document and its chunks are indexed when `MirrorDocUpserted` arrives,
+/// since the mirror publishes it after `chunks.jsonl` is complete. Changes are
+/// buffered in long-lived writers and committed at most every
+/// `commit_interval_secs`; pending events are drained and committed when
+/// `stop` is set.
+pub fn run(bus: EventBus, cfg: &Config, stop: &AtomicBool) -> Result<()> {
+    let rx = bus.subscribe_mirror();
+    serve(rx, cfg, stop)
+}
+
+/// Start the incremental indexer on its own thread. It subscribes before
+/// returning, so events published afterwards are not missed.
+pub fn spawn(bus: EventBus, cfg: Config, stop: Arc<AtomicBool>) -> JoinHandle<Result<()>> {
+    let rx = bus.subscribe_mirror();
+    thread::spawn(move || serve(rx, &cfg, &stop))
+}
+
+fn serve(rx: Receiver<Envelope<MirrorEvent>>, cfg: &Config, stop: &AtomicBool) -> Result<()> {
+    let mut indexer = Indexer::open(cfg)?;
+    let interval = Duration::from_secs(cfg.commit_interval_secs);
+    let mut dirty = false;
+    let mut last_commit = Instant::now();
+    while !stop.load(Ordering::SeqCst) {
+        match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(env) => dirty |= apply_event(&mut indexer, env.data),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if dirty && last_commit.elapsed() >= interval {
+            indexer.commit()?;
+            dirty = false;
+            last_commit = Instant::now();
+        }
+    }
+    for env in rx.try_iter() {
+        dirty |= apply_event(&mut indexer, env.data);
+    }
+    if dirty {
+        indexer.commit()?;
+    }
+    Ok(())
+}
+
+/// Apply one mirror event to the index writers. Returns whether anything
+/// needs committing.
+fn apply_event(indexer: &mut Indexer, event: MirrorEvent) -> bool {
+    match event {
+        MirrorEvent::MirrorChunkUpserted { .. } => return false,
+        MirrorEvent::MirrorDocUpserted { file_uid, .. } => {
+            if let Err(e) = indexer.upsert(&file_uid) {
+                tracing::warn!(file_uid, error = %e, "failed to index document");
+            }
+        }
+        MirrorEvent::MirrorDocDeleted { file_uid } => indexer.delete(&file_uid),
+        MirrorEvent::MirrorChunkDeleted { chunk_id, .. } => indexer.delete_chunk(&chunk_id),
+    }
+    true
 }
 
 /// Rebuild the entire Tantivy index from the SQLite catalog.
@@ -158,13 +586,13 @@ pub fn reindex_all(cfg: &Config, dash: Option<&Dashboard>) -> Result<()> {
         fs::remove_dir_all(index_dir)?;
     }
     fs::create_dir_all(index_dir)?;
-    let (schema, fields) = build_schema();
+    let (schema, fields) = build_schema(&cfg.index.languages);
     let index = Index::create_in_dir(index_dir.as_std_path(), schema)?;
-    register_tokenizers(&index);
+    register_tokenizers(&index, &cfg.index)?;
     let mut writer = index.writer(50_000_000)?; // 50MB
 
     let mut stmt = conn.prepare(
-        "SELECT f.id, f.realpath, f.mtime_ns, f.size, IFNULL(f.mime, ''), \
+        "SELECT f.id, IFNULL(f.inode_hint, ''), f.realpath, f.mtime_ns, f.size, IFNULL(f.mime, ''), \
                 IFNULL(d.lang, ''), IFNULL(d.content_txt, '') \
          FROM files f JOIN documents d ON f.id=d.file_id \
          WHERE f.status='active'",
@@ -173,35 +601,30 @@ pub fn reindex_all(cfg: &Config, dash: Option<&Dashboard>) -> Result<()> {
         Ok((
             row.get::<_, i64>(0)?,
             row.get::<_, String>(1)?,
-            row.get::<_, i64>(2)?,
+            row.get::<_, String>(2)?,
             row.get::<_, i64>(3)?,
-            row.get::<_, String>(4)?,
+            row.get::<_, i64>(4)?,
             row.get::<_, String>(5)?,
             row.get::<_, String>(6)?,
+            row.get::<_, String>(7)?,
         ))
     })?;
 
     for row in rows {
-        let (id, path, mtime_ns, size, mime, lang, content) = row?;
+        let (id, file_uid, path, mtime_ns, size, mime, lang, content) = row?;
         if let Some(d) = dash {
             d.set_file(&path);
         }
-        let mut tdoc = doc!(
-            fields.path => path.clone(),
-            fields.mime => mime,
-            fields.mtime_ns => mtime_ns,
-            fields.size => size,
-            fields.file_id => id,
-        );
-        match lang.as_str() {
-            "en" => tdoc.add_text(fields.body_en, &content),
-            "fr" => tdoc.add_text(fields.body_fr, &content),
-            _ => {
-                tdoc.add_text(fields.body_en, &content);
-                tdoc.add_text(fields.body_fr, &content);
-            }
-        }
-        writer.add_document(tdoc)?;
+        let rec = DocRecord {
+            file_id: id,
+            file_uid: &file_uid,
+            path: &path,
+            mime: &mime,
+            mtime_ns,
+            size,
+            lang: &lang,
+        };
+        writer.add_document(make_doc(&fields, &rec, &content))?;
         if let Some(d) = dash {
             d.inc_file();
         }
@@ -223,13 +646,14 @@ pub fn reindex_all(cfg: &Config, dash: Option<&Dashboard>) -> Result<()> {
         fs::remove_dir_all(&chunk_dir)?;
     }
     fs::create_dir_all(&chunk_dir)?;
-    let (chunk_schema, chunk_fields) = build_chunk_schema();
+    let (chunk_schema, chunk_fields) = build_chunk_schema(&cfg.index.languages);
     let chunk_index = Index::create_in_dir(chunk_dir.as_std_path(), chunk_schema)?;
-    register_tokenizers(&chunk_index);
+    register_tokenizers(&chunk_index, &cfg.index)?;
     let mut chunk_writer = chunk_index.writer(50_000_000)?;
 
     let mut stmt = conn.prepare(
-        "SELECT f.id, f.realpath, IFNULL(d.lang,''), c.chunk_id, c.start_byte, c.end_byte, c.text \
+        "SELECT f.id, IFNULL(f.inode_hint, ''), f.realpath, IFNULL(d.lang,''), c.chunk_id, c.start_byte, c.end_byte, c.text, \
+                f.mtime_ns, f.size, IFNULL(f.mime, '') \
          FROM chunks c JOIN files f ON f.id=c.file_id \
          JOIN documents d ON d.file_id=f.id \
          WHERE f.status='active'",
@@ -240,30 +664,46 @@ pub fn reindex_all(cfg: &Config, dash: Option<&Dashboard>) -> Result<()> {
             row.get::<_, String>(1)?,
             row.get::<_, String>(2)?,
             row.get::<_, String>(3)?,
-            row.get::<_, i64>(4)?,
+            row.get::<_, String>(4)?,
             row.get::<_, i64>(5)?,
-            row.get::<_, String>(6)?,
+            row.get::<_, i64>(6)?,
+            row.get::<_, String>(7)?,
+            row.get::<_, i64>(8)?,
+            row.get::<_, i64>(9)?,
+            row.get::<_, String>(10)?,
         ))
     })?;
 
     for row in rows {
-        let (file_id, path, lang, chunk_id, start_byte, end_byte, text) = row?;
-        let mut tdoc = doc!(
-            chunk_fields.path => path,
-            chunk_fields.chunk_id => chunk_id,
-            chunk_fields.start_byte => start_byte,
-            chunk_fields.end_byte => end_byte,
-            chunk_fields.file_id => file_id,
-        );
-        match lang.as_str() {
-            "en" => tdoc.add_text(chunk_fields.chunk_text_en, &text),
-            "fr" => tdoc.add_text(chunk_fields.chunk_text_fr, &text),
-            _ => {
-                tdoc.add_text(chunk_fields.chunk_text_en, &text);
-                tdoc.add_text(chunk_fields.chunk_text_fr, &text);
-            }
-        }
-        chunk_writer.add_document(tdoc)?;
+        let (
+            file_id,
+            file_uid,
+            path,
+            lang,
+            chunk_id,
+            start_byte,
+            end_byte,
+            text,
+            mtime_ns,
+            size,
+            mime,
+        ) = row?;
+        let rec = DocRecord {
+            file_id,
+            file_uid: &file_uid,
+            path: &path,
+            mime: &mime,
+            mtime_ns,
+            size,
+            lang: &lang,
+        };
+        let chunk = ChunkRecord {
+            chunk_id: &chunk_id,
+            start_byte,
+            end_byte,
+            text: &text,
+        };
+        chunk_writer.add_document(make_chunk_doc(&chunk_fields, &rec, &chunk))?;
         if let Some(d) = dash {
             d.inc_chunk();
         }
@@ -278,22 +718,6 @@ pub fn reindex_all(cfg: &Config, dash: Option<&Dashboard>) -> Result<()> {
     let size = dir_size(index_dir)?;
     log::append(cfg, &format!("index_size\t{}", size))?;
 
-    // Compute embeddings for chunks if enabled
-    if cfg.embedding.provider != "disabled" {
-        let mut stmt = conn.prepare("SELECT chunk_id, text FROM chunks")?;
-        let rows = stmt.query_map([], |row| {
-            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
-        })?;
-        for row in rows {
-            let (chunk_id, text) = row?;
-            let emb = crate::embed::embed_text(&text)?;
-            let vec_bytes: Vec<u8> = emb.iter().flat_map(|f| f.to_le_bytes()).collect();
-            conn.execute(
-                "INSERT OR REPLACE INTO embeddings (chunk_id, model_id, dim, vec) VALUES (?1, ?2, ?3, ?4)",
-                params![chunk_id, "builtin", emb.len() as i64, vec_bytes],
-            )?;
-        }
-    }
     Ok(())
 }
 
@@ -346,21 +770,220 @@ fn dir_size(path: &Utf8Path) -> Result<u64> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::events::{FileMeta, PageBlock, SourceEvent};
+    use crate::{extract, metadata, search};
     use anyhow::Result;
+    use camino::Utf8PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
     use tantivy::schema::Schema;
     use tantivy::Index;
+    use tempfile::tempdir;
+
+    fn wait_until(f: impl Fn() -> bool) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if f() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    fn completed(content_hash: &str, text: &str) -> SourceEvent {
+        SourceEvent::ExtractionCompleted {
+            file_uid: "f1".into(),
+            content_hash: content_hash.into(),
+            extractor: "builtin".into(),
+            extractor_version: "".into(),
+            pages: vec![PageBlock {
+                page_no: 1,
+                text: text.into(),
+                start: 0,
+                end: text.chars().count(),
+            }],
+        }
+    }
 
     #[test]
-    fn tokenizer_handles_decimals_and_dotted_acronyms() -> Result<()> {
-        let schema = Schema::builder().build();
-        let index = Index::create_in_ram(schema);
-        register_tokenizers(&index);
-        let mut tokenizer = index.tokenizers().get("en").unwrap();
+    fn incremental_index_follows_mirror_events() -> Result<()> {
+        let tmp = tempdir()?;
+        let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
+        let cfg = Config {
+            commit_interval_secs: 0,
+            default_language: "en".into(),
+            ..Config::for_test(&root)
+        };
+        let conn = db::open(&cfg.db)?;
+        conn.execute(
+            "INSERT INTO files (realpath, size, mtime_ns, fast_sig, is_offline, attrs, inode_hint, status, created_ts, updated_ts) VALUES (?1,0,0,'sig',0,0,?2,'active',0,0)",
+            params![root.join("a.txt").as_str(), "f1"],
+        )?;
+        let bus = EventBus::new(&cfg.bus.bounds, Arc::new(Mutex::new(conn)));
+        let stop = Arc::new(AtomicBool::new(false));
+        for stage in [mirror::run, run] {
+            let bus_run = bus.clone();
+            let cfg_run = cfg.clone();
+            let stop_run = stop.clone();
+            thread::spawn(move || stage(bus_run, &cfg_run, &stop_run).unwrap());
+        }
+        thread::sleep(Duration::from_millis(200));
+
+        let hits = |q: &str| {
+            search::keyword(&cfg, q, 10, &search::SearchOptions::default())
+                .map(|r| r.results.len())
+                .ok()
+        };
+        let chunk_hits = |q: &str| {
+            search::keyword_chunks(&cfg, q, 10, &search::SearchOptions::default())
+                .map(|r| r.results.len())
+                .ok()
+        };
+
+        bus.publish_source(completed("h1", "hello world"))?;
+        assert!(wait_until(|| hits("hello") == Some(1)));
+        assert_eq!(chunk_hits("hello"), Some(1));
+
+        bus.publish_source(completed("h2", "goodbye world"))?;
+        assert!(wait_until(|| hits("goodbye") == Some(1)));
+        assert_eq!(hits("hello"), Some(0));
+        assert_eq!(hits("world"), Some(1));
+        assert_eq!(chunk_hits("world"), Some(1));
+
+        bus.publish_source(SourceEvent::SyncDelta {
+            added: vec![],
+            modified: vec![],
+            moved: vec![],
+            deleted: vec![FileMeta {
+                file_uid: "f1".into(),
+                path: root.join("a.txt"),
+                size: 0,
+                mtime_ns: 0,
+                fast_sig: "sig".into(),
+                is_offline: false,
+                attrs: 0,
+            }],
+        })?;
+        assert!(wait_until(|| hits("world") == Some(0)));
+        assert_eq!(chunk_hits("world"), Some(0));
+
+        stop.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    #[test]
+    fn watch_mode_rename_updates_index_path_and_mirror() -> Result<()> {
+        let tmp = tempdir()?;
+        let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
+        let docs = root.join("docs");
+        let cfg = Config {
+            roots: vec![docs.clone()],
+            include: vec!["**/*.txt".into()],
+            commit_interval_secs: 0,
+            guard_interval_secs: 0,
+            default_language: "en".into(),
+            ..Config::for_test(&root)
+        };
+        std::fs::create_dir_all(&docs)?;
+        std::fs::write(docs.join("a.txt"), "hello world")?;
+        let bus = EventBus::new(&cfg.bus.bounds, Arc::new(Mutex::new(db::open(&cfg.db)?)));
+        let stop = Arc::new(AtomicBool::new(false));
+        for stage in [metadata::run, extract::run_pool, mirror::run, run] {
+            let bus_run = bus.clone();
+            let cfg_run = cfg.clone();
+            let stop_run = stop.clone();
+            thread::spawn(move || stage(bus_run, &cfg_run, &stop_run).unwrap());
+        }
+        thread::sleep(Duration::from_millis(200));
+        let watcher = {
+            let (bus, cfg, stop) = (bus.clone(), cfg.clone(), stop.clone());
+            thread::spawn(move || crate::fs::watch(&cfg, bus, &stop))
+        };
+
+        let paths = || -> Option<(Vec<String>, Vec<String>)> {
+            let opts = search::SearchOptions::default();
+            let files = search::keyword(&cfg, "hello", 10, &opts).ok()?;
+            let chunks = search::keyword_chunks(&cfg, "hello", 10, &opts).ok()?;
+            Some((
+                files.results.into_iter().map(|h| h.path).collect(),
+                chunks.results.into_iter().map(|h| h.path).collect(),
+            ))
+        };
+        let indexed_at = |path: &Utf8Path| {
+            let path = vec![path.to_string()];
+            paths() == Some((path.clone(), path))
+        };
+        assert!(wait_until(|| indexed_at(&docs.join("a.txt"))));
+
+        std::fs::rename(docs.join("a.txt"), docs.join("b.txt"))?;
+        assert!(wait_until(|| indexed_at(&docs.join("b.txt"))));
+        assert!(cfg.mirror.root.join("b.txt/meta.json").exists());
+        assert!(!cfg.mirror.root.join("a.txt").exists());
+        let meta = std::fs::read_to_string(cfg.mirror.root.join("b.txt/meta.json"))?;
+        assert!(meta.contains(r#""path":"b.txt""#), "{meta}");
+
+        stop.store(true, Ordering::SeqCst);
+        watcher.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn commits_are_deferred_until_interval_or_shutdown() -> Result<()> {
+        let tmp = tempdir()?;
+        let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
+        let cfg = Config {
+            commit_interval_secs: 3600,
+            default_language: "en".into(),
+            ..Config::for_test(&root)
+        };
+        let conn = db::open(&cfg.db)?;
+        conn.execute(
+            "INSERT INTO files (realpath, size, mtime_ns, fast_sig, is_offline, attrs, inode_hint, status, created_ts, updated_ts) VALUES (?1,0,0,'sig',0,0,?2,'active',0,0)",
+            params![root.join("a.txt").as_str(), "f1"],
+        )?;
+        let bus = EventBus::new(&cfg.bus.bounds, Arc::new(Mutex::new(conn)));
+        let stop = Arc::new(AtomicBool::new(false));
+        mirror::spawn(bus.clone(), cfg.clone(), stop.clone());
+        let indexer = spawn(bus.clone(), cfg.clone(), stop.clone());
+        let rx = bus.subscribe_mirror();
+
+        bus.publish_source(completed("h1", "hello world"))?;
+        loop {
+            let env = rx.recv_timeout(Duration::from_secs(5))?;
+            if matches!(env.data, MirrorEvent::MirrorDocUpserted { .. }) {
+                break;
+            }
+        }
+        thread::sleep(Duration::from_millis(300));
+        let hits = |q: &str| {
+            search::keyword(&cfg, q, 10, &search::SearchOptions::default())
+                .map(|r| r.results.len())
+                .ok()
+        };
+        assert_eq!(hits("hello"), Some(0));
 
+        stop.store(true, Ordering::SeqCst);
+        indexer.join().unwrap()?;
+        assert_eq!(hits("hello"), Some(1));
+        Ok(())
+    }
+
+    fn analyze(index: &Index, lang: &str, text: &str) -> Vec<String> {
+        let mut tokenizer = index.tokenizers().get(lang).unwrap();
         let mut tokens = Vec::new();
         tokenizer
-            .token_stream("See C.c.Q. art 12.4.1 and 123.45 with I.B.M.")
+            .token_stream(text)
             .process(&mut |t| tokens.push(t.text.clone()));
+        tokens
+    }
+
+    #[test]
+    fn tokenizer_handles_decimals_and_dotted_acronyms() -> Result<()> {
+        let schema = Schema::builder().build();
+        let index = Index::create_in_ram(schema);
+        register_tokenizers(&index, &IndexConfig::default())?;
+        let tokens = analyze(&index, "en", "See C.c.Q. art 12.4.1 and 123.45 with I.B.M.");
 
         assert!(tokens.contains(&"c.c.q.".to_string()));
         assert!(tokens.contains(&"i.b.m.".to_string()));
@@ -368,4 +991,69 @@ mod tests {
         assert!(tokens.contains(&"123.45".to_string()));
         Ok(())
     }
+
+    #[test]
+    fn analyzers_stem_fold_and_drop_stop_words() -> Result<()> {
+        let index = Index::create_in_ram(Schema::builder().build());
+        register_tokenizers(&index, &IndexConfig::default())?;
+        assert_eq!(analyze(&index, "en", "running runs"), ["run", "run"]);
+        assert_eq!(
+            analyze(&index, "fr", "Résumé resume résumés"),
+            analyze(&index, "fr", "resume resume resume")
+        );
+        assert!(analyze(&index, "fr", "le contrat").contains(&"le".to_string()));
+
+        let cfg = IndexConfig {
+            stop_words: true,
+            ..IndexConfig::default()
+        };
+        register_tokenizers(&index, &cfg)?;
+        assert_eq!(analyze(&index, "en", "the contracts"), ["contract"]);
+        assert_eq!(analyze(&index, "fr", "le contrat"), ["contrat"]);
+        Ok(())
+    }
+
+    #[test]
+    fn configured_languages_drive_fields_and_analyzers() -> Result<()> {
+        let cfg = IndexConfig {
+            languages: vec!["en".into(), "de".into()],
+            ..IndexConfig::default()
+        };
+        let (schema, fields) = build_schema(&cfg.languages);
+        assert!(schema.get_field("body_de").is_ok());
+        assert!(schema.get_field("body_fr").is_err());
+        let index = Index::create_in_ram(schema.clone());
+        register_tokenizers(&index, &cfg)?;
+        assert_eq!(
+            analyze(&index, "de", "Häuser"),
+            analyze(&index, "de", "Haus")
+        );
+
+        let de = schema.get_field("body_de")?;
+        let mut tdoc = TantivyDocument::default();
+        add_lang_text(&mut tdoc, &fields.body, "de", "Haus");
+        assert_eq!(tdoc.get_all(de).count(), 1);
+        assert_eq!(tdoc.field_values().count(), 1);
+        let mut tdoc = TantivyDocument::default();
+        add_lang_text(&mut tdoc, &fields.body, "it", "casa");
+        assert_eq!(tdoc.field_values().count(), 2);
+
+        let french = vec!["fr".to_string()];
+        assert!(IndexFields::from_schema(&schema, &french).is_err());
+        let unknown = IndexConfig {
+            languages: vec!["xx".into()],
+            ..IndexConfig::default()
+        };
+        assert!(register_tokenizers(&index, &unknown).is_err());
+        let cjk = IndexConfig {
+            languages: vec!["ja".into()],
+            ..IndexConfig::default()
+        };
+        register_tokenizers(&index, &cjk)?;
+        assert_eq!(
+            analyze(&index, "ja", "議事録"),
+            ["議", "議事", "事", "事録", "録"]
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,106 @@
+//! Language detection for extracted text.
+
+use whatlang::Lang;
+
+/// Number of characters sampled from a document for detection.
+const SAMPLE_CHARS: usize = 8192;
+
+/// Detect the language of `text`, returning an ISO 639-1 code when one exists
+/// and the ISO 639-3 code otherwise. Returns `None` when the text is too short
+/// or ambiguous for a reliable guess.
+pub fn detect(text: &str) -> Option<&'static str> {
+    let sample: String = text.chars().take(SAMPLE_CHARS).collect();
+    let info = whatlang::detect(&sample)?;
+    if !info.is_reliable() {
+        return None;
+    }
+    Some(iso639_1(info.lang()))
+}
+
+/// Language recorded for a document: the detected language, or
+/// `default_language` when detection is inconclusive.
+pub fn resolve(text: &str, default_language: &str) -> String {
+    detect(text)
+        .map(str::to_string)
+        .unwrap_or_else(|| default_language.to_string())
+}
+
+fn iso639_1(lang: Lang) -> &'static str {
+    match lang {
+        Lang::Eng => "en",
+        Lang::Fra => "fr",
+        Lang::Deu => "de",
+        Lang::Spa => "es",
+        Lang::Ita => "it",
+        Lang::Por => "pt",
+        Lang::Nld => "nl",
+        Lang::Rus => "ru",
+        Lang::Ukr => "uk",
+        Lang::Pol => "pl",
+        Lang::Ces => "cs",
+        Lang::Slk => "sk",
+        Lang::Hun => "hu",
+        Lang::Ron => "ro",
+        Lang::Bul => "bg",
+        Lang::Ell => "el",
+        Lang::Tur => "tr",
+        Lang::Swe => "sv",
+        Lang::Dan => "da",
+        Lang::Nob => "no",
+        Lang::Fin => "fi",
+        Lang::Est => "et",
+        Lang::Lav => "lv",
+        Lang::Lit => "lt",
+        Lang::Slv => "sl",
+        Lang::Hrv => "hr",
+        Lang::Srp => "sr",
+        Lang::Cat => "ca",
+        Lang::Ara => "ar",
+        Lang::Heb => "he",
+        Lang::Hin => "hi",
+        Lang::Cmn => "zh",
+        Lang::Jpn => "ja",
+        Lang::Kor => "ko",
+        Lang::Vie => "vi",
+        Lang::Tha => "th",
+        Lang::Ind => "id",
+        other => other.code(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_english_and_french() {
+        assert_eq!(
+            detect(
+                "The committee reviewed the annual budget and agreed that the \
+                 remaining funds should be spent on training for new employees."
+            ),
+            Some("en")
+        );
+        assert_eq!(
+            detect(
+                "Le comité a examiné le budget annuel et a convenu que les fonds \
+                 restants seraient consacrés à la formation des nouveaux employés."
+            ),
+            Some("fr")
+        );
+    }
+
+    #[test]
+    fn falls_back_to_default_when_inconclusive() {
+        assert_eq!(resolve("12.4.1", "en"), "en");
+        assert_eq!(resolve("", "auto"), "auto");
+        assert_eq!(
+            resolve(
+                "Le contrat a été signé ce matin par les deux parties, qui se sont \
+                 engagées à respecter toutes les conditions prévues.",
+                "en"
+            ),
+            "fr"
+        );
+    }
+}
@@ -1,4 +1,7 @@
+pub mod ann;
+pub mod barrier;
 pub mod bus;
+pub mod catalog;
 pub mod chunk;
 pub mod cli;
 pub mod config;
@@ -6,8 +9,11 @@ pub mod db;
 pub mod embed;
 pub mod events;
 pub mod extract;
+pub mod filetype;
 pub mod fs;
 pub mod index;
+pub mod lang;
+pub mod maintain;
 pub mod metadata;
 pub mod mirror;
 pub mod reconcile;
@@ -1,28 +1,19 @@
-mod bus;
-mod chunk;
-mod cli;
-mod config;
-mod db;
-mod embed;
-mod events;
-mod extract;
-mod fs;
-mod index;
-mod maintain;
-mod metadata;
-mod mirror;
-mod reconcile;
-mod search;
-mod util;
-
 use anyhow::Result;
 use camino::Utf8PathBuf;
 use clap::Parser;
-use cli::{Cli, Command, OneshotArgs, WatchArgs};
+use findx::barrier::{ScanBarrier, ScanSummary};
+use findx::cli::{self, Cli, Command, OneshotArgs, WatchArgs};
+use findx::embed::EmbedSummary;
+use findx::util::{dashboard, lock::Lockfile};
+use findx::util::{log, logging};
+use findx::{
+    bus, config, db, embed, extract, fs, index, maintain, metadata, mirror, reconcile, search,
+};
 use serde::Serialize;
-use std::sync::{atomic::AtomicBool, Arc, Mutex};
-use util::logging;
-use util::{dashboard, lock::Lockfile};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
 
 fn print_json<T: Serialize>(res: &T, compact: bool) -> Result<()> {
     let json = if compact {
@@ -34,40 +25,119 @@ fn print_json<T: Serialize>(res: &T, compact: bool) -> Result<()> {
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let cli = Cli::parse();
-    logging::init(cli.log_format);
+fn search_options(q: &cli::QueryArgs) -> Result<search::SearchOptions> {
+    let time = |t: &Option<String>| t.as_deref().map(search::filter::parse_time).transpose();
+    Ok(search::SearchOptions {
+        snippet_chars: q.snippets.then_some(q.snippet_chars),
+        model: q.model.clone(),
+        exact: q.exact,
+        fusion: search::FusionOptions {
+            method: match q.fusion {
+                cli::Fusion::Rrf => search::Fusion::Rrf,
+                cli::Fusion::Linear => search::Fusion::Linear,
+            },
+            keyword_depth: q.keyword_depth,
+            semantic_depth: q.semantic_depth,
+            rrf_k: q.rrf_k,
+            keyword_weight: q.keyword_weight,
+            semantic_weight: q.semantic_weight,
+        },
+        aggregate: search::AggregateOptions {
+            method: match q.aggregate {
+                cli::Aggregate::Max => search::Aggregation::Max,
+                cli::Aggregate::TopSum => search::Aggregation::TopSum(q.top_n),
+                cli::Aggregate::Mean => search::Aggregation::Mean,
+            },
+            chunk_depth: q.chunk_depth,
+            chunks_per_file: q.chunks_per_file,
+        },
+        filters: search::Filters {
+            paths: q.paths.iter().map(|p| path_prefix(p)).collect(),
+            exts: q.exts.clone(),
+            mimes: q.mimes.clone(),
+            langs: q.langs.clone(),
+            modified_after: time(&q.modified_after)?,
+            modified_before: time(&q.modified_before)?,
+            min_size: q.min_size,
+            max_size: q.max_size,
+        },
+    })
+}
 
-    let mut cfg = match config::Config::load(&cli.config) {
-        Ok(c) => c,
-        Err(_) => config::Config::default(),
-    };
+/// Path filter as cataloged: existing paths are made absolute like the
+/// scanned roots, and directories only match the files beneath them.
+fn path_prefix(path: &str) -> String {
+    let path = Utf8PathBuf::from(path);
+    match path.canonicalize_utf8() {
+        Ok(abs) if abs.is_dir() => {
+            let mut prefix = abs.into_string();
+            if !prefix.ends_with(std::path::MAIN_SEPARATOR) {
+                prefix.push(std::path::MAIN_SEPARATOR);
+            }
+            prefix
+        }
+        Ok(abs) => abs.into_string(),
+        Err(_) => path.into_string(),
+    }
+}
+
+/// Outcome of `build_index`, printed by `findx index`.
+#[derive(Serialize)]
+struct IndexSummary {
+    #[serde(flatten)]
+    scan: ScanSummary,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    embeddings: Option<EmbedSummary>,
+}
 
+/// Scan the roots, wait for extraction and mirroring of the scanned files to
+/// finish, then rebuild the index from the catalog.
+fn build_index(
+    cfg: &config::Config,
+    bus: &bus::EventBus,
+    fs_state: &mut fs::FsState,
+) -> Result<IndexSummary> {
+    let barrier = ScanBarrier::new(bus, cfg);
+    fs::cold_scan(cfg, bus, fs_state)?;
+    let summary = barrier.wait()?;
+    tracing::info!(
+        extracted = summary.extracted,
+        failed = summary.failed,
+        skipped = summary.skipped,
+        "extraction drained"
+    );
+    log::append(
+        cfg,
+        &format!(
+            "extraction\textracted={}\tfailed={}\tskipped={}",
+            summary.extracted, summary.failed, summary.skipped
+        ),
+    )?;
     let conn = db::open(&cfg.db)?;
-    let bus = bus::EventBus::new(&cfg.bus.bounds, Arc::new(Mutex::new(conn)));
-    let bus_meta = bus.clone();
-    let cfg_meta = cfg.clone();
-    let meta_stop = Arc::new(AtomicBool::new(false));
-    let meta_stop_thread = meta_stop.clone();
-    std::thread::spawn(move || {
-        let _ = metadata::run(bus_meta, &cfg_meta, &meta_stop_thread);
-    });
-    let bus_extract = bus.clone();
-    let cfg_extract = cfg.clone();
-    let extract_stop = Arc::new(AtomicBool::new(false));
-    let extract_stop_thread = extract_stop.clone();
-    std::thread::spawn(move || {
-        let _ = extract::run_pool(bus_extract, &cfg_extract, &extract_stop_thread);
-    });
-    let bus_mirror = bus.clone();
-    let cfg_mirror = cfg.clone();
-    let mirror_stop = Arc::new(AtomicBool::new(false));
-    let mirror_stop_thread = mirror_stop.clone();
-    std::thread::spawn(move || {
-        let _ = mirror::run(bus_mirror, &cfg_mirror, &mirror_stop_thread);
-    });
-    let mut fs_state = fs::FsState::default();
+    let total_files: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM files WHERE status='active'",
+        [],
+        |r| r.get(0),
+    )?;
+    dashboard::init(total_files as u64);
+    let dash = dashboard::get();
+    index::reindex_all_with_retry(cfg, dash, 3)?;
+    // Keyword search is available now. The embedding stage does not run
+    // here, so this sweep alone embeds the new chunks and its counts are
+    // exact.
+    let embeddings = embed::embed_all(cfg)?;
+    Ok(IndexSummary {
+        scan: summary,
+        embeddings,
+    })
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    logging::init(cli.log_format);
+
+    let mut cfg = config::Config::load(&cli.config).unwrap_or_default();
+    cfg.embedding.apply_env()?;
 
     match &cli.command {
         Command::Index(args)
@@ -100,6 +170,16 @@ async fn main() -> Result<()> {
         _ => {}
     }
 
+    // Pipeline stages start once CLI overrides are applied so they share the
+    // same catalog and roots as the command.
+    let conn = db::open(&cfg.db)?;
+    let bus = bus::EventBus::new(&cfg.bus.bounds, Arc::new(Mutex::new(conn)));
+    let stages_stop = Arc::new(AtomicBool::new(false));
+    metadata::spawn(bus.clone(), cfg.clone(), stages_stop.clone());
+    extract::spawn(bus.clone(), cfg.clone(), stages_stop.clone());
+    mirror::spawn(bus.clone(), cfg.clone(), stages_stop.clone());
+    let mut fs_state = fs::FsState::default();
+
     let _lock = match &cli.command {
         Command::Index(_) | Command::Watch(_) | Command::Oneshot(_) => {
             let lock_path = Utf8PathBuf::from(".findx/state/index.lock");
@@ -111,86 +191,96 @@ async fn main() -> Result<()> {
     match &cli.command {
         Command::Index(_) => {
             tracing::info!(?cfg, "index");
-            fs::cold_scan(&cfg, &bus, &mut fs_state)?;
-            let conn = db::open(&cfg.db)?;
-            let total_files: i64 = conn.query_row(
-                "SELECT COUNT(*) FROM files WHERE status='active'",
-                [],
-                |r| r.get(0),
-            )?;
-            dashboard::init(total_files as u64);
-            let dash = dashboard::get();
-            index::reindex_all_with_retry(&cfg, dash, 3)?;
+            let summary = build_index(&cfg, &bus, &mut fs_state)?;
+            print_json(&summary, cli.compact_output)?;
         }
         Command::Watch(w) => {
             tracing::info!(threads = w.threads, ?cfg, "watch");
-            let stop = AtomicBool::new(false);
+            let indexer = index::spawn(bus.clone(), cfg.clone(), stages_stop.clone());
+            embed::spawn(bus.clone(), cfg.clone(), stages_stop.clone());
+            let stop = Arc::new(AtomicBool::new(false));
+            let stop_signal = stop.clone();
+            ctrlc::set_handler(move || stop_signal.store(true, Ordering::SeqCst))?;
             fs::watch(&cfg, bus.clone(), &stop)?;
+            // Stop the stages and wait for the indexer to commit what it buffered.
+            stages_stop.store(true, Ordering::SeqCst);
+            match indexer.join() {
+                Ok(Err(e)) => tracing::error!(error = %e, "indexer stopped"),
+                Err(_) => tracing::error!("indexer panicked"),
+                Ok(Ok(())) => {}
+            }
         }
         Command::Query(q) => {
             if !cfg.db.exists() || !cfg.tantivy_index.exists() {
                 println!("No index found, creating one under {:?}", cfg.tantivy_index);
-                fs::cold_scan(&cfg, &bus, &mut fs_state)?;
-                let conn = db::open(&cfg.db)?;
-                let total_files: i64 = conn.query_row(
-                    "SELECT COUNT(*) FROM files WHERE status='active'",
-                    [],
-                    |r| r.get(0),
-                )?;
-                dashboard::init(total_files as u64);
-                let dash = dashboard::get();
-                index::reindex_all_with_retry(&cfg, dash, 3)?;
+                build_index(&cfg, &bus, &mut fs_state)?;
             }
             tracing::info!(mode = ?q.mode, query = %q.query, top_k = q.top_k, chunks = q.chunks, ?cfg, "query");
+            let opts = search_options(q)?;
             match q.mode {
                 cli::QueryMode::Keyword => {
                     if q.chunks {
-                        let res = search::keyword_chunks(&cfg, &q.query, q.top_k)?;
+                        let res = search::keyword_chunks(&cfg, &q.query, q.top_k, &opts)?;
                         print_json(&res, cli.compact_output)?;
                     } else {
-                        let res = search::keyword(&cfg, &q.query, q.top_k)?;
+                        let res = search::keyword(&cfg, &q.query, q.top_k, &opts)?;
                         print_json(&res, cli.compact_output)?;
                     }
                 }
                 cli::QueryMode::Semantic => {
-                    let res = search::semantic_chunks(&cfg, &q.query, q.top_k)?;
-                    print_json(&res, cli.compact_output)?;
+                    if q.chunks {
+                        let res = search::semantic_chunks(&cfg, &q.query, q.top_k, &opts)?;
+                        print_json(&res, cli.compact_output)?;
+                    } else {
+                        let res = search::semantic(&cfg, &q.query, q.top_k, &opts)?;
+                        print_json(&res, cli.compact_output)?;
+                    }
                 }
                 cli::QueryMode::Hybrid => {
-                    let res = search::hybrid_chunks(&cfg, &q.query, q.top_k)?;
-                    print_json(&res, cli.compact_output)?;
+                    if q.chunks {
+                        let res = search::hybrid_chunks(&cfg, &q.query, q.top_k, &opts)?;
+                        print_json(&res, cli.compact_output)?;
+                    } else {
+                        let res = search::hybrid(&cfg, &q.query, q.top_k, &opts)?;
+                        print_json(&res, cli.compact_output)?;
+                    }
                 }
             }
         }
         Command::Oneshot(o) => {
             tracing::info!(mode = ?o.query.mode, query = %o.query.query, ?cfg, "oneshot");
-            fs::cold_scan(&cfg, &bus, &mut fs_state)?;
-            let conn = db::open(&cfg.db)?;
-            let total_files: i64 = conn.query_row(
-                "SELECT COUNT(*) FROM files WHERE status='active'",
-                [],
-                |r| r.get(0),
-            )?;
-            dashboard::init(total_files as u64);
-            let dash = dashboard::get();
-            index::reindex_all_with_retry(&cfg, dash, 3)?;
+            build_index(&cfg, &bus, &mut fs_state)?;
+            let opts = search_options(&o.query)?;
             match o.query.mode {
                 cli::QueryMode::Keyword => {
                     if o.query.chunks {
-                        let res = search::keyword_chunks(&cfg, &o.query.query, o.query.top_k)?;
+                        let res =
+                            search::keyword_chunks(&cfg, &o.query.query, o.query.top_k, &opts)?;
                         print_json(&res, cli.compact_output)?;
                     } else {
-                        let res = search::keyword(&cfg, &o.query.query, o.query.top_k)?;
+                        let res = search::keyword(&cfg, &o.query.query, o.query.top_k, &opts)?;
                         print_json(&res, cli.compact_output)?;
                     }
                 }
                 cli::QueryMode::Semantic => {
-                    let res = search::semantic_chunks(&cfg, &o.query.query, o.query.top_k)?;
-                    print_json(&res, cli.compact_output)?;
+                    if o.query.chunks {
+                        let res =
+                            search::semantic_chunks(&cfg, &o.query.query, o.query.top_k, &opts)?;
+                        print_json(&res, cli.compact_output)?;
+                    } else {
+                        let res = search::semantic(&cfg, &o.query.query, o.query.top_k, &opts)?;
+                        print_json(&res, cli.compact_output)?;
+                    }
                 }
                 cli::QueryMode::Hybrid => {
-                    let res = search::hybrid_chunks(&cfg, &o.query.query, o.query.top_k)?;
-                    print_json(&res, cli.compact_output)?;
+                    if o.query.chunks {
+                        let res =
+                            search::hybrid_chunks(&cfg, &o.query.query, o.query.top_k, &opts)?;
+                        print_json(&res, cli.compact_output)?;
+                    } else {
+                        let res = search::hybrid(&cfg, &o.query.query, o.query.top_k, &opts)?;
+                        print_json(&res, cli.compact_output)?;
+                    }
                 }
             }
         }
@@ -5,7 +5,7 @@ use anyhow::Result;
 use rusqlite::{params, Connection};
 
 use crate::config::Config;
-use crate::db;
+use crate::{ann, db};
 
 /// Run database retention tasks according to configuration.
 pub fn run(cfg: &Config) -> Result<()> {
@@ -80,6 +80,26 @@ fn clean_orphans(conn: &Connection, cfg: &Config) -> Result<()> {
         "DELETE FROM mirror_chunks WHERE file_uid NOT IN (SELECT file_uid FROM mirror_docs)",
         [],
     )?;
+    // Remove chunks of files no longer cataloged, then embeddings whose chunk
+    // text no longer exists anywhere.
+    conn.execute(
+        "DELETE FROM chunks WHERE file_id NOT IN (SELECT id FROM files)",
+        [],
+    )?;
+    let removed = conn.execute(
+        "DELETE FROM embeddings WHERE chunk_id NOT IN (SELECT chunk_id FROM chunks)",
+        [],
+    )?;
+    if removed > 0 {
+        ann::sync_all(conn, cfg)?;
+    }
+    if db::attach_vectors(conn, false)? {
+        conn.execute(
+            "DELETE FROM vectors.full_vectors WHERE NOT EXISTS (SELECT 1 FROM embeddings e \
+             WHERE e.text_hash=full_vectors.text_hash AND e.model_id=full_vectors.model_id)",
+            [],
+        )?;
+    }
     Ok(())
 }
 
@@ -102,49 +122,13 @@ fn now() -> i64 {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::{BusBounds, BusConfig, ExtractConfig, MirrorConfig, RetentionConfig};
     use tempfile::tempdir;
 
-    fn base_config(root: &camino::Utf8Path) -> Config {
-        Config {
-            db: root.join("catalog.db"),
-            tantivy_index: camino::Utf8PathBuf::from("idx"),
-            roots: vec![root.to_path_buf()],
-            include: vec![],
-            exclude: vec![],
-            max_file_size_mb: 200,
-            follow_symlinks: false,
-            include_hidden: false,
-            allow_offline_hydration: false,
-            commit_interval_secs: 45,
-            guard_interval_secs: 180,
-            default_language: "auto".into(),
-            extractor_cmd: String::new(),
-            embedding: crate::config::EmbeddingConfig {
-                provider: "disabled".into(),
-            },
-            mirror: MirrorConfig {
-                root: root.join("raw"),
-            },
-            bus: BusConfig {
-                bounds: BusBounds {
-                    source_fs: 16,
-                    mirror_text: 16,
-                },
-            },
-            extract: ExtractConfig {
-                pool_size: 1,
-                jobs_bound: 16,
-            },
-            retention: RetentionConfig::default(),
-        }
-    }
-
     #[test]
     fn prunes_old_rows() -> Result<()> {
         let tmp = tempdir()?;
         let root = camino::Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
-        let mut cfg = base_config(&root);
+        let mut cfg = Config::for_test(&root);
         cfg.retention.jobs_keep_per_file = 1;
         fs::create_dir_all(&cfg.mirror.root)?;
         let conn = db::open(&cfg.db)?;
@@ -206,4 +190,45 @@ mod tests {
         assert!(!dir.exists());
         Ok(())
     }
+
+    #[test]
+    fn orphan_chunks_and_embeddings_are_removed() -> Result<()> {
+        let tmp = tempdir()?;
+        let root = camino::Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
+        let cfg = Config::for_test(&root);
+        let conn = db::open(&cfg.db)?;
+        conn.execute(
+            "INSERT INTO files (id,realpath,size,mtime_ns,fast_sig,is_offline,attrs,inode_hint,status,created_ts,updated_ts) VALUES (1,'a',0,0,'',0,0,'uid1','active',?1,?1)",
+            params![now()],
+        )?;
+        for (file_id, chunk_id) in [(1, "kept"), (2, "gone")] {
+            conn.execute(
+                "INSERT INTO chunks (file_id, chunk_id, start_byte, end_byte, text) VALUES (?1, ?2, 0, 1, 'x')",
+                params![file_id, chunk_id],
+            )?;
+        }
+        for chunk_id in ["kept", "gone", "stale"] {
+            conn.execute(
+                "INSERT INTO embeddings (chunk_id, model_id, dim, vec) VALUES (?1, 'builtin', 1, x'00000000')",
+                params![chunk_id],
+            )?;
+        }
+        drop(conn);
+        run(&cfg)?;
+        let conn = db::open(&cfg.db)?;
+        let chunks: Vec<String> = conn
+            .prepare("SELECT chunk_id FROM chunks")?
+            .query_map([], |r| r.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(chunks, ["kept"]);
+        let embeddings: Vec<String> = conn
+            .prepare("SELECT chunk_id FROM embeddings")?
+            .query_map([], |r| r.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(embeddings, ["kept"]);
+        let index = ann::load(&ann::dir(&cfg), "builtin")?.unwrap();
+        assert!(index.contains("kept"));
+        assert!(!index.contains("gone"));
+        Ok(())
+    }
 }
@@ -2,38 +2,56 @@ use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc, Mutex,
 };
+use std::thread::{self, JoinHandle};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
 use rusqlite::params;
 
-use crate::bus::EventBus;
+use crate::bus::{Envelope, EventBus};
 use crate::config::Config;
-use crate::db;
 use crate::events::{FileMeta, FileMove, SourceEvent};
-use crossbeam_channel::RecvTimeoutError;
+use crate::{db, filetype};
+use crossbeam_channel::{Receiver, RecvTimeoutError};
 
 /// Run the metadata service, consuming `source.fs` events and updating the
-/// `files` table. Added and modified files trigger `ExtractionRequested` events.
+/// `files` table. Added and modified files trigger `ExtractionRequested` events
+/// and moved files a `FileMoved` event once their new path is recorded.
 pub fn run(bus: EventBus, cfg: &Config, stop: &AtomicBool) -> Result<()> {
-    let conn = Arc::new(Mutex::new(db::open(&cfg.db)?));
     let rx = bus.subscribe_source();
+    serve(rx, bus, cfg, stop)
+}
+
+/// Start the metadata service on its own thread. It subscribes before
+/// returning, so events published afterwards are not missed.
+pub fn spawn(bus: EventBus, cfg: Config, stop: Arc<AtomicBool>) -> JoinHandle<Result<()>> {
+    let rx = bus.subscribe_source();
+    thread::spawn(move || serve(rx, bus, &cfg, &stop))
+}
+
+fn serve(
+    rx: Receiver<Envelope<SourceEvent>>,
+    bus: EventBus,
+    cfg: &Config,
+    stop: &AtomicBool,
+) -> Result<()> {
+    let conn = Arc::new(Mutex::new(db::open(&cfg.db)?));
     while !stop.load(Ordering::SeqCst) {
         match rx.recv_timeout(Duration::from_millis(100)) {
-            Ok(env) => match env.data {
-                SourceEvent::SyncDelta {
+            Ok(env) => {
+                if let SourceEvent::SyncDelta {
                     added,
                     modified,
                     moved,
                     deleted,
-                } => {
+                } = env.data
+                {
                     handle_added(&bus, &conn, cfg, &added)?;
                     handle_modified(&bus, &conn, cfg, &modified)?;
-                    handle_moved(&conn, &moved)?;
+                    handle_moved(&bus, &conn, &moved)?;
                     handle_deleted(&conn, &deleted)?;
                 }
-                _ => {}
-            },
+            }
             Err(RecvTimeoutError::Timeout) => continue,
             Err(RecvTimeoutError::Disconnected) => break,
         }
@@ -52,7 +70,9 @@ fn handle_added(
         let conn = conn.lock().unwrap();
         let status = if f.is_offline { "offline" } else { "active" };
         conn.execute(
-            "INSERT OR REPLACE INTO files (realpath, size, mtime_ns, fast_sig, is_offline, attrs, inode_hint, status, created_ts, updated_ts) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?9)",
+            "INSERT INTO files (realpath, size, mtime_ns, fast_sig, is_offline, attrs, inode_hint, status, created_ts, updated_ts, mime) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?9, ?10) \
+             ON CONFLICT(realpath) DO UPDATE SET size=excluded.size, mtime_ns=excluded.mtime_ns, fast_sig=excluded.fast_sig, is_offline=excluded.is_offline, \
+             attrs=excluded.attrs, inode_hint=excluded.inode_hint, status=excluded.status, updated_ts=excluded.updated_ts, mime=excluded.mime",
             params![
                 f.path.as_str(),
                 f.size as i64,
@@ -62,7 +82,8 @@ fn handle_added(
                 f.attrs as i64,
                 f.file_uid,
                 status,
-                now_ts
+                now_ts,
+                filetype::mime(f.path.as_str())
             ],
         )?;
         db::log_op(&conn, "add", None, Some(f.path.as_str()), None)?;
@@ -86,7 +107,7 @@ fn handle_modified(
         let now_ts = now();
         let conn = conn.lock().unwrap();
         conn.execute(
-            "UPDATE files SET realpath=?2, size=?3, mtime_ns=?4, fast_sig=?5, is_offline=?6, attrs=?7, hash=NULL, status='active', updated_ts=?8 WHERE inode_hint=?1",
+            "UPDATE files SET realpath=?2, size=?3, mtime_ns=?4, fast_sig=?5, is_offline=?6, attrs=?7, hash=NULL, status='active', updated_ts=?8, mime=?9 WHERE inode_hint=?1",
             params![
                 f.file_uid,
                 f.path.as_str(),
@@ -95,7 +116,8 @@ fn handle_modified(
                 f.fast_sig,
                 f.is_offline as i64,
                 f.attrs as i64,
-                now_ts
+                now_ts,
+                filetype::mime(f.path.as_str())
             ],
         )?;
         db::log_op(&conn, "mod", Some(f.path.as_str()), None, None)?;
@@ -109,13 +131,22 @@ fn handle_modified(
     Ok(())
 }
 
-fn handle_moved(conn: &Arc<Mutex<rusqlite::Connection>>, moves: &[FileMove]) -> Result<()> {
+fn handle_moved(
+    bus: &EventBus,
+    conn: &Arc<Mutex<rusqlite::Connection>>,
+    moves: &[FileMove],
+) -> Result<()> {
     for m in moves {
         let now_ts = now();
         let conn = conn.lock().unwrap();
-        conn.execute(
-            "UPDATE files SET realpath=?2, updated_ts=?3 WHERE inode_hint=?1",
-            params![m.file_uid, m.to.as_str(), now_ts],
+        let updated = conn.execute(
+            "UPDATE files SET realpath=?2, updated_ts=?3, mime=?4 WHERE inode_hint=?1",
+            params![
+                m.file_uid,
+                m.to.as_str(),
+                now_ts,
+                filetype::mime(m.to.as_str())
+            ],
         )?;
         db::log_op(
             &conn,
@@ -124,6 +155,14 @@ fn handle_moved(conn: &Arc<Mutex<rusqlite::Connection>>, moves: &[FileMove]) ->
             Some(m.to.as_str()),
             None,
         )?;
+        drop(conn);
+        if updated > 0 {
+            bus.publish_source(SourceEvent::FileMoved {
+                file_uid: m.file_uid.clone(),
+                from: m.from.clone(),
+                to: m.to.clone(),
+            })?;
+        }
     }
     Ok(())
 }
@@ -152,7 +191,6 @@ fn now() -> i64 {
 mod tests {
     use super::*;
     use crate::bus::EventBus;
-    use crate::config::{BusBounds, BusConfig, ExtractConfig, MirrorConfig, RetentionConfig};
     use camino::Utf8PathBuf;
     use std::sync::{
         atomic::{AtomicBool, Ordering},
@@ -168,51 +206,24 @@ mod tests {
         std::fs::write(root.join("a.txt"), b"hello")?;
 
         let cfg = crate::config::Config {
-            db: root.join("catalog.db"),
-            tantivy_index: Utf8PathBuf::from("idx"),
-            roots: vec![root.clone()],
             include: vec!["**/*.txt".into()],
-            exclude: vec![],
-            max_file_size_mb: 200,
-            follow_symlinks: false,
-            include_hidden: false,
-            allow_offline_hydration: false,
-            commit_interval_secs: 45,
-            guard_interval_secs: 180,
-            default_language: "auto".into(),
-            extractor_cmd: String::new(),
-            embedding: crate::config::EmbeddingConfig {
-                provider: "disabled".into(),
-            },
-            mirror: MirrorConfig {
-                root: Utf8PathBuf::from("raw"),
-            },
-            bus: BusConfig {
-                bounds: BusBounds {
-                    source_fs: 16,
-                    mirror_text: 16,
-                },
-            },
-            extract: ExtractConfig {
-                pool_size: 1,
-                jobs_bound: 16,
-            },
-            retention: RetentionConfig::default(),
+            ..crate::config::Config::for_test(&root)
         };
 
         let conn = db::open(&cfg.db)?;
         let bus = EventBus::new(&cfg.bus.bounds, Arc::new(Mutex::new(conn)));
-        let bus_meta = bus.clone();
-        let cfg_meta = cfg.clone();
+        let events = bus.subscribe_source();
         let stop = Arc::new(AtomicBool::new(false));
-        let stop_run = stop.clone();
-        let handle = std::thread::spawn(move || {
-            run(bus_meta, &cfg_meta, &stop_run).unwrap();
-        });
+        let handle = spawn(bus.clone(), cfg.clone(), stop.clone());
+        // Block until the service has recorded a file and published `wanted`.
+        let wait_for = |wanted: fn(&SourceEvent) -> bool| -> Result<()> {
+            while !wanted(&events.recv_timeout(Duration::from_secs(5))?.data) {}
+            Ok(())
+        };
 
         let mut state = crate::fs::FsState::default();
         crate::fs::cold_scan(&cfg, &bus, &mut state)?;
-        std::thread::sleep(Duration::from_millis(200));
+        wait_for(|e| matches!(e, SourceEvent::ExtractionRequested { .. }))?;
 
         let conn = db::open(&cfg.db)?;
         let uid: String = conn.query_row(
@@ -223,7 +234,7 @@ mod tests {
 
         std::fs::rename(root.join("a.txt"), root.join("b.txt"))?;
         crate::fs::cold_scan(&cfg, &bus, &mut state)?;
-        std::thread::sleep(Duration::from_millis(200));
+        wait_for(|e| matches!(e, SourceEvent::FileMoved { .. }))?;
 
         let (uid2, path): (String, String) = conn.query_row(
             "SELECT inode_hint, realpath FROM files WHERE status='active'",
@@ -236,7 +247,7 @@ mod tests {
 
         stop.store(true, Ordering::SeqCst);
         drop(bus);
-        handle.join().unwrap();
+        handle.join().unwrap()?;
         Ok(())
     }
 }
@@ -4,22 +4,23 @@ use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc, Mutex,
 };
+use std::thread::{self, JoinHandle};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 use chrono::Utc;
-use crossbeam_channel::RecvTimeoutError;
-use rusqlite::params;
-use serde::Serialize;
-use sha2::{Digest, Sha256};
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
 
-use crate::bus::EventBus;
+use crate::bus::{Envelope, EventBus};
+use crate::catalog;
+use crate::chunk::{Chunker, TextChunk};
 use crate::config::Config;
 use crate::db;
 use crate::events::{MirrorEvent, PageBlock, SourceEvent};
-
-const TOKENS_PER_CHUNK: usize = 200;
+use crate::lang;
 
 #[derive(Serialize)]
 struct Meta<'a> {
@@ -34,17 +35,26 @@ struct Meta<'a> {
     created_ts: String,
 }
 
-#[derive(Serialize)]
-struct PageSpan {
-    page: u32,
-    start_char: usize,
-    end_char: usize,
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PageSpan {
+    pub page: u32,
+    pub start_char: usize,
+    pub end_char: usize,
 }
 
-#[derive(Serialize)]
-struct ByteSpan {
-    start: usize,
-    end: usize,
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ByteSpan {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// 1-based line and column range of a chunk within the document text.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LineSpan {
+    pub line_from: usize,
+    pub col_from: usize,
+    pub line_to: usize,
+    pub col_to: usize,
 }
 
 #[derive(Serialize)]
@@ -57,13 +67,51 @@ struct Chunk<'a> {
     text: &'a str,
     page_spans: Vec<PageSpan>,
     byte_span: ByteSpan,
+    line_span: LineSpan,
     tokens_est: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    section: Option<&'a str>,
+}
+
+/// Chunk record as read back from a mirrored `chunks.jsonl`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ChunkRecord {
+    pub chunk_id: String,
+    pub order: u64,
+    pub text: String,
+    pub page_spans: Vec<PageSpan>,
+    pub byte_span: ByteSpan,
+}
+
+/// Document metadata as read back from a mirrored `meta.json`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct MetaRecord {
+    pub file_uid: String,
+    pub content_hash: String,
+    pub page_count: usize,
+    pub lang: String,
 }
 
-/// Run the mirror builder, consuming `ExtractionCompleted` events and writing
-/// mirror artifacts under `mirror.root`.
+/// Run the mirror builder, keeping the artifacts under `mirror.root` and the
+/// catalog text in step with extractions, moves and deletions.
 pub fn run(bus: EventBus, cfg: &Config, stop: &AtomicBool) -> Result<()> {
     let rx = bus.subscribe_source();
+    serve(rx, bus, cfg, stop)
+}
+
+/// Start the mirror builder on its own thread. It subscribes before
+/// returning, so events published afterwards are not missed.
+pub fn spawn(bus: EventBus, cfg: Config, stop: Arc<AtomicBool>) -> JoinHandle<Result<()>> {
+    let rx = bus.subscribe_source();
+    thread::spawn(move || serve(rx, bus, &cfg, &stop))
+}
+
+fn serve(
+    rx: Receiver<Envelope<SourceEvent>>,
+    bus: EventBus,
+    cfg: &Config,
+    stop: &AtomicBool,
+) -> Result<()> {
     let conn = Arc::new(Mutex::new(db::open(&cfg.db)?));
     while !stop.load(Ordering::SeqCst) {
         match rx.recv_timeout(Duration::from_millis(100)) {
@@ -75,7 +123,7 @@ pub fn run(bus: EventBus, cfg: &Config, stop: &AtomicBool) -> Result<()> {
                     extractor_version,
                     pages,
                 } => {
-                    handle_extraction(
+                    if let Err(e) = handle_extraction(
                         &bus,
                         &conn,
                         cfg,
@@ -84,7 +132,19 @@ pub fn run(bus: EventBus, cfg: &Config, stop: &AtomicBool) -> Result<()> {
                         &extractor,
                         &extractor_version,
                         &pages,
-                    )?;
+                    ) {
+                        tracing::warn!(file_uid, error = %e, "failed to mirror document");
+                    }
+                }
+                SourceEvent::SyncDelta { deleted, .. } => {
+                    for f in &deleted {
+                        remove_doc(&bus, &conn, cfg, &f.file_uid)?;
+                    }
+                }
+                SourceEvent::FileMoved { file_uid, to, .. } => {
+                    if let Err(e) = move_doc(&bus, &conn, cfg, &file_uid, &to) {
+                        tracing::warn!(file_uid, error = %e, "failed to move mirrored document");
+                    }
                 }
                 _ => {}
             },
@@ -95,6 +155,130 @@ pub fn run(bus: EventBus, cfg: &Config, stop: &AtomicBool) -> Result<()> {
     Ok(())
 }
 
+/// Return the mirror directory of `file_uid`, if the document is mirrored.
+pub fn doc_dir(
+    conn: &rusqlite::Connection,
+    cfg: &Config,
+    file_uid: &str,
+) -> Result<Option<Utf8PathBuf>> {
+    let rel: Option<String> = conn
+        .query_row(
+            "SELECT path FROM mirror_docs WHERE file_uid=?1",
+            params![file_uid],
+            |r| r.get(0),
+        )
+        .optional()?;
+    Ok(rel.map(|p| cfg.mirror.root.join(p)))
+}
+
+/// Read the `meta.json` of a mirrored document.
+pub fn read_meta(dir: &Utf8Path) -> Result<MetaRecord> {
+    let content = fs::read_to_string(dir.join("meta.json"))?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Read all chunks of a mirrored document in order.
+pub fn read_chunks(dir: &Utf8Path) -> Result<Vec<ChunkRecord>> {
+    let content = fs::read_to_string(dir.join("chunks.jsonl"))?;
+    let mut chunks = Vec::new();
+    for line in content.lines().filter(|l| !l.trim().is_empty()) {
+        chunks.push(serde_json::from_str::<ChunkRecord>(line)?);
+    }
+    chunks.sort_by_key(|c| c.order);
+    Ok(chunks)
+}
+
+/// Remove the artifacts, catalog text and embeddings of a deleted file and
+/// announce it with `MirrorDocDeleted`.
+fn remove_doc(
+    bus: &EventBus,
+    conn: &Arc<Mutex<rusqlite::Connection>>,
+    cfg: &Config,
+    file_uid: &str,
+) -> Result<()> {
+    let dir = {
+        let conn = conn.lock().unwrap();
+        catalog::delete_document(&conn, file_uid)?;
+        let dir = doc_dir(&conn, cfg, file_uid)?;
+        if dir.is_some() {
+            conn.execute(
+                "DELETE FROM mirror_docs WHERE file_uid=?1",
+                params![file_uid],
+            )?;
+            conn.execute(
+                "DELETE FROM mirror_chunks WHERE file_uid=?1",
+                params![file_uid],
+            )?;
+        }
+        dir
+    };
+    if let Some(dir) = dir {
+        let _ = fs::remove_dir_all(&dir);
+        bus.publish_mirror(MirrorEvent::MirrorDocDeleted {
+            file_uid: file_uid.to_string(),
+        })?;
+    }
+    Ok(())
+}
+
+/// Move the artifacts of `file_uid` to the mirror path of `to`, where its
+/// source now lives, and announce the document again.
+fn move_doc(
+    bus: &EventBus,
+    conn: &Arc<Mutex<rusqlite::Connection>>,
+    cfg: &Config,
+    file_uid: &str,
+    to: &Utf8Path,
+) -> Result<()> {
+    let row: Option<(String, String)> = {
+        let conn = conn.lock().unwrap();
+        conn.query_row(
+            "SELECT path, content_hash FROM mirror_docs WHERE file_uid=?1",
+            params![file_uid],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .optional()?
+    };
+    // Not mirrored yet: the extraction reads the new path from the catalog.
+    let Some((old_rel, content_hash)) = row else {
+        return Ok(());
+    };
+    let rel = relativize(to, &cfg.roots);
+    if rel.as_str() != old_rel {
+        let from = cfg.mirror.root.join(&old_rel);
+        let dir = cfg.mirror.root.join(&rel);
+        if let Some(parent) = dir.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::rename(&from, &dir)?;
+        let meta_path = dir.join("meta.json");
+        let mut meta: serde_json::Value = serde_json::from_str(&fs::read_to_string(&meta_path)?)?;
+        meta["path"] = serde_json::Value::from(rel.as_str());
+        let tmp = dir.join("meta.json.tmp");
+        fs::write(&tmp, serde_json::to_vec(&meta)?)?;
+        fs::rename(&tmp, &meta_path)?;
+        let conn = conn.lock().unwrap();
+        conn.execute(
+            "UPDATE mirror_docs SET path=?2, updated_ts=?3 WHERE file_uid=?1",
+            params![file_uid, rel.as_str(), now()],
+        )?;
+    }
+    bus.publish_mirror(MirrorEvent::MirrorDocUpserted {
+        file_uid: file_uid.to_string(),
+        content_hash,
+    })?;
+    Ok(())
+}
+
+/// Mirror an extracted document and store its text, in the language detected
+/// from it or `default_language`, before publishing `MirrorDocUpserted`.
+/// Chunks it no longer contains lose their embeddings and are announced with
+/// `MirrorChunkDeleted`; a document that cannot be mirrored is reported with
+/// `MirrorDocDeleted`.
+#[allow(clippy::too_many_arguments)]
 fn handle_extraction(
     bus: &EventBus,
     conn: &Arc<Mutex<rusqlite::Connection>>,
@@ -105,19 +289,32 @@ fn handle_extraction(
     extractor_version: &str,
     pages: &[PageBlock],
 ) -> Result<()> {
-    let path_str: String = {
+    let (file_id, path_str): (i64, String) = {
         let c = conn.lock().unwrap();
         c.query_row(
-            "SELECT realpath FROM files WHERE inode_hint=?1",
+            "SELECT id, realpath FROM files WHERE inode_hint=?1",
             params![file_uid],
-            |r| r.get(0),
-        )?
-    };
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+    }
+    .inspect_err(|_| {
+        let _ = bus.publish_mirror(MirrorEvent::MirrorDocDeleted {
+            file_uid: file_uid.to_string(),
+        });
+    })?;
     let path = Utf8PathBuf::from(path_str);
     let rel = relativize(&path, &cfg.roots);
     let dir = cfg.mirror.root.join(&rel);
     fs::create_dir_all(&dir)?;
 
+    let text = pages
+        .iter()
+        .map(|p| p.text.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let lang = lang::resolve(&text, &cfg.default_language);
+    let chunks = Chunker::new(&cfg.chunking).chunk(file_id, pages);
+
     let res: Result<()> = (|| {
         write_meta(
             &dir,
@@ -127,10 +324,10 @@ fn handle_extraction(
             extractor,
             extractor_version,
             pages.len(),
-            &cfg.default_language,
+            &lang,
         )?;
 
-        {
+        let gone = {
             let conn = conn.lock().unwrap();
             let ts = now();
             conn.execute(
@@ -141,9 +338,28 @@ fn handle_extraction(
                 "DELETE FROM mirror_chunks WHERE file_uid=?1",
                 params![file_uid],
             )?;
-        }
+            let lang = (lang != "auto").then_some(lang.as_str());
+            let file_id = catalog::write_document(
+                &conn,
+                file_uid,
+                extractor,
+                extractor_version,
+                pages,
+                lang,
+            )?;
+            match file_id {
+                Some(file_id) => catalog::write_chunks(&conn, file_id, &chunks)?,
+                None => Vec::new(),
+            }
+        };
 
-        write_chunks(bus, conn, &dir, file_uid, content_hash, pages)?;
+        write_chunks(bus, conn, &dir, file_uid, content_hash, &chunks)?;
+        for chunk_id in gone {
+            bus.publish_mirror(MirrorEvent::MirrorChunkDeleted {
+                chunk_id,
+                file_uid: file_uid.to_string(),
+            })?;
+        }
         bus.publish_mirror(MirrorEvent::MirrorDocUpserted {
             file_uid: file_uid.to_string(),
             content_hash: content_hash.to_string(),
@@ -175,6 +391,7 @@ fn handle_extraction(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn write_meta(
     dir: &Utf8PathBuf,
     rel: &Utf8PathBuf,
@@ -212,9 +429,9 @@ fn write_chunks(
     dir: &Utf8PathBuf,
     file_uid: &str,
     content_hash: &str,
-    pages: &[PageBlock],
+    chunks: &[TextChunk],
 ) -> Result<()> {
-    write_chunks_impl(bus, conn, dir, file_uid, content_hash, pages, None)
+    write_chunks_impl(bus, conn, dir, file_uid, content_hash, chunks, None)
 }
 
 fn write_chunks_impl(
@@ -223,77 +440,60 @@ fn write_chunks_impl(
     dir: &Utf8PathBuf,
     file_uid: &str,
     content_hash: &str,
-    pages: &[PageBlock],
+    chunks: &[TextChunk],
     limit: Option<usize>,
 ) -> Result<()> {
     let chunks_path = dir.join("chunks.jsonl");
     let tmp = dir.join("chunks.jsonl.tmp");
     let file = File::create(&tmp)?;
     let mut writer = BufWriter::new(file);
-    let mut order = 0u64;
-    for page in pages {
-        let mut idx = 0usize;
-        let chars: Vec<char> = page.text.chars().collect();
-        while idx < chars.len() {
-            let mut end = idx;
-            let mut tokens = 0usize;
-            while end < chars.len() && tokens < TOKENS_PER_CHUNK {
-                if chars[end].is_whitespace() {
-                    while end < chars.len() && chars[end].is_whitespace() {
-                        end += 1;
-                    }
-                    tokens += 1;
-                } else {
-                    end += 1;
-                }
-            }
-            if end == idx {
-                break;
-            }
-            let text: String = chars[idx..end].iter().collect();
-            let chunk_id = make_chunk_id(file_uid, content_hash, page.page_no, idx, end, &text);
-            let chunk = Chunk {
-                v: 1,
-                chunk_id: chunk_id.clone(),
-                file_uid,
-                content_hash,
-                order,
-                text: &text,
-                page_spans: vec![PageSpan {
-                    page: page.page_no,
-                    start_char: idx,
-                    end_char: end,
-                }],
-                byte_span: ByteSpan {
-                    start: page.start + idx,
-                    end: page.start + end,
-                },
-                tokens_est: text.split_whitespace().count(),
-            };
-            serde_json::to_writer(&mut writer, &chunk)?;
-            writer.write_all(b"\n")?;
-            writer.flush()?;
-            {
-                let conn = conn.lock().unwrap();
-                conn.execute(
-                    "INSERT OR REPLACE INTO mirror_chunks (chunk_id, file_uid, ord) VALUES (?1, ?2, ?3)",
-                    params![chunk_id, file_uid, order as i64],
-                )?;
-            }
-            bus.publish_mirror(MirrorEvent::MirrorChunkUpserted {
-                chunk_id: chunk.chunk_id.clone(),
-                file_uid: file_uid.to_string(),
-                order,
-            })?;
-            order += 1;
-            if let Some(l) = limit {
-                if order as usize == l {
-                    writer.flush()?;
-                    writer.get_ref().sync_all()?;
-                    return Err(anyhow!("simulated crash"));
-                }
+    for (order, c) in (0u64..).zip(chunks) {
+        let chunk = Chunk {
+            v: 1,
+            chunk_id: c.chunk_id.clone(),
+            file_uid,
+            content_hash,
+            order,
+            text: &c.text,
+            page_spans: vec![PageSpan {
+                page: c.page_no,
+                start_char: c.start_char,
+                end_char: c.end_char,
+            }],
+            byte_span: ByteSpan {
+                start: c.start_byte,
+                end: c.end_byte,
+            },
+            line_span: LineSpan {
+                line_from: c.line_from,
+                col_from: c.col_from,
+                line_to: c.line_to,
+                col_to: c.col_to,
+            },
+            tokens_est: c.tokens,
+            section: c.section.as_deref(),
+        };
+        serde_json::to_writer(&mut writer, &chunk)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+        {
+            let conn = conn.lock().unwrap();
+            conn.execute(
+                "INSERT OR REPLACE INTO mirror_chunks (chunk_id, file_uid, ord) VALUES (?1, ?2, ?3)",
+                params![c.chunk_id, file_uid, order as i64],
+            )?;
+        }
+        bus.publish_mirror(MirrorEvent::MirrorChunkUpserted {
+            chunk_id: c.chunk_id.clone(),
+            file_uid: file_uid.to_string(),
+            order,
+        })?;
+        if let Some(l) = limit {
+            if order as usize + 1 == l {
+                writer.flush()?;
+                writer.get_ref().sync_all()?;
+                return Err(anyhow!("simulated crash"));
             }
-            idx = end;
         }
     }
     writer.flush()?;
@@ -309,33 +509,10 @@ fn write_chunks_with_limit(
     dir: &Utf8PathBuf,
     file_uid: &str,
     content_hash: &str,
-    pages: &[PageBlock],
+    chunks: &[TextChunk],
     limit: usize,
 ) -> Result<()> {
-    write_chunks_impl(bus, conn, dir, file_uid, content_hash, pages, Some(limit))
-}
-
-fn make_chunk_id(
-    file_uid: &str,
-    content_hash: &str,
-    page_no: u32,
-    start: usize,
-    end: usize,
-    text: &str,
-) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(file_uid.as_bytes());
-    hasher.update(content_hash.as_bytes());
-    hasher.update(page_no.to_be_bytes());
-    hasher.update(start.to_be_bytes());
-    hasher.update(end.to_be_bytes());
-    let normalized = text
-        .replace("\r\n", "\n")
-        .replace('\r', "\n")
-        .trim_end()
-        .to_string();
-    hasher.update(normalized.as_bytes());
-    format!("ch:{:x}", hasher.finalize())
+    write_chunks_impl(bus, conn, dir, file_uid, content_hash, chunks, Some(limit))
 }
 
 fn relativize(path: &Utf8Path, roots: &[Utf8PathBuf]) -> Utf8PathBuf {
@@ -360,7 +537,6 @@ fn now() -> i64 {
 mod tests {
     use super::*;
     use crate::bus::EventBus;
-    use crate::config::{BusBounds, BusConfig, ExtractConfig, MirrorConfig, RetentionConfig};
     use std::collections::HashSet;
     use std::fs;
     use std::sync::atomic::AtomicBool;
@@ -370,38 +546,7 @@ mod tests {
     fn writes_meta_and_chunks() -> Result<()> {
         let tmp = tempdir()?;
         let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
-        let cfg = crate::config::Config {
-            db: root.join("catalog.db"),
-            tantivy_index: Utf8PathBuf::from("idx"),
-            roots: vec![root.clone()],
-            include: vec![],
-            exclude: vec![],
-            max_file_size_mb: 200,
-            follow_symlinks: false,
-            include_hidden: false,
-            allow_offline_hydration: false,
-            commit_interval_secs: 45,
-            guard_interval_secs: 180,
-            default_language: "auto".into(),
-            extractor_cmd: String::new(),
-            embedding: crate::config::EmbeddingConfig {
-                provider: "disabled".into(),
-            },
-            mirror: MirrorConfig {
-                root: root.join("raw"),
-            },
-            bus: BusConfig {
-                bounds: BusBounds {
-                    source_fs: 8,
-                    mirror_text: 8,
-                },
-            },
-            extract: ExtractConfig {
-                pool_size: 1,
-                jobs_bound: 8,
-            },
-            retention: RetentionConfig::default(),
-        };
+        let cfg = crate::config::Config::for_test(&root);
         let conn = db::open(&cfg.db)?;
         conn.execute(
             "INSERT INTO files (realpath, size, mtime_ns, fast_sig, is_offline, attrs, inode_hint, status, created_ts, updated_ts) VALUES (?1,0,0,'sig',0,0,?2,'active',0,0)",
@@ -448,52 +593,11 @@ mod tests {
         Ok(())
     }
 
-    #[test]
-    fn chunk_id_deterministic() {
-        let a = make_chunk_id("f", "h", 1, 0, 4, "test\n");
-        let b = make_chunk_id("f", "h", 1, 0, 4, "test\r\n");
-        let c = make_chunk_id("f", "h", 1, 0, 4, "test   \r\n");
-        let d = make_chunk_id("f", "h", 1, 0, 4, "test");
-        assert_eq!(a, b);
-        assert_eq!(c, d);
-    }
-
     #[test]
     fn unicode_offsets() -> Result<()> {
         let tmp = tempdir()?;
         let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
-        let cfg = crate::config::Config {
-            db: root.join("catalog.db"),
-            tantivy_index: Utf8PathBuf::from("idx"),
-            roots: vec![root.clone()],
-            include: vec![],
-            exclude: vec![],
-            max_file_size_mb: 200,
-            follow_symlinks: false,
-            include_hidden: false,
-            allow_offline_hydration: false,
-            commit_interval_secs: 45,
-            guard_interval_secs: 180,
-            default_language: "auto".into(),
-            extractor_cmd: String::new(),
-            embedding: crate::config::EmbeddingConfig {
-                provider: "disabled".into(),
-            },
-            mirror: MirrorConfig {
-                root: root.join("raw"),
-            },
-            bus: BusConfig {
-                bounds: BusBounds {
-                    source_fs: 8,
-                    mirror_text: 8,
-                },
-            },
-            extract: ExtractConfig {
-                pool_size: 1,
-                jobs_bound: 8,
-            },
-            retention: RetentionConfig::default(),
-        };
+        let cfg = crate::config::Config::for_test(&root);
         let conn = db::open(&cfg.db)?;
         conn.execute(
             "INSERT INTO files (realpath, size, mtime_ns, fast_sig, is_offline, attrs, inode_hint, status, created_ts, updated_ts) VALUES (?1,0,0,'sig',0,0,?2,'active',0,0)",
@@ -519,7 +623,7 @@ mod tests {
         )?;
         let chunks_path = cfg.mirror.root.join("a.txt").join("chunks.jsonl");
         let line = std::fs::read_to_string(chunks_path)?;
-        let v: serde_json::Value = serde_json::from_str(&line.trim())?;
+        let v: serde_json::Value = serde_json::from_str(line.trim())?;
         let span = &v["page_spans"][0];
         assert_eq!(span["start_char"].as_u64().unwrap(), 0);
         assert_eq!(
@@ -533,38 +637,7 @@ mod tests {
     fn resume_after_partial_chunks() -> Result<()> {
         let tmp = tempdir()?;
         let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
-        let cfg = crate::config::Config {
-            db: root.join("catalog.db"),
-            tantivy_index: Utf8PathBuf::from("idx"),
-            roots: vec![root.clone()],
-            include: vec![],
-            exclude: vec![],
-            max_file_size_mb: 200,
-            follow_symlinks: false,
-            include_hidden: false,
-            allow_offline_hydration: false,
-            commit_interval_secs: 45,
-            guard_interval_secs: 180,
-            default_language: "auto".into(),
-            extractor_cmd: String::new(),
-            embedding: crate::config::EmbeddingConfig {
-                provider: "disabled".into(),
-            },
-            mirror: MirrorConfig {
-                root: root.join("raw"),
-            },
-            bus: BusConfig {
-                bounds: BusBounds {
-                    source_fs: 8,
-                    mirror_text: 8,
-                },
-            },
-            extract: ExtractConfig {
-                pool_size: 1,
-                jobs_bound: 8,
-            },
-            retention: RetentionConfig::default(),
-        };
+        let cfg = crate::config::Config::for_test(&root);
         let conn = db::open(&cfg.db)?;
         conn.execute(
             "INSERT INTO files (realpath, size, mtime_ns, fast_sig, is_offline, attrs, inode_hint, status, created_ts, updated_ts) VALUES (?1,0,0,'sig',0,0,?2,'active',0,0)",
@@ -593,7 +666,8 @@ mod tests {
             )?;
         }
         // simulate crash after 3 chunks
-        let _ = write_chunks_with_limit(&bus, &conn_arc, &dir, "f1", "h1", &pages, 3);
+        let chunks = Chunker::new(&cfg.chunking).chunk(1, &pages);
+        let _ = write_chunks_with_limit(&bus, &conn_arc, &dir, "f1", "h1", &chunks, 3);
 
         // restart and run fully
         handle_extraction(&bus, &conn_arc, &cfg, "f1", "h1", "builtin", "", &pages)?;
@@ -615,4 +689,112 @@ mod tests {
         assert_eq!(ids.len(), 5);
         Ok(())
     }
+
+    #[test]
+    fn chunk_ids_survive_atomic_saves() -> Result<()> {
+        let tmp = tempdir()?;
+        let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
+        let cfg = crate::config::Config {
+            chunking: crate::config::ChunkingConfig {
+                max_tokens: 3,
+                ..Default::default()
+            },
+            ..crate::config::Config::for_test(&root)
+        };
+        assert_eq!(
+            cfg.chunking.strategy,
+            crate::config::ChunkStrategy::FixedTokens
+        );
+        let conn = db::open(&cfg.db)?;
+        let path = root.join("a.txt");
+        let upsert = "INSERT INTO files (realpath, size, mtime_ns, fast_sig, is_offline, attrs, inode_hint, status, created_ts, updated_ts) VALUES (?1,0,0,'sig',0,0,?2,'active',0,0) \
+             ON CONFLICT(realpath) DO UPDATE SET inode_hint=excluded.inode_hint";
+        conn.execute(upsert, params![path.as_str(), "ux-1:1"])?;
+        let conn_arc = Arc::new(Mutex::new(conn));
+        let bus = EventBus::new(&cfg.bus.bounds, conn_arc.clone());
+        let dir = cfg.mirror.root.join("a.txt");
+        let extract = |file_uid: &str, text: &str| -> Result<Vec<String>> {
+            let page = PageBlock {
+                page_no: 1,
+                text: text.into(),
+                start: 0,
+                end: text.chars().count(),
+            };
+            handle_extraction(&bus, &conn_arc, &cfg, file_uid, "h", "builtin", "", &[page])?;
+            Ok(read_chunks(&dir)?.into_iter().map(|c| c.chunk_id).collect())
+        };
+
+        let before = extract("ux-1:1", "one two three four five six")?;
+        // An editor saving through a temporary file leaves a new inode at the
+        // same path, which the catalog records on the existing row.
+        conn_arc
+            .lock()
+            .unwrap()
+            .execute(upsert, params![path.as_str(), "ux-1:2"])?;
+        let after = extract("ux-1:2", "one two three four five six seven")?;
+        assert_eq!(before.len(), 2);
+        assert_eq!(after.len(), 3);
+        assert_eq!(&after[..2], &before[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn removed_chunks_lose_their_embeddings() -> Result<()> {
+        let tmp = tempdir()?;
+        let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
+        let cfg = crate::config::Config {
+            chunking: crate::config::ChunkingConfig {
+                max_tokens: 2,
+                ..Default::default()
+            },
+            ..crate::config::Config::for_test(&root)
+        };
+        let conn = db::open(&cfg.db)?;
+        conn.execute(
+            "INSERT INTO files (realpath, size, mtime_ns, fast_sig, is_offline, attrs, inode_hint, status, created_ts, updated_ts) VALUES (?1,0,0,'sig',0,0,?2,'active',0,0)",
+            params![root.join("a.txt").as_str(), "f1"],
+        )?;
+        let conn_arc = Arc::new(Mutex::new(conn));
+        let bus = EventBus::new(&cfg.bus.bounds, conn_arc.clone());
+        let dir = cfg.mirror.root.join("a.txt");
+        let extract = |text: &str| -> Result<Vec<String>> {
+            let page = PageBlock {
+                page_no: 1,
+                text: text.into(),
+                start: 0,
+                end: text.chars().count(),
+            };
+            handle_extraction(&bus, &conn_arc, &cfg, "f1", "h", "builtin", "", &[page])?;
+            Ok(read_chunks(&dir)?.into_iter().map(|c| c.chunk_id).collect())
+        };
+        let embedded = || -> Result<Vec<String>> {
+            let conn = conn_arc.lock().unwrap();
+            let mut stmt = conn.prepare("SELECT chunk_id FROM embeddings ORDER BY chunk_id")?;
+            let ids = stmt.query_map([], |r| r.get(0))?;
+            Ok(ids.collect::<rusqlite::Result<_>>()?)
+        };
+
+        let ids = extract("alpha beta gamma delta")?;
+        for id in &ids {
+            conn_arc.lock().unwrap().execute(
+                "INSERT INTO embeddings (chunk_id, model_id, dim, vec) VALUES (?1, 'm', 1, x'00000000')",
+                params![id],
+            )?;
+        }
+        let rx = bus.subscribe_mirror();
+        assert_eq!(extract("alpha beta")?, ids[..1]);
+        assert_eq!(embedded()?, ids[..1]);
+        let deleted: Vec<String> = rx
+            .try_iter()
+            .filter_map(|env| match env.data {
+                MirrorEvent::MirrorChunkDeleted { chunk_id, .. } => Some(chunk_id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(deleted, ids[1..]);
+
+        remove_doc(&bus, &conn_arc, &cfg, "f1")?;
+        assert!(embedded()?.is_empty());
+        Ok(())
+    }
 }
@@ -87,52 +87,16 @@ fn relativize(path: &Utf8Path, roots: &[Utf8PathBuf]) -> Utf8PathBuf {
 mod tests {
     use super::*;
     use crate::bus::EventBus;
-    use crate::config::{BusBounds, BusConfig, ExtractConfig, MirrorConfig, RetentionConfig};
     use crossbeam_channel::RecvTimeoutError;
     use std::sync::{Arc, Mutex};
     use std::time::Duration;
     use tempfile::tempdir;
 
-    fn base_config(root: &Utf8Path) -> crate::config::Config {
-        crate::config::Config {
-            db: root.join("catalog.db"),
-            tantivy_index: Utf8PathBuf::from("idx"),
-            roots: vec![root.to_path_buf()],
-            include: vec![],
-            exclude: vec![],
-            max_file_size_mb: 200,
-            follow_symlinks: false,
-            include_hidden: false,
-            allow_offline_hydration: false,
-            commit_interval_secs: 45,
-            guard_interval_secs: 180,
-            default_language: "auto".into(),
-            extractor_cmd: String::new(),
-            embedding: crate::config::EmbeddingConfig {
-                provider: "disabled".into(),
-            },
-            mirror: MirrorConfig {
-                root: root.join("raw"),
-            },
-            bus: BusConfig {
-                bounds: BusBounds {
-                    source_fs: 16,
-                    mirror_text: 16,
-                },
-            },
-            extract: ExtractConfig {
-                pool_size: 1,
-                jobs_bound: 16,
-            },
-            retention: RetentionConfig::default(),
-        }
-    }
-
     #[test]
     fn missing_mirror_triggers_extraction() -> Result<()> {
         let tmp = tempdir()?;
         let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
-        let cfg = base_config(&root);
+        let cfg = crate::config::Config::for_test(&root);
 
         let conn = db::open(&cfg.db)?;
         conn.execute(
@@ -160,7 +124,7 @@ mod tests {
     fn removes_orphan_mirror() -> Result<()> {
         let tmp = tempdir()?;
         let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
-        let cfg = base_config(&root);
+        let cfg = crate::config::Config::for_test(&root);
 
         let dir = cfg.mirror.root.join("b.txt");
         fs::create_dir_all(&dir)?;
@@ -0,0 +1,320 @@
+//! Restrictions on the files a search may return, applied by Tantivy for
+//! keyword search and to the candidates of vector search.
+
+use std::collections::HashSet;
+use std::ops::Bound;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use rusqlite::Connection;
+use tantivy::query::{BooleanQuery, Occur, Query, RangeQuery, RegexQuery, TermQuery};
+use tantivy::schema::{Field, IndexRecordOption};
+use tantivy::Term;
+
+use crate::filetype;
+use crate::index::FilterFields;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Filters {
+    /// Path prefixes; a file must start with one of them.
+    pub paths: Vec<String>,
+    /// Extensions, without the dot; a file must have one of them.
+    pub exts: Vec<String>,
+    /// MIME types, or `type/*` for every subtype of `type`.
+    pub mimes: Vec<String>,
+    /// Language codes of the detected document language.
+    pub langs: Vec<String>,
+    /// Files modified at or after this time.
+    pub modified_after: Option<DateTime<Utc>>,
+    /// Files modified before this time.
+    pub modified_before: Option<DateTime<Utc>>,
+    /// Smallest file size in bytes.
+    pub min_size: Option<u64>,
+    /// Largest file size in bytes.
+    pub max_size: Option<u64>,
+}
+
+/// Catalog attributes of a file the filters look at.
+struct FileAttrs<'a> {
+    path: &'a str,
+    mime: &'a str,
+    lang: &'a str,
+    mtime_ns: i64,
+    size: i64,
+}
+
+impl Filters {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// `query` restricted to the files passing the filters.
+    pub fn restrict(&self, query: Box<dyn Query>, fields: FilterFields) -> Result<Box<dyn Query>> {
+        if self.is_empty() {
+            return Ok(query);
+        }
+        let mut clauses = vec![(Occur::Must, query)];
+        let any = |queries: Vec<Box<dyn Query>>| -> Box<dyn Query> {
+            let should = queries.into_iter().map(|q| (Occur::Should, q)).collect();
+            Box::new(BooleanQuery::new(should))
+        };
+        if !self.paths.is_empty() {
+            let prefixes = self
+                .paths
+                .iter()
+                .map(|p| prefix_query(p, fields.path))
+                .collect::<Result<_>>()?;
+            clauses.push((Occur::Must, any(prefixes)));
+        }
+        if !self.exts.is_empty() {
+            let exts = self
+                .exts
+                .iter()
+                .map(|e| term_query(fields.ext, &normalize_ext(e)))
+                .collect();
+            clauses.push((Occur::Must, any(exts)));
+        }
+        if !self.mimes.is_empty() {
+            let mimes = self
+                .mimes
+                .iter()
+                .map(|m| match m.strip_suffix('*') {
+                    Some(prefix) => prefix_query(prefix, fields.mime),
+                    None => Ok(term_query(fields.mime, m)),
+                })
+                .collect::<Result<_>>()?;
+            clauses.push((Occur::Must, any(mimes)));
+        }
+        if !self.langs.is_empty() {
+            let langs = self
+                .langs
+                .iter()
+                .map(|l| term_query(fields.lang, &l.to_lowercase()))
+                .collect();
+            clauses.push((Occur::Must, any(langs)));
+        }
+        let (after, before) = self.mtime_range();
+        if after.is_some() || before.is_some() {
+            clauses.push((Occur::Must, range_query(fields.mtime_ns, after, before)));
+        }
+        if self.min_size.is_some() || self.max_size.is_some() {
+            let min = self.min_size.map(clamp_i64);
+            let max = self.max_size.map(|s| clamp_i64(s).saturating_add(1));
+            clauses.push((Occur::Must, range_query(fields.size, min, max)));
+        }
+        Ok(Box::new(BooleanQuery::new(clauses)))
+    }
+
+    /// Ids of the active files passing the filters, or `None` when no filter
+    /// is set.
+    pub fn matching_files(&self, conn: &Connection) -> Result<Option<HashSet<i64>>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let mut stmt = conn.prepare(
+            "SELECT f.id, f.realpath, IFNULL(f.mime, ''), IFNULL(d.lang, ''), f.mtime_ns, f.size \
+             FROM files f LEFT JOIN documents d ON d.file_id=f.id WHERE f.status='active'",
+        )?;
+        let mut rows = stmt.query([])?;
+        let mut ids = HashSet::new();
+        while let Some(row) = rows.next()? {
+            let path: String = row.get(1)?;
+            let mime: String = row.get(2)?;
+            let lang: String = row.get(3)?;
+            let attrs = FileAttrs {
+                path: &path,
+                mime: &mime,
+                lang: &lang,
+                mtime_ns: row.get(4)?,
+                size: row.get(5)?,
+            };
+            if self.matches(&attrs) {
+                ids.insert(row.get(0)?);
+            }
+        }
+        Ok(Some(ids))
+    }
+
+    /// Whether a file passes the filters, with the semantics of [`restrict`].
+    ///
+    /// [`restrict`]: Self::restrict
+    fn matches(&self, file: &FileAttrs) -> bool {
+        let mime = match file.mime {
+            "" => filetype::mime(file.path).unwrap_or_default(),
+            mime => mime,
+        };
+        let ext = filetype::extension(file.path);
+        let (after, before) = self.mtime_range();
+        (self.paths.is_empty() || self.paths.iter().any(|p| file.path.starts_with(p.as_str())))
+            && (self.exts.is_empty()
+                || self
+                    .exts
+                    .iter()
+                    .any(|e| ext.as_deref() == Some(normalize_ext(e).as_str())))
+            && (self.mimes.is_empty()
+                || self.mimes.iter().any(|m| match m.strip_suffix('*') {
+                    Some(prefix) => mime.starts_with(prefix),
+                    None => mime == m,
+                }))
+            && (self.langs.is_empty()
+                || self.langs.iter().any(|l| l.eq_ignore_ascii_case(file.lang)))
+            && after.is_none_or(|t| file.mtime_ns >= t)
+            && before.is_none_or(|t| file.mtime_ns < t)
+            && self.min_size.is_none_or(|s| file.size >= clamp_i64(s))
+            && self.max_size.is_none_or(|s| file.size <= clamp_i64(s))
+    }
+
+    /// Modification time bounds in nanoseconds, inclusive then exclusive.
+    fn mtime_range(&self) -> (Option<i64>, Option<i64>) {
+        let ns = |t: &DateTime<Utc>| t.timestamp_nanos_opt().unwrap_or(i64::MAX);
+        (
+            self.modified_after.as_ref().map(ns),
+            self.modified_before.as_ref().map(ns),
+        )
+    }
+}
+
+/// Parse a time given as an RFC 3339 timestamp or a `YYYY-MM-DD` date, the
+/// latter meaning midnight UTC.
+pub fn parse_time(s: &str) -> Result<DateTime<Utc>> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map(|d| d.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        .map_err(|_| anyhow!("invalid time `{s}`; expected YYYY-MM-DD or an RFC 3339 timestamp"))
+}
+
+fn normalize_ext(ext: &str) -> String {
+    ext.trim_start_matches('.').to_lowercase()
+}
+
+fn clamp_i64(v: u64) -> i64 {
+    i64::try_from(v).unwrap_or(i64::MAX)
+}
+
+fn term_query(field: Field, text: &str) -> Box<dyn Query> {
+    Box::new(TermQuery::new(
+        Term::from_field_text(field, text),
+        IndexRecordOption::Basic,
+    ))
+}
+
+/// Terms of `field` starting with `prefix`.
+fn prefix_query(prefix: &str, field: Field) -> Result<Box<dyn Query>> {
+    let mut pattern = String::with_capacity(prefix.len() + 2);
+    for c in prefix.chars() {
+        if "\\.+*?()|[]{}^$#&-~".contains(c) {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push_str(".*");
+    Ok(Box::new(RegexQuery::from_pattern(&pattern, field)?))
+}
+
+/// Values of `field` from `min` included to `max` excluded.
+fn range_query(field: Field, min: Option<i64>, max: Option<i64>) -> Box<dyn Query> {
+    let bound = |v: Option<i64>, bound: fn(Term) -> Bound<Term>| match v {
+        Some(v) => bound(Term::from_field_i64(field, v)),
+        None => Bound::Unbounded,
+    };
+    Box::new(RangeQuery::new(
+        bound(min, Bound::Included),
+        bound(max, Bound::Excluded),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use rusqlite::params;
+
+    #[test]
+    fn parses_dates_and_timestamps() -> Result<()> {
+        assert_eq!(
+            parse_time("2024-05-01")?.to_rfc3339(),
+            "2024-05-01T00:00:00+00:00"
+        );
+        assert_eq!(
+            parse_time("2024-05-01T12:00:00+02:00")?.to_rfc3339(),
+            "2024-05-01T10:00:00+00:00"
+        );
+        assert!(parse_time("May 1st").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn matching_files_applies_every_filter() -> Result<()> {
+        let conn = db::open(camino::Utf8Path::new(":memory:"))?;
+        let day = |d: &str| parse_time(d).unwrap().timestamp_nanos_opt().unwrap();
+        for (id, path, mime, size, mtime, lang) in [
+            (1, "/docs/a.md", None, 10, day("2024-01-10"), "en"),
+            (
+                2,
+                "/docs/sub/B.PDF",
+                Some("application/pdf"),
+                5000,
+                day("2024-06-01"),
+                "fr",
+            ),
+            (3, "/other/c.txt", None, 100, day("2023-03-01"), ""),
+        ] {
+            conn.execute(
+                "INSERT INTO files (id, realpath, mime, size, mtime_ns, status, created_ts, updated_ts) VALUES (?1,?2,?3,?4,?5,'active',0,0)",
+                params![id, path, mime, size, mtime],
+            )?;
+            conn.execute(
+                "INSERT INTO documents (file_id, extractor, extractor_version, lang, page_count, content_md, content_txt, ocr_applied, updated_ts) VALUES (?1,'doc','v',?2,1,'','',0,0)",
+                params![id, lang],
+            )?;
+        }
+        let ids = |filters: Filters| -> Result<Vec<i64>> {
+            let mut ids: Vec<_> = filters
+                .matching_files(&conn)?
+                .unwrap()
+                .into_iter()
+                .collect();
+            ids.sort();
+            Ok(ids)
+        };
+
+        assert!(Filters::default().matching_files(&conn)?.is_none());
+        let path = |p: &str| Filters {
+            paths: vec![p.into()],
+            ..Filters::default()
+        };
+        assert_eq!(ids(path("/docs/"))?, [1, 2]);
+        assert_eq!(ids(path("/doc"))?, [1, 2]);
+        let exts = Filters {
+            exts: vec![".pdf".into(), "TXT".into()],
+            ..Filters::default()
+        };
+        assert_eq!(ids(exts)?, [2, 3]);
+        // Files cataloged without a MIME type get the one of their extension.
+        let mimes = Filters {
+            mimes: vec!["text/*".into()],
+            ..Filters::default()
+        };
+        assert_eq!(ids(mimes)?, [1, 3]);
+        let langs = Filters {
+            langs: vec!["FR".into()],
+            ..Filters::default()
+        };
+        assert_eq!(ids(langs)?, [2]);
+        let time = Filters {
+            modified_after: Some(parse_time("2024-01-10")?),
+            modified_before: Some(parse_time("2024-06-01")?),
+            ..Filters::default()
+        };
+        assert_eq!(ids(time)?, [1]);
+        let size = Filters {
+            min_size: Some(10),
+            max_size: Some(100),
+            ..Filters::default()
+        };
+        assert_eq!(ids(size)?, [1, 3]);
+        Ok(())
+    }
+}
@@ -1,18 +1,24 @@
 //! Query the Tantivy index for keyword search.
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use chrono::{DateTime, TimeZone, Utc};
 use serde::Serialize;
 use tantivy::collector::TopDocs;
-use tantivy::query::QueryParser;
-use tantivy::schema::Value;
-use tantivy::{Index, TantivyDocument};
+use tantivy::query::{BooleanQuery, Occur, Query, QueryParser, TermQuery};
+use tantivy::schema::{Field, IndexRecordOption, Value};
+use tantivy::snippet::SnippetGenerator;
+use tantivy::{Index, Searcher, TantivyDocument, Term};
 
-use crate::config::Config;
+use crate::config::{Config, Quantization};
+use crate::embed::quant;
 use crate::index::{self, ChunkFields, IndexFields};
-use crate::{db, embed};
-use std::collections::HashMap;
-use std::convert::TryInto;
+use crate::{ann, db, embed};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{HashMap, HashSet};
+
+pub mod filter;
+
+pub use filter::Filters;
 
 #[derive(Serialize)]
 pub struct SearchHit {
@@ -20,6 +26,11 @@ pub struct SearchHit {
     pub score: f32,
     pub file_id: i64,
     pub mtime: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+    /// Best-matching chunks of the file.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<ChunkSearchHit>,
 }
 
 #[derive(Serialize)]
@@ -34,6 +45,41 @@ pub struct ChunkSearchHit {
     pub chunk_id: String,
     pub start_byte: i64,
     pub end_byte: i64,
+    #[serde(flatten)]
+    pub location: Location,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+    /// Rank and score among the keyword candidates of a hybrid search.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyword: Option<RetrieverHit>,
+    /// Rank and score among the semantic candidates of a hybrid search.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub semantic: Option<RetrieverHit>,
+}
+
+/// Where one retriever placed a hit: its 1-based rank and its own score.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct RetrieverHit {
+    pub rank: usize,
+    pub score: f32,
+}
+
+/// Where a chunk sits in its source document: the page range for paged
+/// formats and the 1-based line and column range for plaintext files.
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Location {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_from: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_to: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_from: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub col_from: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_to: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub col_to: Option<i64>,
 }
 
 #[derive(Serialize)]
@@ -41,19 +87,107 @@ pub struct ChunkSearchResults {
     pub results: Vec<ChunkSearchHit>,
 }
 
+/// Options shared by all search modes.
+#[derive(Clone, Debug, Default)]
+pub struct SearchOptions {
+    /// Attach to each hit a snippet of at most this many characters with the
+    /// matched terms wrapped in `<b>` tags.
+    pub snippet_chars: Option<usize>,
+    /// Embedding model whose vectors semantic search uses, instead of the
+    /// active one.
+    pub model: Option<String>,
+    /// Score every stored vector instead of querying the ANN index.
+    pub exact: bool,
+    /// How hybrid search merges keyword and semantic candidates.
+    pub fusion: FusionOptions,
+    /// How chunk hits are grouped into file results.
+    pub aggregate: AggregateOptions,
+    /// Files results are restricted to.
+    pub filters: Filters,
+}
+
+/// Method merging the candidate lists of hybrid search.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Fusion {
+    /// Reciprocal rank fusion: each list adds `weight / (rrf_k + rank)`.
+    #[default]
+    Rrf,
+    /// Each list adds `weight` times its score min-max normalized to [0, 1].
+    Linear,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FusionOptions {
+    pub method: Fusion,
+    /// Keyword candidates fetched before fusion, at least the requested K.
+    pub keyword_depth: usize,
+    /// Semantic candidates fetched before fusion, at least the requested K.
+    pub semantic_depth: usize,
+    pub rrf_k: f32,
+    pub keyword_weight: f32,
+    pub semantic_weight: f32,
+}
+
+impl Default for FusionOptions {
+    fn default() -> Self {
+        Self {
+            method: Fusion::Rrf,
+            keyword_depth: 100,
+            semantic_depth: 100,
+            rrf_k: 60.0,
+            keyword_weight: 1.0,
+            semantic_weight: 1.0,
+        }
+    }
+}
+
+/// How the scores of a file's chunks make up the score of the file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Aggregation {
+    /// Score of the best chunk.
+    #[default]
+    Max,
+    /// Sum of the scores of the best N chunks.
+    TopSum(usize),
+    /// Mean score of the file's candidate chunks.
+    Mean,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AggregateOptions {
+    pub method: Aggregation,
+    /// Chunk candidates grouped into files, at least the requested K.
+    pub chunk_depth: usize,
+    /// Chunks listed with each file.
+    pub chunks_per_file: usize,
+}
+
+impl Default for AggregateOptions {
+    fn default() -> Self {
+        Self {
+            method: Aggregation::Max,
+            chunk_depth: 200,
+            chunks_per_file: 3,
+        }
+    }
+}
+
 /// Execute a keyword query against the index and return the top K results.
-pub fn keyword(cfg: &Config, query: &str, top_k: usize) -> Result<SearchResults> {
+pub fn keyword(
+    cfg: &Config,
+    query: &str,
+    top_k: usize,
+    opts: &SearchOptions,
+) -> Result<SearchResults> {
     let index = Index::open_in_dir(cfg.tantivy_index.as_std_path())?;
-    index::register_tokenizers(&index);
+    index::register_tokenizers(&index, &cfg.index)?;
     let schema = index.schema();
-    let fields = IndexFields::from_schema(&schema);
+    let fields = IndexFields::from_schema(&schema, &cfg.index.languages)?;
     let reader = index.reader()?;
     let searcher = reader.searcher();
-    let mut parser = QueryParser::for_index(&index, vec![fields.body_en, fields.body_fr]);
-    parser.set_field_boost(fields.body_en, 1.0);
-    parser.set_field_boost(fields.body_fr, 1.0);
-    let q = parser.parse_query(query)?;
-    let top_docs = searcher.search(&q, &TopDocs::with_limit(top_k))?;
+    let q = query_parser(&index, &fields.body).parse_query(query)?;
+    let filtered = opts.filters.restrict(q.box_clone(), fields.filter())?;
+    let top_docs = searcher.search(&filtered, &TopDocs::with_limit(top_k))?;
     let mut hits = Vec::new();
     for (score, addr) in top_docs {
         let retrieved: TantivyDocument = searcher.doc(addr)?;
@@ -70,151 +204,757 @@ pub fn keyword(cfg: &Config, query: &str, top_k: usize) -> Result<SearchResults>
             .get_first(fields.mtime_ns)
             .and_then(|v| v.as_i64())
             .unwrap_or_default();
-        let secs = mtime_ns / 1_000_000_000;
-        let nanos = (mtime_ns % 1_000_000_000) as u32;
-        let mtime = Utc
-            .timestamp_opt(secs, nanos)
-            .single()
-            .unwrap_or_else(|| Utc.timestamp_opt(0, 0).single().unwrap());
         hits.push(SearchHit {
             path,
             score,
             file_id,
-            mtime,
+            mtime: mtime(mtime_ns),
+            snippet: None,
+            chunks: Vec::new(),
         });
     }
+    if let Some(max_chars) = opts.snippet_chars {
+        let highlighter = Highlighter::new(&searcher, q.as_ref(), &fields.body, max_chars)?;
+        let conn = db::open(&cfg.db)?;
+        let mut stmt = conn.prepare(
+            "SELECT IFNULL(content_txt, ''), IFNULL(lang, '') FROM documents WHERE file_id=?1",
+        )?;
+        for hit in &mut hits {
+            let row: Option<(String, String)> = stmt
+                .query_row(params![hit.file_id], |r| Ok((r.get(0)?, r.get(1)?)))
+                .optional()?;
+            if let Some((text, lang)) = row {
+                hit.snippet = Some(highlighter.snippet(&text, &lang));
+            }
+        }
+    }
+    keyword_file_chunks(cfg, query, opts, &mut hits)?;
     Ok(SearchResults { results: hits })
 }
 
+fn mtime(mtime_ns: i64) -> DateTime<Utc> {
+    let secs = mtime_ns / 1_000_000_000;
+    let nanos = (mtime_ns % 1_000_000_000) as u32;
+    Utc.timestamp_opt(secs, nanos)
+        .single()
+        .unwrap_or_else(|| Utc.timestamp_opt(0, 0).single().unwrap())
+}
+
 /// Execute a keyword query against the chunk index and return the top K results.
-pub fn keyword_chunks(cfg: &Config, query: &str, top_k: usize) -> Result<ChunkSearchResults> {
-    let index_dir = cfg.tantivy_index.join("chunks");
-    let index = Index::open_in_dir(index_dir.as_std_path())?;
-    index::register_tokenizers(&index);
-    let schema = index.schema();
-    let fields = ChunkFields::from_schema(&schema);
+pub fn keyword_chunks(
+    cfg: &Config,
+    query: &str,
+    top_k: usize,
+    opts: &SearchOptions,
+) -> Result<ChunkSearchResults> {
+    let mut hits = keyword_chunk_hits(cfg, query, top_k, &opts.filters)?;
+    chunk_snippets(cfg, query, opts, &mut hits)?;
+    Ok(ChunkSearchResults { results: hits })
+}
+
+fn keyword_chunk_hits(
+    cfg: &Config,
+    query: &str,
+    top_k: usize,
+    filters: &Filters,
+) -> Result<Vec<ChunkSearchHit>> {
+    let (index, fields) = open_chunk_index(cfg)?;
     let reader = index.reader()?;
     let searcher = reader.searcher();
-    let mut parser =
-        QueryParser::for_index(&index, vec![fields.chunk_text_en, fields.chunk_text_fr]);
-    parser.set_field_boost(fields.chunk_text_en, 1.0);
-    parser.set_field_boost(fields.chunk_text_fr, 1.0);
-    let q = parser.parse_query(query)?;
+    let q = query_parser(&index, &fields.chunk_text).parse_query(query)?;
+    let q = filters.restrict(q, fields.filter())?;
     let top_docs = searcher.search(&q, &TopDocs::with_limit(top_k))?;
     let mut hits = Vec::new();
     for (score, addr) in top_docs {
-        let retrieved: TantivyDocument = searcher.doc(addr)?;
-        let path = retrieved
-            .get_first(fields.path)
-            .and_then(|v| v.as_str())
-            .unwrap_or_default()
-            .to_string();
-        let chunk_id = retrieved
-            .get_first(fields.chunk_id)
+        hits.push(chunk_hit(&searcher.doc(addr)?, &fields, score));
+    }
+    locate(&db::open(&cfg.db)?, &mut hits)?;
+    Ok(hits)
+}
+
+/// List with each keyword file hit its best-matching chunks in the chunk
+/// index, so file results carry chunks whatever the search mode.
+fn keyword_file_chunks(
+    cfg: &Config,
+    query: &str,
+    opts: &SearchOptions,
+    files: &mut [SearchHit],
+) -> Result<()> {
+    let limit = opts.aggregate.chunks_per_file;
+    if limit == 0 || files.is_empty() {
+        return Ok(());
+    }
+    let (index, fields) = open_chunk_index(cfg)?;
+    let searcher = index.reader()?.searcher();
+    let q = query_parser(&index, &fields.chunk_text).parse_query(query)?;
+    let highlighter = opts
+        .snippet_chars
+        .map(|max_chars| Highlighter::new(&searcher, q.as_ref(), &fields.chunk_text, max_chars))
+        .transpose()?;
+    let conn = db::open(&cfg.db)?;
+    for file in files {
+        let path = Term::from_field_text(fields.path, &file.path);
+        let in_file = BooleanQuery::new(vec![
+            (Occur::Must, q.box_clone()),
+            (
+                Occur::Must,
+                Box::new(TermQuery::new(path, IndexRecordOption::Basic)),
+            ),
+        ]);
+        let mut chunks = Vec::new();
+        for (score, addr) in searcher.search(&in_file, &TopDocs::with_limit(limit))? {
+            chunks.push(chunk_hit(&searcher.doc(addr)?, &fields, score));
+        }
+        locate(&conn, &mut chunks)?;
+        if let Some(highlighter) = &highlighter {
+            attach_snippets(&conn, highlighter, &mut chunks)?;
+        }
+        file.chunks = chunks;
+    }
+    Ok(())
+}
+
+/// Build a chunk hit from a document of the chunk index.
+fn chunk_hit(doc: &TantivyDocument, fields: &ChunkFields, score: f32) -> ChunkSearchHit {
+    let text = |field| {
+        doc.get_first(field)
             .and_then(|v| v.as_str())
             .unwrap_or_default()
-            .to_string();
-        let start_byte = retrieved
-            .get_first(fields.start_byte)
+            .to_string()
+    };
+    let int = |field| {
+        doc.get_first(field)
             .and_then(|v| v.as_i64())
-            .unwrap_or_default();
-        let end_byte = retrieved
-            .get_first(fields.end_byte)
-            .and_then(|v| v.as_i64())
-            .unwrap_or_default();
-        hits.push(ChunkSearchHit {
-            path,
-            score,
-            chunk_id,
-            start_byte,
-            end_byte,
-        });
+            .unwrap_or_default()
+    };
+    ChunkSearchHit {
+        path: text(fields.path),
+        score,
+        chunk_id: text(fields.chunk_id),
+        start_byte: int(fields.start_byte),
+        end_byte: int(fields.end_byte),
+        location: Location::default(),
+        snippet: None,
+        keyword: None,
+        semantic: None,
     }
-    Ok(ChunkSearchResults { results: hits })
+}
+
+/// Execute a semantic query and return the top K files, scored from their
+/// best-matching chunks.
+pub fn semantic(
+    cfg: &Config,
+    query: &str,
+    top_k: usize,
+    opts: &SearchOptions,
+) -> Result<SearchResults> {
+    let depth = opts.aggregate.chunk_depth.max(top_k);
+    let hits = semantic_chunk_hits(cfg, query, depth, opts)?;
+    files_from_chunks(cfg, query, hits, top_k, opts)
 }
 
 /// Execute a semantic query using embeddings over chunks.
-pub fn semantic_chunks(cfg: &Config, query: &str, top_k: usize) -> Result<ChunkSearchResults> {
+pub fn semantic_chunks(
+    cfg: &Config,
+    query: &str,
+    top_k: usize,
+    opts: &SearchOptions,
+) -> Result<ChunkSearchResults> {
+    let mut hits = semantic_chunk_hits(cfg, query, top_k, opts)?;
+    chunk_snippets(cfg, query, opts, &mut hits)?;
+    Ok(ChunkSearchResults { results: hits })
+}
+
+fn semantic_chunk_hits(
+    cfg: &Config,
+    query: &str,
+    top_k: usize,
+    opts: &SearchOptions,
+) -> Result<Vec<ChunkSearchHit>> {
     let conn = db::open(&cfg.db)?;
-    let q_vec = embed::embed_text(query)?;
-    let mut stmt = conn.prepare(
-        "SELECT e.chunk_id, e.vec, e.dim, f.realpath, c.start_byte, c.end_byte \
-         FROM embeddings e JOIN chunks c ON e.chunk_id=c.chunk_id \
-         JOIN files f ON f.id=c.file_id WHERE f.status='active' AND e.model_id='builtin'",
+    let model = match &opts.model {
+        Some(model) => model.clone(),
+        None => embed::active_model(&cfg.embedding)?,
+    };
+    let dim = model_dim(&conn, &model)?;
+    let q_vec = embed::embed_query(&cfg.embedding, &model, query)?;
+    if q_vec.len() != dim {
+        bail!(
+            "model `{model}` embedded the query into {} dimensions but its stored vectors have {dim}",
+            q_vec.len()
+        );
+    }
+    let files = opts.filters.matching_files(&conn)?;
+    let filtered = load_filter(&conn, files.as_ref())?;
+    if opts.exact {
+        return rank_by_vector(&conn, &model, &q_vec, top_k, filtered);
+    }
+    let index = match ann::cached(&ann::dir(cfg), &model)? {
+        Some(index) if index.dim() == dim => index,
+        _ => return rank_by_vector(&conn, &model, &q_vec, top_k, filtered),
+    };
+    // Few enough chunks pass the filters that scoring them all is cheaper
+    // than digging for them through the index.
+    if filtered && filtered_chunks(&conn)? * EXACT_FILTER_RATIO <= index.len() {
+        return rank_by_vector(&conn, &model, &q_vec, top_k, filtered);
+    }
+    match rank_by_ann(&conn, &index, &q_vec, top_k, filtered)? {
+        Some(hits) => Ok(hits),
+        None => rank_by_vector(&conn, &model, &q_vec, top_k, filtered),
+    }
+}
+
+/// Fill the temp table `filter_files`, which ranking queries join on to
+/// restrict their chunks, with `files`. Returns whether a filter applies.
+fn load_filter(conn: &Connection, files: Option<&HashSet<i64>>) -> Result<bool> {
+    let Some(files) = files else {
+        return Ok(false);
+    };
+    conn.execute_batch(
+        "CREATE TEMP TABLE IF NOT EXISTS filter_files (id INTEGER PRIMARY KEY); \
+         DELETE FROM temp.filter_files;",
+    )?;
+    let tx = conn.unchecked_transaction()?;
+    {
+        let mut stmt = tx.prepare("INSERT INTO temp.filter_files (id) VALUES (?1)")?;
+        for id in files {
+            stmt.execute(params![id])?;
+        }
+    }
+    tx.commit()?;
+    Ok(true)
+}
+
+/// Number of chunks of the files in `filter_files`.
+fn filtered_chunks(conn: &Connection) -> Result<usize> {
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM chunks WHERE file_id IN (SELECT id FROM temp.filter_files)",
+        [],
+        |r| r.get(0),
     )?;
-    let rows = stmt.query_map([], |row| {
+    Ok(count as usize)
+}
+
+/// SQL condition restricting the chunks `c` to the files in `filter_files`
+/// when `filtered` is set.
+fn filter_clause(filtered: bool) -> &'static str {
+    if filtered {
+        " AND c.file_id IN (SELECT id FROM temp.filter_files)"
+    } else {
+        ""
+    }
+}
+
+/// Dimension of the vectors stored for `model`, or an error naming the
+/// models that do have embeddings.
+fn model_dim(conn: &Connection, model: &str) -> Result<usize> {
+    let mut stmt = conn.prepare("SELECT model_id, MIN(dim) FROM embeddings GROUP BY model_id")?;
+    let models = stmt
+        .query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, i64>(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    if let Some((_, dim)) = models.iter().find(|(m, _)| m == model) {
+        return Ok(*dim as usize);
+    }
+    if models.is_empty() {
+        bail!("no embeddings stored; enable an embedding provider and run `findx index`");
+    }
+    let names: Vec<_> = models.iter().map(|(m, _)| m.as_str()).collect();
+    bail!(
+        "no embeddings stored for model `{model}`; available models: {}",
+        names.join(", ")
+    )
+}
+
+/// Candidates rescored with full-precision vectors per requested hit, when
+/// the stored vectors are quantized.
+const RESCORE_FACTOR: usize = 8;
+
+/// A filtered search scores its chunks exactly instead of querying the ANN
+/// index when they are at most one in this many indexed vectors.
+const EXACT_FILTER_RATIO: usize = 10;
+
+/// Neighbours fetched from the ANN index per requested hit, at most, before
+/// giving up on it for exact scoring.
+const MAX_FETCH_FACTOR: usize = 64;
+
+/// Score the chunks embedded with `model` against `q_vec`, returning the top
+/// K by dot product. Only chunks of the files in `filter_files` are scored
+/// when `filtered` is set.
+fn rank_by_vector(
+    conn: &Connection,
+    model: &str,
+    q_vec: &[f32],
+    top_k: usize,
+    filtered: bool,
+) -> Result<Vec<ChunkSearchHit>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT e.chunk_id, e.vec, e.dim, e.quantization, e.text_hash, f.realpath, c.start_byte, c.end_byte \
+         FROM embeddings e JOIN chunks c ON e.chunk_id=c.chunk_id \
+         JOIN files f ON f.id=c.file_id WHERE f.status='active' AND e.model_id=?1{}",
+        filter_clause(filtered)
+    ))?;
+    let mut rows = stmt.query(params![model])?;
+    // Hits with the quantization and text hash needed to rescore them.
+    let mut candidates = Vec::new();
+    while let Some(row) = rows.next()? {
         let chunk_id: String = row.get(0)?;
-        let vec_bytes: Vec<u8> = row.get(1)?;
+        let code: Vec<u8> = row.get(1)?;
         let dim: i64 = row.get(2)?;
-        let path: String = row.get(3)?;
-        let start_byte: i64 = row.get(4)?;
-        let end_byte: i64 = row.get(5)?;
-        let mut vec = Vec::with_capacity(dim as usize);
-        for i in 0..dim as usize {
-            let offset = i * 4;
-            let arr: [u8; 4] = vec_bytes[offset..offset + 4].try_into().unwrap();
-            vec.push(f32::from_le_bytes(arr));
+        let quantization = Quantization::parse(&row.get::<_, String>(3)?)?;
+        if dim as usize != q_vec.len() {
+            bail!(
+                "chunk {chunk_id} has a {dim}-dimensional `{model}` vector but the query has {}",
+                q_vec.len()
+            );
         }
-        Ok((chunk_id, vec, path, start_byte, end_byte))
-    })?;
-    let mut hits = Vec::new();
-    for row in rows {
-        let (chunk_id, vec, path, start_byte, end_byte) = row?;
-        let score: f32 = q_vec.iter().zip(vec.iter()).map(|(a, b)| a * b).sum();
-        hits.push(ChunkSearchHit {
-            path,
-            score,
+        let hit = ChunkSearchHit {
+            path: row.get(5)?,
+            score: quant::score(quantization, q_vec, &code),
             chunk_id,
-            start_byte,
-            end_byte,
-        });
+            start_byte: row.get(6)?,
+            end_byte: row.get(7)?,
+            location: Location::default(),
+            snippet: None,
+            keyword: None,
+            semantic: None,
+        };
+        candidates.push((hit, quantization, row.get::<_, Option<String>>(4)?));
+    }
+    candidates.sort_by(|a, b| b.0.score.total_cmp(&a.0.score));
+
+    // Quantized scores only pick candidates; the best of them are rescored
+    // with their full-precision vectors.
+    candidates.truncate(top_k * RESCORE_FACTOR);
+    let mut hits = Vec::with_capacity(candidates.len());
+    for (mut hit, quantization, text_hash) in candidates {
+        if quantization != Quantization::None {
+            let vector = embed::full_vector(conn, model, text_hash.as_deref())?;
+            hit.score = quant::dot(q_vec, &vector);
+        }
+        hits.push(hit);
     }
     hits.sort_by(|a, b| b.score.total_cmp(&a.score));
     hits.truncate(top_k);
-    Ok(ChunkSearchResults { results: hits })
+    locate(conn, &mut hits)?;
+    Ok(hits)
 }
 
-fn rrf(bm25: &[ChunkSearchHit], ann: &[ChunkSearchHit], top_k: usize) -> Vec<ChunkSearchHit> {
-    let k_rrf = 60.0;
-    let mut scores: HashMap<String, (ChunkSearchHit, f32)> = HashMap::new();
-    for (rank, item) in bm25.iter().enumerate() {
-        let contrib = 1.0 / (k_rrf + rank as f32 + 1.0);
-        scores
-            .entry(item.chunk_id.clone())
-            .and_modify(|(_, s)| *s += contrib)
-            .or_insert((item.clone(), contrib));
-    }
-    for (rank, item) in ann.iter().enumerate() {
-        let contrib = 1.0 / (k_rrf + rank as f32 + 1.0);
-        scores
-            .entry(item.chunk_id.clone())
-            .and_modify(|(_, s)| *s += contrib)
-            .or_insert((item.clone(), contrib));
-    }
-    let mut out: Vec<ChunkSearchHit> = scores
-        .into_iter()
-        .map(|(_, (hit, s))| ChunkSearchHit { score: s, ..hit })
-        .collect();
-    out.sort_by(|a, b| b.score.total_cmp(&a.score));
-    out.truncate(top_k);
+/// Look up the nearest neighbours of `q_vec` in `index`, keeping the top K
+/// that belong to active files, and to the files in `filter_files` when
+/// `filtered` is set. The index is over-fetched, and searched again deeper
+/// while too few neighbours pass, so filters and deleted files the index has
+/// not caught up with do not shorten the results. Returns `None` when
+/// `MAX_FETCH_FACTOR` neighbours per hit still fall short.
+fn rank_by_ann(
+    conn: &Connection,
+    index: &ann::Index,
+    q_vec: &[f32],
+    top_k: usize,
+    filtered: bool,
+) -> Result<Option<Vec<ChunkSearchHit>>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT f.realpath, c.start_byte, c.end_byte FROM chunks c \
+         JOIN files f ON f.id=c.file_id WHERE c.chunk_id=?1 AND f.status='active'{}",
+        filter_clause(filtered)
+    ))?;
+    let max_fetch = top_k * MAX_FETCH_FACTOR;
+    let mut fetch = top_k * 2;
+    loop {
+        let mut hits = Vec::new();
+        for (chunk_id, score) in index.search(q_vec, fetch) {
+            let row = stmt
+                .query_row(params![chunk_id], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+                .optional()?;
+            if let Some((path, start_byte, end_byte)) = row {
+                hits.push(ChunkSearchHit {
+                    path,
+                    score,
+                    chunk_id: chunk_id.to_string(),
+                    start_byte,
+                    end_byte,
+                    location: Location::default(),
+                    snippet: None,
+                    keyword: None,
+                    semantic: None,
+                });
+            }
+            if hits.len() == top_k {
+                break;
+            }
+        }
+        if hits.len() == top_k || fetch >= index.len() {
+            locate(conn, &mut hits)?;
+            return Ok(Some(hits));
+        }
+        if fetch >= max_fetch {
+            return Ok(None);
+        }
+        fetch = (fetch * 4).min(max_fetch);
+    }
+}
+
+fn open_chunk_index(cfg: &Config) -> Result<(Index, ChunkFields)> {
+    let index_dir = cfg.tantivy_index.join("chunks");
+    let index = Index::open_in_dir(index_dir.as_std_path())?;
+    index::register_tokenizers(&index, &cfg.index)?;
+    let fields = ChunkFields::from_schema(&index.schema(), &cfg.index.languages)?;
+    Ok((index, fields))
+}
+
+/// Query parser searching the text field of every indexed language.
+fn query_parser(index: &Index, fields: &[(String, Field)]) -> QueryParser {
+    let fields: Vec<_> = fields.iter().map(|(_, f)| *f).collect();
+    let mut parser = QueryParser::for_index(index, fields.clone());
+    for field in fields {
+        parser.set_field_boost(field, 1.0);
+    }
+    parser
+}
+
+/// Attach a snippet of the stored chunk text to each hit when requested.
+fn chunk_snippets(
+    cfg: &Config,
+    query: &str,
+    opts: &SearchOptions,
+    hits: &mut [ChunkSearchHit],
+) -> Result<()> {
+    match chunk_highlighter(cfg, query, opts)? {
+        Some(highlighter) => attach_snippets(&db::open(&cfg.db)?, &highlighter, hits),
+        None => Ok(()),
+    }
+}
+
+/// Highlighter of the chunk texts when snippets are requested. Terms of
+/// `query` are highlighted whether the hits came from BM25 or from
+/// embeddings; syntax errors are tolerated since semantic queries are
+/// natural language.
+fn chunk_highlighter(
+    cfg: &Config,
+    query: &str,
+    opts: &SearchOptions,
+) -> Result<Option<Highlighter>> {
+    let Some(max_chars) = opts.snippet_chars else {
+        return Ok(None);
+    };
+    let (index, fields) = open_chunk_index(cfg)?;
+    let searcher = index.reader()?.searcher();
+    let (q, _) = query_parser(&index, &fields.chunk_text).parse_query_lenient(query);
+    let highlighter = Highlighter::new(&searcher, q.as_ref(), &fields.chunk_text, max_chars)?;
+    Ok(Some(highlighter))
+}
+
+/// Attach to each hit a snippet of its stored chunk text.
+fn attach_snippets(
+    conn: &Connection,
+    highlighter: &Highlighter,
+    hits: &mut [ChunkSearchHit],
+) -> Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT c.text, IFNULL(d.lang, '') FROM chunks c \
+         LEFT JOIN documents d ON d.file_id=c.file_id WHERE c.chunk_id=?1",
+    )?;
+    for hit in hits {
+        let row: Option<(String, String)> = stmt
+            .query_row(params![hit.chunk_id], |r| Ok((r.get(0)?, r.get(1)?)))
+            .optional()?;
+        if let Some((text, lang)) = row {
+            hit.snippet = Some(highlighter.snippet(&text, &lang));
+        }
+    }
+    Ok(())
+}
+
+/// Highlights the terms of a query in text using the analyzer of each
+/// indexed language.
+struct Highlighter {
+    generators: Vec<(String, SnippetGenerator)>,
+    max_chars: usize,
+}
+
+impl Highlighter {
+    fn new(
+        searcher: &Searcher,
+        query: &dyn Query,
+        fields: &[(String, Field)],
+        max_chars: usize,
+    ) -> Result<Self> {
+        let mut generators = Vec::new();
+        for (lang, field) in fields {
+            let mut generator = SnippetGenerator::create(searcher, query, *field)?;
+            generator.set_max_num_chars(max_chars);
+            generators.push((lang.clone(), generator));
+        }
+        Ok(Self {
+            generators,
+            max_chars,
+        })
+    }
+
+    /// HTML snippet of `text` with matched terms in `<b>` tags, trying the
+    /// analyzer of `lang` first. Text without any match yields its opening
+    /// characters.
+    fn snippet(&self, text: &str, lang: &str) -> String {
+        let preferred = self.generators.iter().filter(|(l, _)| l == lang);
+        let others = self.generators.iter().filter(|(l, _)| l != lang);
+        for (_, generator) in preferred.chain(others) {
+            let snippet = generator.snippet(text);
+            if !snippet.is_empty() {
+                return snippet.to_html();
+            }
+        }
+        let text = text.trim_start();
+        let end = text
+            .char_indices()
+            .nth(self.max_chars)
+            .map_or(text.len(), |(i, c)| {
+                // Cut at the last word boundary that fits.
+                if c.is_whitespace() {
+                    i
+                } else {
+                    text[..i].rfind(char::is_whitespace).unwrap_or(i)
+                }
+            });
+        escape_html(text[..end].trim_end())
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
     out
 }
 
-/// Hybrid search combining BM25 and embedding scores with Reciprocal Rank Fusion.
-pub fn hybrid_chunks(cfg: &Config, query: &str, top_k: usize) -> Result<ChunkSearchResults> {
-    let bm25 = keyword_chunks(cfg, query, top_k)?.results;
-    let ann = semantic_chunks(cfg, query, top_k)?.results;
-    let fused = rrf(&bm25, &ann, top_k);
+/// Fill the location of each hit from the catalog. Files extracted by the
+/// builtin plaintext reader get line ranges; other formats get page ranges.
+fn locate(conn: &Connection, hits: &mut [ChunkSearchHit]) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT c.page_from, c.page_to, c.line_from, c.col_from, c.line_to, c.col_to, IFNULL(d.extractor, '') \
+         FROM chunks c LEFT JOIN documents d ON d.file_id=c.file_id WHERE c.chunk_id=?1",
+    )?;
+    for hit in hits {
+        let row = stmt
+            .query_row(params![hit.chunk_id], |r| {
+                Ok((
+                    Location {
+                        page_from: r.get(0)?,
+                        page_to: r.get(1)?,
+                        line_from: r.get(2)?,
+                        col_from: r.get(3)?,
+                        line_to: r.get(4)?,
+                        col_to: r.get(5)?,
+                    },
+                    r.get::<_, String>(6)?,
+                ))
+            })
+            .optional()?;
+        let Some((mut location, extractor)) = row else {
+            continue;
+        };
+        if extractor == "builtin" {
+            location.page_from = None;
+            location.page_to = None;
+        } else {
+            location.line_from = None;
+            location.col_from = None;
+            location.line_to = None;
+            location.col_to = None;
+        }
+        hit.location = location;
+    }
+    Ok(())
+}
+
+/// Merge keyword and semantic candidates into the top K hits, recording on
+/// each hit where both retrievers ranked it.
+fn fuse(
+    bm25: Vec<ChunkSearchHit>,
+    ann: Vec<ChunkSearchHit>,
+    top_k: usize,
+    opts: &FusionOptions,
+) -> Vec<ChunkSearchHit> {
+    let keyword_range = score_range(&bm25);
+    let semantic_range = score_range(&ann);
+    let mut fused: Vec<ChunkSearchHit> = Vec::new();
+    let mut by_id: HashMap<String, usize> = HashMap::new();
+    for (semantic, list) in [(false, bm25), (true, ann)] {
+        for (rank, hit) in list.into_iter().enumerate() {
+            let found = Some(RetrieverHit {
+                rank: rank + 1,
+                score: hit.score,
+            });
+            let i = match by_id.get(&hit.chunk_id) {
+                Some(&i) => i,
+                None => {
+                    by_id.insert(hit.chunk_id.clone(), fused.len());
+                    fused.push(hit);
+                    fused.len() - 1
+                }
+            };
+            if semantic {
+                fused[i].semantic = found;
+            } else {
+                fused[i].keyword = found;
+            }
+        }
+    }
+    let part = |found: Option<RetrieverHit>, weight: f32, (min, max): (f32, f32)| {
+        let Some(found) = found else {
+            return 0.0;
+        };
+        weight
+            * match opts.method {
+                Fusion::Rrf => 1.0 / (opts.rrf_k + found.rank as f32),
+                Fusion::Linear if max > min => (found.score - min) / (max - min),
+                Fusion::Linear => 1.0,
+            }
+    };
+    for hit in &mut fused {
+        hit.score = part(hit.keyword, opts.keyword_weight, keyword_range)
+            + part(hit.semantic, opts.semantic_weight, semantic_range);
+    }
+    fused.sort_by(|a, b| b.score.total_cmp(&a.score));
+    fused.truncate(top_k);
+    fused
+}
+
+/// Lowest and highest score of `hits`.
+fn score_range(hits: &[ChunkSearchHit]) -> (f32, f32) {
+    hits.iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), h| {
+            (min.min(h.score), max.max(h.score))
+        })
+}
+
+/// Hybrid search fusing BM25 and embedding candidates, fetched at the depths
+/// of `opts.fusion`, by reciprocal rank or normalized score.
+pub fn hybrid_chunks(
+    cfg: &Config,
+    query: &str,
+    top_k: usize,
+    opts: &SearchOptions,
+) -> Result<ChunkSearchResults> {
+    let mut fused = hybrid_chunk_hits(cfg, query, top_k, opts)?;
+    chunk_snippets(cfg, query, opts, &mut fused)?;
     Ok(ChunkSearchResults { results: fused })
 }
 
+/// Hybrid search returning the top K files, scored from their best fused
+/// chunks.
+pub fn hybrid(
+    cfg: &Config,
+    query: &str,
+    top_k: usize,
+    opts: &SearchOptions,
+) -> Result<SearchResults> {
+    let depth = opts.aggregate.chunk_depth.max(top_k);
+    let hits = hybrid_chunk_hits(cfg, query, depth, opts)?;
+    files_from_chunks(cfg, query, hits, top_k, opts)
+}
+
+fn hybrid_chunk_hits(
+    cfg: &Config,
+    query: &str,
+    top_k: usize,
+    opts: &SearchOptions,
+) -> Result<Vec<ChunkSearchHit>> {
+    let fusion = &opts.fusion;
+    let bm25 = keyword_chunk_hits(cfg, query, fusion.keyword_depth.max(top_k), &opts.filters)?;
+    let ann = semantic_chunk_hits(cfg, query, fusion.semantic_depth.max(top_k), opts)?;
+    Ok(fuse(bm25, ann, top_k, fusion))
+}
+
+/// Group ranked chunk hits into the top K files and attach snippets to the
+/// chunks listed with each.
+fn files_from_chunks(
+    cfg: &Config,
+    query: &str,
+    hits: Vec<ChunkSearchHit>,
+    top_k: usize,
+    opts: &SearchOptions,
+) -> Result<SearchResults> {
+    let conn = db::open(&cfg.db)?;
+    let mut files = aggregate(&conn, hits, top_k, &opts.aggregate)?;
+    let highlighter = chunk_highlighter(cfg, query, opts)?;
+    for file in &mut files {
+        if let Some(highlighter) = &highlighter {
+            attach_snippets(&conn, highlighter, &mut file.chunks)?;
+        }
+        file.snippet = file.chunks.first().and_then(|c| c.snippet.clone());
+    }
+    Ok(SearchResults { results: files })
+}
+
+/// Group `hits`, ranked best first, by file and score each file from its
+/// chunks. Mean and top-N sum only see the chunks among `hits`.
+fn aggregate(
+    conn: &Connection,
+    hits: Vec<ChunkSearchHit>,
+    top_k: usize,
+    opts: &AggregateOptions,
+) -> Result<Vec<SearchHit>> {
+    let mut stmt = conn.prepare(
+        "SELECT f.id, f.mtime_ns FROM chunks c JOIN files f ON f.id=c.file_id WHERE c.chunk_id=?1",
+    )?;
+    // Files in order of their best chunk, with all their chunk hits.
+    let mut files: Vec<(SearchHit, Vec<ChunkSearchHit>)> = Vec::new();
+    let mut by_id: HashMap<i64, usize> = HashMap::new();
+    for hit in hits {
+        let row: Option<(i64, i64)> = stmt
+            .query_row(params![hit.chunk_id], |r| Ok((r.get(0)?, r.get(1)?)))
+            .optional()?;
+        let Some((file_id, mtime_ns)) = row else {
+            continue;
+        };
+        let i = *by_id.entry(file_id).or_insert_with(|| {
+            files.push((
+                SearchHit {
+                    path: hit.path.clone(),
+                    score: 0.0,
+                    file_id,
+                    mtime: mtime(mtime_ns),
+                    snippet: None,
+                    chunks: Vec::new(),
+                },
+                Vec::new(),
+            ));
+            files.len() - 1
+        });
+        files[i].1.push(hit);
+    }
+    let mut out: Vec<SearchHit> = files
+        .into_iter()
+        .map(|(mut file, mut chunks)| {
+            let scores = chunks.iter().map(|c| c.score);
+            file.score = match opts.method {
+                Aggregation::Max => chunks[0].score,
+                Aggregation::TopSum(n) => scores.take(n.max(1)).sum(),
+                Aggregation::Mean => scores.sum::<f32>() / chunks.len() as f32,
+            };
+            chunks.truncate(opts.chunks_per_file);
+            file.chunks = chunks;
+            file
+        })
+        .collect();
+    out.sort_by(|a, b| b.score.total_cmp(&a.score));
+    out.truncate(top_k);
+    Ok(out)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use camino::Utf8PathBuf;
     use tempfile::tempdir;
 
-    use crate::config::{Config, EmbeddingConfig, RetentionConfig};
+    use crate::config::{ChunkingConfig, Config, EmbeddingConfig};
     use crate::db;
     use rusqlite::params;
 
@@ -222,48 +962,56 @@ mod tests {
     fn keyword_search_returns_hit() -> Result<()> {
         let tmp = tempdir()?;
         let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
-        let db_path = root.join("catalog.db");
-        let idx_path = root.join("idx");
         let cfg = Config {
-            db: db_path.clone(),
-            tantivy_index: idx_path.clone(),
-            roots: vec![],
-            include: vec![],
-            exclude: vec![],
-            max_file_size_mb: 200,
-            follow_symlinks: false,
-            include_hidden: false,
-            allow_offline_hydration: false,
-            commit_interval_secs: 45,
-            guard_interval_secs: 180,
             default_language: "en".into(),
-            extractor_cmd: String::new(),
-            embedding: EmbeddingConfig {
-                provider: "disabled".into(),
-            },
-            mirror: crate::config::MirrorConfig {
-                root: Utf8PathBuf::from("raw"),
-            },
-            bus: crate::config::BusConfig {
-                bounds: crate::config::BusBounds {
-                    source_fs: 16,
-                    mirror_text: 16,
-                },
-            },
-            extract: crate::config::ExtractConfig {
-                pool_size: 1,
-                jobs_bound: 16,
-            },
-            retention: RetentionConfig::default(),
+            ..Config::for_test(&root)
         };
 
-        let conn = db::open(&db_path)?;
+        let conn = db::open(&cfg.db)?;
         conn.execute("INSERT INTO files (id, realpath, size, mtime_ns, status, created_ts, updated_ts) VALUES (1,'/tmp/a.txt',1,0,'active',0,0)", [])?;
         conn.execute("INSERT INTO documents (file_id, extractor, extractor_version, lang, page_count, content_md, content_txt, ocr_applied, updated_ts) VALUES (1,'doc','v','en',1,'','hello world',0,0)", [])?;
 
         index::reindex_all(&cfg, None)?;
-        let res = keyword(&cfg, "hello", 10)?;
+        let res = keyword(&cfg, "hello", 10, &SearchOptions::default())?;
         assert_eq!(res.results.len(), 1);
+        let chunks = &res.results[0].chunks;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].path, "/tmp/a.txt");
+        assert_eq!((chunks[0].start_byte, chunks[0].end_byte), (0, 11));
+        Ok(())
+    }
+
+    #[test]
+    fn keyword_search_matches_cjk_text() -> Result<()> {
+        let tmp = tempdir()?;
+        let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
+        let cfg = Config {
+            default_language: "en".into(),
+            ..Config::for_test(&root)
+        };
+
+        let conn = db::open(&cfg.db)?;
+        conn.execute("INSERT INTO files (id, realpath, size, mtime_ns, status, created_ts, updated_ts) VALUES (1,'/tmp/ja.txt',1,0,'active',0,0)", [])?;
+        conn.execute("INSERT INTO files (id, realpath, size, mtime_ns, status, created_ts, updated_ts) VALUES (2,'/tmp/en.txt',1,0,'active',0,0)", [])?;
+        conn.execute("INSERT INTO documents (file_id, extractor, extractor_version, lang, page_count, content_md, content_txt, ocr_applied, updated_ts) VALUES (1,'doc','v','ja',1,'','東京都庁は新宿にあります',0,0)", [])?;
+        conn.execute("INSERT INTO documents (file_id, extractor, extractor_version, lang, page_count, content_md, content_txt, ocr_applied, updated_ts) VALUES (2,'doc','v','en',1,'','Meeting notes 会議の議事録 for the quarter',0,0)", [])?;
+
+        index::reindex_all(&cfg, None)?;
+        let paths = |q: &str| -> Result<Vec<String>> {
+            Ok(keyword(&cfg, q, 10, &SearchOptions::default())?
+                .results
+                .into_iter()
+                .map(|h| h.path)
+                .collect())
+        };
+        assert_eq!(paths("新宿")?, ["/tmp/ja.txt"]);
+        assert_eq!(paths("東京都")?, ["/tmp/ja.txt"]);
+        assert_eq!(paths("議事録")?, ["/tmp/en.txt"]);
+        assert!(paths("京新")?.is_empty());
+        // One-character words match the characters of longer runs.
+        assert_eq!(paths("庁")?, ["/tmp/ja.txt"]);
+        assert_eq!(paths("会")?, ["/tmp/en.txt"]);
+        assert!(paths("水")?.is_empty());
         Ok(())
     }
 
@@ -271,101 +1019,512 @@ mod tests {
     fn keyword_chunk_search_returns_hit() -> Result<()> {
         let tmp = tempdir()?;
         let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
-        let db_path = root.join("catalog.db");
-        let idx_path = root.join("idx");
         let cfg = Config {
-            db: db_path.clone(),
-            tantivy_index: idx_path.clone(),
-            roots: vec![],
-            include: vec![],
-            exclude: vec![],
-            max_file_size_mb: 200,
-            follow_symlinks: false,
-            include_hidden: false,
-            allow_offline_hydration: false,
-            commit_interval_secs: 45,
-            guard_interval_secs: 180,
             default_language: "en".into(),
-            extractor_cmd: String::new(),
-            embedding: EmbeddingConfig {
-                provider: "disabled".into(),
-            },
-            mirror: crate::config::MirrorConfig {
-                root: Utf8PathBuf::from("raw"),
-            },
-            bus: crate::config::BusConfig {
-                bounds: crate::config::BusBounds {
-                    source_fs: 16,
-                    mirror_text: 16,
-                },
-            },
-            extract: crate::config::ExtractConfig {
-                pool_size: 1,
-                jobs_bound: 16,
-            },
-            retention: RetentionConfig::default(),
+            ..Config::for_test(&root)
         };
 
-        let conn = db::open(&db_path)?;
+        let conn = db::open(&cfg.db)?;
         conn.execute("INSERT INTO files (id, realpath, size, mtime_ns, status, created_ts, updated_ts) VALUES (1,'/tmp/a.txt',1,0,'active',0,0)", [])?;
         let long_text = "hello world".repeat(100);
         conn.execute("INSERT INTO documents (file_id, extractor, extractor_version, lang, page_count, content_md, content_txt, ocr_applied, updated_ts) VALUES (1,'doc','v','en',1,'',?1,0,0)",
             params![long_text])?;
 
         index::reindex_all(&cfg, None)?;
-        let res = keyword_chunks(&cfg, "hello", 10)?;
+        let res = keyword_chunks(&cfg, "hello", 10, &SearchOptions::default())?;
         assert!(!res.results.is_empty());
         Ok(())
     }
 
     #[test]
-    fn semantic_chunk_search_returns_hit() -> Result<()> {
+    fn chunk_hits_carry_lines_or_pages() -> Result<()> {
         let tmp = tempdir()?;
         let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
-        let db_path = root.join("catalog.db");
-        let idx_path = root.join("idx");
         let cfg = Config {
-            db: db_path.clone(),
-            tantivy_index: idx_path.clone(),
-            roots: vec![],
-            include: vec![],
-            exclude: vec![],
-            max_file_size_mb: 200,
-            follow_symlinks: false,
-            include_hidden: false,
-            allow_offline_hydration: false,
-            commit_interval_secs: 45,
-            guard_interval_secs: 180,
             default_language: "en".into(),
-            extractor_cmd: String::new(),
-            embedding: EmbeddingConfig {
-                provider: "builtin".into(),
-            },
-            mirror: crate::config::MirrorConfig {
-                root: Utf8PathBuf::from("raw"),
+            chunking: ChunkingConfig {
+                strategy: crate::config::ChunkStrategy::Paragraph,
+                max_tokens: 4,
+                overlap_tokens: 0,
             },
-            bus: crate::config::BusConfig {
-                bounds: crate::config::BusBounds {
-                    source_fs: 16,
-                    mirror_text: 16,
+            ..Config::for_test(&root)
+        };
+
+        let conn = db::open(&cfg.db)?;
+        conn.execute("INSERT INTO files (id, realpath, size, mtime_ns, status, created_ts, updated_ts) VALUES (1,'/tmp/a.rs',1,0,'active',0,0)", [])?;
+        conn.execute("INSERT INTO files (id, realpath, size, mtime_ns, status, created_ts, updated_ts) VALUES (2,'/tmp/b.pdf',1,0,'active',0,0)", [])?;
+        conn.execute("INSERT INTO documents (file_id, extractor, extractor_version, lang, page_count, content_md, content_txt, ocr_applied, updated_ts) VALUES (1,'builtin','','en',1,'',?1,0,0)",
+            params!["fn main() {}\n\n  let walrus = 1;"])?;
+        conn.execute("INSERT INTO documents (file_id, extractor, extractor_version, lang, page_count, content_md, content_txt, ocr_applied, updated_ts) VALUES (2,'docling','','en',2,'',?1,0,0)",
+            params!["cover page\x0cthe penguin chapter"])?;
+
+        index::reindex_all(&cfg, None)?;
+        let hit = keyword_chunks(&cfg, "walrus", 10, &SearchOptions::default())?
+            .results
+            .remove(0);
+        assert_eq!(
+            hit.location,
+            Location {
+                line_from: Some(3),
+                col_from: Some(1),
+                line_to: Some(3),
+                col_to: Some(17),
+                ..Location::default()
+            }
+        );
+        let hit = keyword_chunks(&cfg, "penguin", 10, &SearchOptions::default())?
+            .results
+            .remove(0);
+        assert_eq!(
+            hit.location,
+            Location {
+                page_from: Some(2),
+                page_to: Some(2),
+                ..Location::default()
+            }
+        );
+        let json = serde_json::to_value(&hit)?;
+        assert_eq!(json["page_from"], 2);
+        assert!(json.get("line_from").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn snippets_highlight_matched_terms() -> Result<()> {
+        let tmp = tempdir()?;
+        let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
+        let cfg = Config {
+            default_language: "en".into(),
+            ..Config::for_test(&root)
+        };
+
+        let conn = db::open(&cfg.db)?;
+        conn.execute("INSERT INTO files (id, realpath, size, mtime_ns, status, created_ts, updated_ts) VALUES (1,'/tmp/a.txt',1,0,'active',0,0)", [])?;
+        conn.execute("INSERT INTO documents (file_id, extractor, extractor_version, lang, page_count, content_md, content_txt, ocr_applied, updated_ts) VALUES (1,'builtin','','en',1,'',?1,0,0)",
+            params!["Notes & minutes. The walruses gathered on the beach at dawn."])?;
+
+        index::reindex_all(&cfg, None)?;
+        let opts = SearchOptions {
+            snippet_chars: Some(40),
+            ..SearchOptions::default()
+        };
+        let res = keyword(&cfg, "walrus", 10, &opts)?;
+        assert_eq!(
+            res.results[0].snippet.as_deref(),
+            Some("Notes &amp; minutes. The <b>walruses</b> gathered")
+        );
+        assert!(res.results[0].chunks[0]
+            .snippet
+            .as_deref()
+            .unwrap()
+            .contains("<b>walruses</b>"));
+
+        let mut hits = keyword_chunks(&cfg, "walrus", 10, &opts)?.results;
+        assert!(hits[0]
+            .snippet
+            .as_deref()
+            .unwrap()
+            .contains("<b>walruses</b>"));
+        assert!(
+            keyword_chunks(&cfg, "walrus", 10, &SearchOptions::default())?.results[0]
+                .snippet
+                .is_none()
+        );
+
+        // Hits without a lexical match, as from embeddings, show the start of
+        // the chunk.
+        chunk_snippets(&cfg, "seal", &opts, &mut hits)?;
+        assert_eq!(
+            hits[0].snippet.as_deref(),
+            Some("Notes &amp; minutes. The walruses gathered")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn filters_restrict_keyword_chunk_and_vector_search() -> Result<()> {
+        let tmp = tempdir()?;
+        let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
+        let cfg = Config {
+            default_language: "en".into(),
+            ..Config::for_test(&root)
+        };
+
+        let conn = db::open(&cfg.db)?;
+        let day = |d: &str| {
+            filter::parse_time(d)
+                .unwrap()
+                .timestamp_nanos_opt()
+                .unwrap()
+        };
+        for (id, path, size, mtime, lang) in [
+            (1, "/docs/a.md", 10, day("2024-01-10"), "en"),
+            (2, "/docs/sub/b.pdf", 5000, day("2024-06-01"), "fr"),
+            (3, "/other/c.txt", 100, day("2023-03-01"), "en"),
+        ] {
+            conn.execute(
+                "INSERT INTO files (id, realpath, size, mtime_ns, status, created_ts, updated_ts) VALUES (?1,?2,?3,?4,'active',0,0)",
+                params![id, path, size, mtime],
+            )?;
+            conn.execute(
+                "INSERT INTO documents (file_id, extractor, extractor_version, lang, page_count, content_md, content_txt, ocr_applied, updated_ts) VALUES (?1,'doc','v',?2,1,'','walrus notes',0,0)",
+                params![id, lang],
+            )?;
+        }
+        index::reindex_all(&cfg, None)?;
+        let chunk_ids: Vec<(String, i64)> = conn
+            .prepare("SELECT chunk_id, file_id FROM chunks")?
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        for (chunk_id, file_id) in &chunk_ids {
+            conn.execute(
+                "INSERT INTO embeddings (chunk_id, model_id, dim, vec) VALUES (?1, 'm', 2, ?2)",
+                params![chunk_id, embed::encode_vector(&[1.0, *file_id as f32])],
+            )?;
+        }
+
+        let cases = [
+            (
+                Filters {
+                    paths: vec!["/docs/".into()],
+                    ..Filters::default()
+                },
+                vec!["/docs/a.md", "/docs/sub/b.pdf"],
+            ),
+            (
+                Filters {
+                    exts: vec!["pdf".into(), "txt".into()],
+                    ..Filters::default()
+                },
+                vec!["/docs/sub/b.pdf", "/other/c.txt"],
+            ),
+            (
+                Filters {
+                    mimes: vec!["text/*".into()],
+                    langs: vec!["en".into()],
+                    ..Filters::default()
+                },
+                vec!["/docs/a.md", "/other/c.txt"],
+            ),
+            (
+                Filters {
+                    modified_after: Some(filter::parse_time("2024-01-01")?),
+                    max_size: Some(1000),
+                    ..Filters::default()
                 },
+                vec!["/docs/a.md"],
+            ),
+        ];
+        for (filters, expected) in cases {
+            let opts = SearchOptions {
+                filters: filters.clone(),
+                ..SearchOptions::default()
+            };
+            let sorted = |mut paths: Vec<String>| {
+                paths.sort();
+                paths.dedup();
+                paths
+            };
+            let keyword_paths = keyword(&cfg, "walrus", 10, &opts)?
+                .results
+                .into_iter()
+                .map(|h| h.path)
+                .collect();
+            assert_eq!(sorted(keyword_paths), expected, "{filters:?}");
+            let chunk_paths = keyword_chunks(&cfg, "walrus", 10, &opts)?
+                .results
+                .into_iter()
+                .map(|h| h.path)
+                .collect();
+            assert_eq!(sorted(chunk_paths), expected, "{filters:?}");
+            let filtered = load_filter(&conn, filters.matching_files(&conn)?.as_ref())?;
+            let vector_paths = rank_by_vector(&conn, "m", &[0.0, 1.0], 10, filtered)?
+                .into_iter()
+                .map(|h| h.path)
+                .collect();
+            assert_eq!(sorted(vector_paths), expected, "{filters:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn vector_ranking_is_limited_to_one_model() -> Result<()> {
+        let conn = db::open(camino::Utf8Path::new(":memory:"))?;
+        conn.execute("INSERT INTO files (id, realpath, size, mtime_ns, status, created_ts, updated_ts) VALUES (1,'/tmp/a.txt',1,0,'active',0,0)", [])?;
+        let embedding = |chunk_id: &str, model: &str, vec: &[f32]| -> Result<()> {
+            conn.execute(
+                "INSERT OR IGNORE INTO chunks (file_id, chunk_id, start_byte, end_byte, text) VALUES (1, ?1, 0, 0, '')",
+                params![chunk_id],
+            )?;
+            let bytes: Vec<u8> = vec.iter().flat_map(|f| f.to_le_bytes()).collect();
+            conn.execute(
+                "INSERT INTO embeddings (chunk_id, model_id, dim, vec) VALUES (?1, ?2, ?3, ?4)",
+                params![chunk_id, model, vec.len() as i64, bytes],
+            )?;
+            Ok(())
+        };
+        embedding("c1", "small", &[1.0, 0.0])?;
+        embedding("c2", "small", &[0.0, 1.0])?;
+        embedding("c1", "large", &[0.0, 0.0, 1.0])?;
+
+        assert_eq!(model_dim(&conn, "small")?, 2);
+        assert_eq!(model_dim(&conn, "large")?, 3);
+        let err = model_dim(&conn, "other").unwrap_err().to_string();
+        assert!(err.contains("available models: large, small"), "{err}");
+
+        let hits = rank_by_vector(&conn, "small", &[0.2, 0.9], 10, false)?;
+        let ids: Vec<_> = hits.iter().map(|h| h.chunk_id.as_str()).collect();
+        assert_eq!(ids, ["c2", "c1"]);
+        assert_eq!(
+            rank_by_vector(&conn, "large", &[0.0, 0.0, 1.0], 10, false)?.len(),
+            1
+        );
+        let Err(err) = rank_by_vector(&conn, "small", &[1.0, 0.0, 0.0], 10, false) else {
+            panic!("mismatched dimensions were accepted");
+        };
+        assert!(err.to_string().contains("2-dimensional"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn quantized_candidates_are_rescored_exactly() -> Result<()> {
+        let conn = db::open(camino::Utf8Path::new(":memory:"))?;
+        conn.execute("INSERT INTO files (id, realpath, size, mtime_ns, status, created_ts, updated_ts) VALUES (1,'/tmp/a.txt',1,0,'active',0,0)", [])?;
+        for (id, text) in [("c1", "near"), ("c2", "far")] {
+            conn.execute(
+                "INSERT INTO chunks (file_id, chunk_id, start_byte, end_byte, text) VALUES (1, ?1, 0, 0, ?2)",
+                params![id, text],
+            )?;
+        }
+        // Both vectors share the same signs, so their binary codes tie.
+        embed::embed_chunks(
+            &conn,
+            &embed::EmbedParams {
+                model_id: "m".into(),
+                quantization: Quantization::Binary,
+                ..embed::EmbedParams::default()
             },
-            extract: crate::config::ExtractConfig {
-                pool_size: 1,
-                jobs_bound: 16,
+            None,
+            |texts| {
+                Ok(texts
+                    .iter()
+                    .map(|t| {
+                        if t == "near" {
+                            vec![0.9, 0.1]
+                        } else {
+                            vec![0.1, 0.9]
+                        }
+                    })
+                    .collect())
             },
-            retention: RetentionConfig::default(),
+        )?;
+        let hits = rank_by_vector(&conn, "m", &[1.0, 0.0], 2, false)?;
+        let ranked: Vec<_> = hits
+            .iter()
+            .map(|h| (h.chunk_id.as_str(), h.score))
+            .collect();
+        assert_eq!(ranked, [("c1", 0.9), ("c2", 0.1)]);
+        Ok(())
+    }
+
+    #[test]
+    fn ann_ranking_matches_exact_ranking_for_active_files() -> Result<()> {
+        let tmp = tempdir()?;
+        let dir = camino::Utf8Path::from_path(tmp.path()).unwrap().join("ann");
+        let conn = db::open(camino::Utf8Path::new(":memory:"))?;
+        conn.execute("INSERT INTO files (id, realpath, size, mtime_ns, status, created_ts, updated_ts) VALUES (1,'/tmp/a.txt',1,0,'active',0,0)", [])?;
+        conn.execute("INSERT INTO files (id, realpath, size, mtime_ns, status, created_ts, updated_ts) VALUES (2,'/tmp/b.txt',1,0,'deleted',0,0)", [])?;
+        conn.execute("INSERT INTO files (id, realpath, size, mtime_ns, status, created_ts, updated_ts) VALUES (3,'/tmp/c.txt',1,0,'active',0,0)", [])?;
+        for (i, (file_id, vec)) in [
+            (1, [1.0, 0.0]),
+            (1, [0.6, 0.8]),
+            (1, [0.0, 1.0]),
+            (2, [0.8, 0.6]),
+            (3, [0.99, 0.14]),
+        ]
+        .iter()
+        .enumerate()
+        {
+            conn.execute(
+                "INSERT INTO chunks (file_id, chunk_id, start_byte, end_byte, text) VALUES (?1, ?2, 0, 0, '')",
+                params![file_id, format!("c{i}")],
+            )?;
+            conn.execute(
+                "INSERT INTO embeddings (chunk_id, model_id, dim, vec) VALUES (?1, 'm', 2, ?2)",
+                params![format!("c{i}"), embed::encode_vector(vec)],
+            )?;
+        }
+        ann::sync(&conn, &dir, "m")?;
+        let index = ann::load(&dir, "m")?.unwrap();
+
+        let ids = |hits: Vec<ChunkSearchHit>| -> Vec<String> {
+            hits.into_iter().map(|h| h.chunk_id).collect()
+        };
+        let exact = ids(rank_by_vector(&conn, "m", &[1.0, 0.1], 2, false)?);
+        assert_eq!(exact, ["c4", "c0"]);
+        let ann = rank_by_ann(&conn, &index, &[1.0, 0.1], 2, false)?;
+        assert_eq!(ids(ann.unwrap()), exact);
+
+        let filtered = load_filter(&conn, Some(&HashSet::from([1])))?;
+        let exact = ids(rank_by_vector(&conn, "m", &[1.0, 0.1], 2, filtered)?);
+        assert_eq!(exact, ["c0", "c1"]);
+        let ann = rank_by_ann(&conn, &index, &[1.0, 0.1], 2, filtered)?;
+        assert_eq!(ids(ann.unwrap()), exact);
+        assert_eq!(filtered_chunks(&conn)?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn fusion_ranks_and_reports_both_retrievers() {
+        let hits = |list: &[(&str, f32)]| -> Vec<ChunkSearchHit> {
+            list.iter()
+                .map(|(id, score)| ChunkSearchHit {
+                    path: String::new(),
+                    score: *score,
+                    chunk_id: id.to_string(),
+                    start_byte: 0,
+                    end_byte: 0,
+                    location: Location::default(),
+                    snippet: None,
+                    keyword: None,
+                    semantic: None,
+                })
+                .collect()
+        };
+        let bm25 = || hits(&[("a", 9.0), ("b", 5.0), ("c", 1.0)]);
+        let ann = || hits(&[("c", 0.9), ("d", 0.8), ("a", 0.1)]);
+        let ids = |fused: &[ChunkSearchHit]| -> Vec<String> {
+            fused.iter().map(|h| h.chunk_id.clone()).collect()
+        };
+
+        let opts = FusionOptions::default();
+        let fused = fuse(bm25(), ann(), 4, &opts);
+        assert_eq!(ids(&fused), ["a", "c", "b", "d"]);
+        assert!((fused[0].score - (1.0 / 61.0 + 1.0 / 63.0)).abs() < 1e-6);
+        assert_eq!(
+            fused[0].keyword,
+            Some(RetrieverHit {
+                rank: 1,
+                score: 9.0
+            })
+        );
+        assert_eq!(
+            fused[0].semantic,
+            Some(RetrieverHit {
+                rank: 3,
+                score: 0.1
+            })
+        );
+        assert_eq!(fused[3].keyword, None);
+        let json = serde_json::to_value(&fused[3]).unwrap();
+        assert_eq!(json["semantic"]["rank"], 2);
+        assert!(json.get("keyword").is_none());
+
+        let semantic_only = FusionOptions {
+            keyword_weight: 0.0,
+            ..FusionOptions::default()
+        };
+        assert_eq!(ids(&fuse(bm25(), ann(), 2, &semantic_only)), ["c", "d"]);
+
+        // Normalized scores: a = 1 + 0, c = 0 + 1, b = 0.5, d = 0.875.
+        let linear = FusionOptions {
+            method: Fusion::Linear,
+            ..FusionOptions::default()
+        };
+        let fused = fuse(bm25(), ann(), 4, &linear);
+        assert_eq!(ids(&fused), ["a", "c", "d", "b"]);
+        assert!((fused[2].score - 0.875).abs() < 1e-6);
+    }
+
+    #[test]
+    fn chunk_hits_aggregate_into_files() -> Result<()> {
+        let conn = db::open(camino::Utf8Path::new(":memory:"))?;
+        for (id, path) in [(1, "/tmp/long.pdf"), (2, "/tmp/short.txt")] {
+            conn.execute("INSERT INTO files (id, realpath, size, mtime_ns, status, created_ts, updated_ts) VALUES (?1,?2,1,0,'active',0,0)", params![id, path])?;
+        }
+        let ranked = [
+            ("l1", 1, 0.9),
+            ("s1", 2, 0.8),
+            ("l2", 1, 0.3),
+            ("l3", 1, 0.2),
+        ];
+        for (chunk_id, file_id, _) in ranked {
+            conn.execute(
+                "INSERT INTO chunks (file_id, chunk_id, start_byte, end_byte, text) VALUES (?1, ?2, 0, 0, '')",
+                params![file_id, chunk_id],
+            )?;
+        }
+        let hits = || {
+            ranked
+                .iter()
+                .map(|(chunk_id, file_id, score)| ChunkSearchHit {
+                    path: format!("/tmp/{file_id}"),
+                    score: *score,
+                    chunk_id: chunk_id.to_string(),
+                    start_byte: 0,
+                    end_byte: 0,
+                    location: Location::default(),
+                    snippet: None,
+                    keyword: None,
+                    semantic: None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let files = |method| -> Result<Vec<(i64, f32, Vec<String>)>> {
+            let opts = AggregateOptions {
+                method,
+                chunks_per_file: 2,
+                ..AggregateOptions::default()
+            };
+            Ok(aggregate(&conn, hits(), 10, &opts)?
+                .into_iter()
+                .map(|f| {
+                    let chunks = f.chunks.into_iter().map(|c| c.chunk_id).collect();
+                    (f.file_id, (f.score * 100.0).round() / 100.0, chunks)
+                })
+                .collect())
         };
 
-        std::env::set_var("EMBEDDING_MODEL", "snowflake/snowflake-arctic-embed-xs");
+        assert_eq!(
+            files(Aggregation::Max)?,
+            [
+                (1, 0.9, vec!["l1".to_string(), "l2".to_string()]),
+                (2, 0.8, vec!["s1".to_string()])
+            ]
+        );
+        let sum = files(Aggregation::TopSum(2))?;
+        assert_eq!((sum[0].0, sum[0].1), (1, 1.2));
+        let mean = files(Aggregation::Mean)?;
+        assert_eq!((mean[0].0, mean[1].0, mean[1].1), (2, 1, 0.47));
+        assert_eq!(
+            aggregate(&conn, hits(), 1, &AggregateOptions::default())?.len(),
+            1
+        );
+        Ok(())
+    }
 
-        let conn = db::open(&db_path)?;
+    #[test]
+    fn semantic_chunk_search_returns_hit() -> Result<()> {
+        let tmp = tempdir()?;
+        let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
+        let cfg = Config {
+            default_language: "en".into(),
+            embedding: EmbeddingConfig {
+                provider: "builtin".into(),
+                model: Some("snowflake/snowflake-arctic-embed-xs".into()),
+                ..EmbeddingConfig::default()
+            },
+            ..Config::for_test(&root)
+        };
+
+        let conn = db::open(&cfg.db)?;
         conn.execute("INSERT INTO files (id, realpath, size, mtime_ns, status, created_ts, updated_ts) VALUES (1,'/tmp/a.txt',1,0,'active',0,0)", [])?;
         let long_text = "hello world".repeat(100);
         conn.execute("INSERT INTO documents (file_id, extractor, extractor_version, lang, page_count, content_md, content_txt, ocr_applied, updated_ts) VALUES (1,'doc','v','en',1,'',?1,0,0)", params![long_text])?;
 
         index::reindex_all(&cfg, None)?;
-        let res = semantic_chunks(&cfg, "hello", 10)?;
+        crate::embed::embed_all(&cfg)?;
+        let res = semantic_chunks(&cfg, "hello", 10, &SearchOptions::default())?;
         assert!(!res.results.is_empty());
         Ok(())
     }
@@ -374,50 +1533,24 @@ mod tests {
     fn hybrid_chunk_search_returns_hit() -> Result<()> {
         let tmp = tempdir()?;
         let root = Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap();
-        let db_path = root.join("catalog.db");
-        let idx_path = root.join("idx");
         let cfg = Config {
-            db: db_path.clone(),
-            tantivy_index: idx_path.clone(),
-            roots: vec![],
-            include: vec![],
-            exclude: vec![],
-            max_file_size_mb: 200,
-            follow_symlinks: false,
-            include_hidden: false,
-            allow_offline_hydration: false,
-            commit_interval_secs: 45,
-            guard_interval_secs: 180,
             default_language: "en".into(),
-            extractor_cmd: String::new(),
             embedding: EmbeddingConfig {
                 provider: "builtin".into(),
+                model: Some("snowflake/snowflake-arctic-embed-xs".into()),
+                ..EmbeddingConfig::default()
             },
-            mirror: crate::config::MirrorConfig {
-                root: Utf8PathBuf::from("raw"),
-            },
-            bus: crate::config::BusConfig {
-                bounds: crate::config::BusBounds {
-                    source_fs: 16,
-                    mirror_text: 16,
-                },
-            },
-            extract: crate::config::ExtractConfig {
-                pool_size: 1,
-                jobs_bound: 16,
-            },
-            retention: RetentionConfig::default(),
+            ..Config::for_test(&root)
         };
 
-        std::env::set_var("EMBEDDING_MODEL", "snowflake/snowflake-arctic-embed-xs");
-
-        let conn = db::open(&db_path)?;
+        let conn = db::open(&cfg.db)?;
         conn.execute("INSERT INTO files (id, realpath, size, mtime_ns, status, created_ts, updated_ts) VALUES (1,'/tmp/a.txt',1,0,'active',0,0)", [])?;
         let long_text = "hello world".repeat(100);
         conn.execute("INSERT INTO documents (file_id, extractor, extractor_version, lang, page_count, content_md, content_txt, ocr_applied, updated_ts) VALUES (1,'doc','v','en',1,'',?1,0,0)", params![long_text])?;
 
         index::reindex_all(&cfg, None)?;
-        let res = hybrid_chunks(&cfg, "hello", 10)?;
+        crate::embed::embed_all(&cfg)?;
+        let res = hybrid_chunks(&cfg, "hello", 10, &SearchOptions::default())?;
         assert!(!res.results.is_empty());
         Ok(())
     }
@@ -11,6 +11,7 @@ pub struct Dashboard {
     mp: Arc<MultiProgress>,
     files: ProgressBar,
     chunks: ProgressBar,
+    embeds: ProgressBar,
 }
 
 impl Dashboard {
@@ -35,10 +36,19 @@ impl Dashboard {
         let chunks = mp.add(ProgressBar::new(0));
         chunks.set_style(chunk_style);
         chunks.set_prefix("Chunks");
+        let embed_style = ProgressStyle::with_template(
+            "{prefix:<7} {msg:<40} {wide_bar} {pos}/{len} ({per_sec})",
+        )
+        .unwrap()
+        .progress_chars("##-");
+        let embeds = mp.add(ProgressBar::new(0));
+        embeds.set_style(embed_style);
+        embeds.set_prefix("Embed");
         Some(Self {
             mp: Arc::new(mp),
             files,
             chunks,
+            embeds,
         })
     }
 
@@ -72,6 +82,27 @@ impl Dashboard {
         self.chunks.finish();
     }
 
+    /// Count chunks queued for the embedding stage.
+    pub fn add_embed_backlog(&self, n: u64) {
+        self.embeds.inc_length(n);
+        self.show_embed_backlog();
+    }
+
+    /// Count chunks handled by the embedding stage.
+    pub fn inc_embedded(&self, n: u64) {
+        self.embeds.inc(n);
+        self.show_embed_backlog();
+    }
+
+    fn show_embed_backlog(&self) {
+        let backlog = self
+            .embeds
+            .length()
+            .unwrap_or_default()
+            .saturating_sub(self.embeds.position());
+        self.embeds.set_message(format!("backlog {backlog}"));
+    }
+
     /// Add a persistent spinner used in watch mode.
     pub fn watch_spinner(&self) -> ProgressBar {
         let spinner = self.mp.add(ProgressBar::new_spinner());
@@ -0,0 +1,95 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::{fs, process::Command, thread};
+
+use serde_json::{json, Value};
+use tempfile::tempdir;
+
+/// Serve an Ollama-style embedding API answering every input with a vector,
+/// and return its URL.
+fn embedding_service() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/api/embed", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut len = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(v) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    len = v.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).unwrap();
+            let request: Value = serde_json::from_slice(&body).unwrap();
+            let inputs = request["input"].as_array().unwrap();
+            let vectors: Vec<_> = (0..inputs.len()).map(|i| [1.0, i as f32]).collect();
+            let body = json!({ "embeddings": vectors }).to_string();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+        }
+    });
+    url
+}
+
+#[test]
+fn index_run_embeds_each_chunk_once() -> anyhow::Result<()> {
+    let tmp = tempdir()?;
+    let docs = tmp.path().join("docs");
+    fs::create_dir_all(&docs)?;
+    let n = 12;
+    for i in 0..n {
+        fs::write(docs.join(format!("{i}.txt")), format!("note number {i}"))?;
+    }
+    let config = format!(
+        r#"
+db = "catalog.db"
+tantivy_index = "idx"
+roots = ["docs"]
+include = ["**/*.txt"]
+exclude = []
+max_file_size_mb = 1
+follow_symlinks = false
+commit_interval_secs = 1
+guard_interval_secs = 180
+default_language = "en"
+extractor_cmd = ""
+
+[embedding]
+provider = "external"
+url = "{}"
+model = "stub"
+api = "ollama"
+batch_size = 2
+"#,
+        embedding_service()
+    );
+    fs::write(tmp.path().join("findx.toml"), config)?;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_findx"))
+        .current_dir(tmp.path())
+        .args(["--compact-output", "index"])
+        .output()?;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8(output.stdout)?;
+    let summary: Value = serde_json::from_str(stdout.lines().last().unwrap())?;
+    assert_eq!(summary["extracted"], n);
+    assert_eq!(summary["embeddings"]["computed"], n);
+    assert_eq!(summary["embeddings"]["reused"], 0);
+    Ok(())
+}
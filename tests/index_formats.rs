@@ -2,7 +2,7 @@ use camino::Utf8PathBuf;
 use std::{fs, process::Command};
 use tempfile::tempdir;
 
-use findx::config::{Config, EmbeddingConfig, RetentionConfig};
+use findx::config::Config;
 use findx::{bus::EventBus, fs as findx_fs, index, metadata, search};
 use std::sync::{
     atomic::{AtomicBool, Ordering},
@@ -52,31 +52,16 @@ fn indexes_various_document_types() -> anyhow::Result<()> {
         roots: vec![root.clone()],
         include: vec!["**/*".into()],
         exclude: vec![],
-        max_file_size_mb: 200,
-        follow_symlinks: false,
-        include_hidden: false,
-        allow_offline_hydration: false,
-        commit_interval_secs: 45,
-        guard_interval_secs: 180,
         default_language: "en".into(),
         extractor_cmd: extractor.as_str().into(),
-        embedding: EmbeddingConfig {
-            provider: "disabled".into(),
-        },
         mirror: findx::config::MirrorConfig {
             root: root.join("raw"),
         },
-        bus: findx::config::BusConfig {
-            bounds: findx::config::BusBounds {
-                source_fs: 16,
-                mirror_text: 16,
-            },
-        },
         extract: findx::config::ExtractConfig {
             pool_size: 1,
             jobs_bound: 16,
         },
-        retention: RetentionConfig::default(),
+        ..Config::default()
     };
 
     // Scan filesystem and extract contents (legacy path pending new pipeline)
@@ -104,7 +89,7 @@ fn indexes_various_document_types() -> anyhow::Result<()> {
     ];
 
     for (query, filename) in cases {
-        let res = search::keyword(&cfg, query, 10)?;
+        let res = search::keyword(&cfg, query, 10, &search::SearchOptions::default())?;
         assert!(
             res.results.iter().any(|h| h.path.ends_with(filename)),
             "query '{query}' did not return '{filename}'"